@@ -0,0 +1,178 @@
+#ifndef WSTF_H
+#define WSTF_H
+
+/* Generated by cbindgen from src/parser/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+#define WSTF_FLAG_IS_BID 0x01
+#define WSTF_FLAG_IS_TRADE 0x02
+#define WSTF_FLAG_SNAPSHOT_START 0x04
+#define WSTF_FLAG_SNAPSHOT_END 0x08
+#define WSTF_FLAG_RESET 0x10
+#define WSTF_FLAG_IMPLIED 0x20
+#define WSTF_FLAG_AGGRESSOR_UNKNOWN 0x40
+
+typedef enum WstfStatus {
+  WSTF_STATUS_OK = 0,
+  WSTF_STATUS_NULL_ARGUMENT = 1,
+  WSTF_STATUS_INVALID_STRING = 2,
+  WSTF_STATUS_IO = 3,
+  WSTF_STATUS_INVALID_FILE = 4,
+  WSTF_STATUS_UNSUPPORTED = 5,
+  WSTF_STATUS_PANIC = 6,
+} WstfStatus;
+
+typedef struct WstfUpdate {
+  uint64_t ts;
+  uint32_t seq;
+  bool is_trade;
+  bool is_bid;
+  // Row flags other than `is_bid` and `is_trade`, including bits this version does not know.
+  uint8_t flags;
+  float price;
+  float size;
+} WstfUpdate;
+
+// An owned array of updates, released with `wstf_slice_free`.
+typedef struct WstfSlice {
+  struct WstfUpdate *ptr;
+  size_t len;
+} WstfSlice;
+
+typedef struct WstfMetadata {
+  // Owned symbol, released with `wstf_metadata_free`.
+  char *symbol;
+  uint64_t nums;
+  uint64_t min_ts;
+  uint64_t max_ts;
+  // Timestamp units per second, 1000 for milliseconds.
+  uint64_t ts_per_second;
+} WstfMetadata;
+
+// Decodes every update of `fname` into `out`.
+//
+// # Safety
+//
+// `fname` must be null or a NUL terminated string, `out` and `err` null or writable.
+enum WstfStatus wstf_decode(const char *fname, struct WstfSlice *out, char **err);
+
+// Decodes the first `num` batches of `fname` into `out`.
+//
+// # Safety
+//
+// See `wstf_decode`.
+enum WstfStatus wstf_decode_with_limit(const char *fname,
+                                       uint32_t num,
+                                       struct WstfSlice *out,
+                                       char **err);
+
+// Decodes the updates of `fname` with `min_ts <= ts <= max_ts` into `out`.
+//
+// # Safety
+//
+// See `wstf_decode`.
+enum WstfStatus wstf_range(const char *fname,
+                           uint64_t min_ts,
+                           uint64_t max_ts,
+                           struct WstfSlice *out,
+                           char **err);
+
+// Decodes the batches of an in-memory stream into `out`.
+//
+// # Safety
+//
+// `buf` must be null or point to `len` readable bytes, `out` and `err` null or writable.
+enum WstfStatus wstf_decode_buffer(const unsigned char *buf,
+                                   size_t len,
+                                   struct WstfSlice *out,
+                                   char **err);
+
+// Renders every update of `fname` as CSV into `out`, released with `wstf_str_free`.
+//
+// # Safety
+//
+// See `wstf_decode`.
+enum WstfStatus wstf_to_csv(const char *fname, char **out, char **err);
+
+// Reads the header summary of `fname` into `out`, released with `wstf_metadata_free`.
+//
+// # Safety
+//
+// See `wstf_decode`.
+enum WstfStatus wstf_metadata(const char *fname, struct WstfMetadata *out, char **err);
+
+// Releases the updates of a slice returned by this library.
+//
+// # Safety
+//
+// `slice` must come from this library and must not be used or released again.
+void wstf_slice_free(struct WstfSlice slice);
+
+// Releases the symbol of `meta` and resets it to null.
+//
+// # Safety
+//
+// `meta` must be null or filled by `wstf_metadata`.
+void wstf_metadata_free(struct WstfMetadata *meta);
+
+// Releases a string returned by this library.
+//
+// # Safety
+//
+// `s` must be null or come from this library, and must not be used or released again.
+void wstf_str_free(char *s);
+
+// Superseded by `wstf_to_csv`, returns null on failure.
+//
+// # Safety
+//
+// `fname` must be null or a NUL terminated string.
+char *read_wstf_to_csv(const char *fname);
+
+// Superseded by `wstf_decode_with_limit` and `wstf_to_csv`, returns null on failure.
+//
+// # Safety
+//
+// `fname` must be null or a NUL terminated string.
+char *read_wstf_to_csv_with_limit(const char *fname, uint32_t num);
+
+// Superseded by `wstf_decode`, returns an empty slice on failure.
+//
+// # Safety
+//
+// `fname` must be null or a NUL terminated string.
+struct WstfSlice read_wstf_to_arr(const char *fname);
+
+// Superseded by `wstf_decode_with_limit`, returns an empty slice on failure.
+//
+// # Safety
+//
+// `fname` must be null or a NUL terminated string.
+struct WstfSlice read_wstf_to_arr_with_limit(const char *fname, uint32_t num);
+
+// Returns null on success, or an error message released with `wstf_str_free`.
+//
+// # Safety
+//
+// Every argument must be null or a NUL terminated string.
+char *parse_kaiko_csv_to_wstf(const char *symbol, const char *fname, const char *csv_str);
+
+// Superseded by `wstf_decode_buffer`, returns an empty slice on failure.
+//
+// # Safety
+//
+// `n` must be null or point to `len` readable bytes.
+struct WstfSlice parse_stream(unsigned char *n, uint32_t len);
+
+// Superseded by `wstf_str_free`.
+//
+// # Safety
+//
+// See `wstf_str_free`.
+void str_free(char *s);
+
+#endif  /* WSTF_H */
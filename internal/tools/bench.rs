@@ -1,9 +1,14 @@
 use clap::{App, Arg};
+use std::fs;
 use std::time::SystemTime;
 use wstf::algorithms::histogram::Histogram;
 use wstf::algorithms::levels::Levels;
-use wstf::protocol::file_format::{encode, get_range_in_file};
-use wstf::update::Update;
+use wstf::protocol::compression::Compression;
+use wstf::protocol::encoding::BatchEncoding;
+use wstf::protocol::file_format::{
+    decode, encode, encode_with_options, get_range_in_file, EncodeOptions,
+};
+use wstf::update::{Flags, Update};
 
 static FNAME: &str = "./internal/mocks/tmp.wstf";
 static EVENTS_PER_MS: u64 = 100;
@@ -23,6 +28,7 @@ fn prepare_data_range(from_ts: u64, to_ts: u64, events_per_ms: u64) {
                     price: 0f32,
                     is_bid: false,
                     is_trade: false,
+                    flags: Flags::FLAG_EMPTY,
                 }
             })
         })
@@ -80,6 +86,58 @@ fn bench_histogram(range_min_ts: u64, range_max_ts: u64) {
     println!("histogram: [algorithms] elapsed time: {:?}", elapsed);
 }
 
+fn bench_compression(range_min_ts: u64, range_max_ts: u64) {
+    let ups = decode(FNAME, None).expect("Error in decode function");
+    let mut raw_size = None;
+
+    let formats = [BatchEncoding::Raw, BatchEncoding::Xor]
+        .into_iter()
+        .flat_map(|encoding| {
+            Compression::available()
+                .into_iter()
+                .map(move |c| (encoding, c))
+        });
+
+    for (encoding, compression) in formats {
+        let label = format!("{}+{}", encoding, compression);
+        let fname = format!("{}.{}", FNAME, label);
+        let opts = EncodeOptions {
+            compression,
+            encoding,
+            ..Default::default()
+        };
+
+        let start_time = SystemTime::now();
+        encode_with_options(&fname, "default", &ups, &opts).expect("Error in encode function");
+        let encode_elapsed = start_time.elapsed().expect("Clock may have gone backwards");
+
+        let start_time = SystemTime::now();
+        decode(&fname, None).expect("Error in decode function");
+        let decode_elapsed = start_time.elapsed().expect("Clock may have gone backwards");
+
+        let start_time = SystemTime::now();
+        get_range_in_file(&fname, range_min_ts, range_max_ts).expect("Error in range function");
+        let range_elapsed = start_time.elapsed().expect("Clock may have gone backwards");
+
+        let size = fs::metadata(&fname).expect("Unable to stat file").len();
+        let raw_size = *raw_size.get_or_insert(size);
+        fs::remove_file(&fname).expect("Unable to remove file");
+
+        let throughput = |secs: f64| ups.len() as f64 / secs;
+        println!(
+            "compression: [{}] size: {} bytes, ratio: {:.2}, encode: {:?} ({:.0} updates/s), decode: {:?} ({:.0} updates/s), range: {:?}",
+            label,
+            size,
+            raw_size as f64 / size as f64,
+            encode_elapsed,
+            throughput(encode_elapsed.as_secs_f64()),
+            decode_elapsed,
+            throughput(decode_elapsed.as_secs_f64()),
+            range_elapsed
+        );
+    }
+}
+
 fn main() {
     let matches = App::new("wstf-bench")
         .version("0.1.0")
@@ -107,6 +165,12 @@ fn main() {
                 .long("prepare")
                 .help("Prepare data for benchmarking"),
         )
+        .arg(
+            Arg::with_name("compression")
+                .short("c")
+                .long("compression")
+                .help("Compare compressed encodings against the uncompressed format"),
+        )
         .arg(
             Arg::with_name("range_min_ts")
                 .short("r")
@@ -146,6 +210,7 @@ fn main() {
         .expect("Unable to parse to_ts");
 
     let prepare = matches.is_present("prepare");
+    let compression = matches.is_present("compression");
 
     let range_min_ts = matches
         .value_of("range_min_ts")
@@ -169,6 +234,11 @@ fn main() {
         prepare_data_range(range_from_ts, range_to_ts, events_per_ms);
     }
 
+    if compression {
+        bench_compression(range_min_ts, range_max_ts);
+        return;
+    }
+
     benchmark_range(range_min_ts, range_max_ts);
     benchmark_levels(range_min_ts, range_max_ts);
     bench_histogram(range_min_ts, range_max_ts);
@@ -1,7 +1,21 @@
 use clap::{App, Arg};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use wstf::parser::text::{decode_to_csv, decode_to_ndjson, CsvWriter, NdjsonWriter, TextOptions};
 use wstf::parser::utils::{scan_files_for_range, total_folder_updates_len};
 use wstf::protocol::file_format::{decode, read_meta};
-use wstf::update::UpdateVecConvert;
+use wstf::update::Update;
+
+#[cfg(feature = "arrow")]
+fn write_parquet(output: &str, ups: &[Update]) {
+    let file = std::fs::File::create(output).unwrap();
+    wstf::parser::arrow_io::write_parquet(file, ups).unwrap();
+}
+
+#[cfg(not(feature = "arrow"))]
+fn write_parquet(_output: &str, _ups: &[Update]) {
+    println!("Parquet output requires the arrow feature!");
+}
 
 fn main() {
     let matches = App::new("client")
@@ -59,7 +73,12 @@ fn main() {
             Arg::with_name("csv")
                 .short("c")
                 .long("csv")
-                .help("output csv (default is JSON)"),
+                .help("output csv (default is newline-delimited JSON)"),
+        )
+        .arg(
+            Arg::with_name("parquet")
+                .long("parquet")
+                .help("write parquet to the output file"),
         )
         .arg(
             Arg::with_name("output")
@@ -81,6 +100,8 @@ fn main() {
 
     let print_metadata = matches.is_present("meta");
     let csv = matches.is_present("csv");
+    let parquet = matches.is_present("parquet");
+    let output = matches.value_of("output").unwrap_or("");
 
     if input == "" && (symbol == "" || min == "" || max == "") && (folder == "" && !print_metadata)
     {
@@ -88,40 +109,60 @@ fn main() {
         return;
     }
 
-    let txt = if input != "" {
+    if parquet && (output == "" || print_metadata) {
+        println!("Parquet output needs an output file and cannot show metadata!");
+        return;
+    }
+
+    if parquet {
+        let ups = if input != "" {
+            decode(input, None).unwrap()
+        } else {
+            scan_files_for_range(folder, symbol, min.parse().unwrap(), max.parse().unwrap())
+                .unwrap()
+        };
+        return write_parquet(output, &ups);
+    }
+
+    let mut out: Box<dyn Write> = if output != "" {
+        Box::new(BufWriter::new(File::create(output).unwrap()))
+    } else {
+        Box::new(BufWriter::new(io::stdout().lock()))
+    };
+    let opts = TextOptions {
+        header: true,
+        ..Default::default()
+    };
+
+    if input != "" {
         if print_metadata {
-            format!("{}", read_meta(input).unwrap())
+            writeln!(out, "{}", read_meta(input).unwrap()).unwrap();
+        } else if csv {
+            decode_to_csv(input, &mut out, &opts).unwrap();
         } else {
-            let ups = decode(input, None).unwrap();
-            if csv {
-                format!("{}", ups.as_csv())
-            } else {
-                format!("[{}]", ups.as_json())
-            }
+            decode_to_ndjson(input, &mut out, &opts).unwrap();
         }
+    } else if print_metadata {
+        writeln!(
+            out,
+            "Total updates in folder: {}",
+            total_folder_updates_len(folder).unwrap()
+        )
+        .unwrap();
     } else {
-        if print_metadata {
-            format!(
-                "Total updates in folder: {}",
-                total_folder_updates_len(folder).unwrap()
-            )
+        let ups = scan_files_for_range(folder, symbol, min.parse().unwrap(), max.parse().unwrap())
+            .unwrap();
+        if csv {
+            let mut writer = CsvWriter::new(&mut out, opts).unwrap();
+            for up in &ups {
+                writer.write(up).unwrap();
+            }
         } else {
-            let ups =
-                scan_files_for_range(folder, symbol, min.parse().unwrap(), max.parse().unwrap())
-                    .unwrap();
-            if csv {
-                format!("{}", ups.as_csv())
-            } else {
-                format!("[{}]", ups.as_json())
+            let mut writer = NdjsonWriter::new(&mut out, opts);
+            for up in &ups {
+                writer.write(up).unwrap();
             }
         }
-    };
-
-    let output = matches.value_of("output").unwrap_or("");
-
-    if output != "" {
-        std::fs::write(output, txt).unwrap();
-    } else {
-        println!("{}", txt);
     }
+    out.flush().unwrap();
 }
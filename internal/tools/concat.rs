@@ -168,6 +168,7 @@ pub fn combine_files(
 #[test]
 fn wstf_merging() {
     use std::fs::remove_file;
+    use wstf::update::Flags;
 
     let mut update_timestamps_first: Vec<u64> = (0..1000).collect();
     update_timestamps_first.append(&mut vec![
@@ -187,6 +188,7 @@ fn wstf_merging() {
                     seq: i as u32 + seq_offset as u32,
                     is_trade: false,
                     is_bid: true,
+                    flags: Flags::FLAG_EMPTY,
                     price: *ts as f32 + if last_timestamp == *ts { 1. } else { 0. },
                     size: *ts as f32,
                 };
@@ -0,0 +1,90 @@
+use clap::{App, Arg};
+use std::process::exit;
+use wstf::parser::importers::{import_file, Exchange};
+use wstf::protocol::file_format::EncodeOptions;
+
+fn main() {
+    let matches = App::new("import")
+        .version("0.1.0")
+        .author("alxshelepenok <alxshelepenok@gmail.com>")
+        .about(
+            "Converts exchange market-data dumps with one JSON message per line into wstf.
+       Examples:
+       wstf-import -e binance -s BTCUSDT -i depth.jsonl -o BTCUSDT.wstf",
+        )
+        .arg(
+            Arg::with_name("exchange")
+                .short("e")
+                .long("exchange")
+                .value_name("EXCHANGE")
+                .help("Format of the dump")
+                .possible_values(&["binance", "bybit", "coinbase"])
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("symbol")
+                .short("s")
+                .long("symbol")
+                .value_name("SYMBOL")
+                .help("Symbol to import, messages for other symbols are skipped")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("INPUT")
+                .help("File to read")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("OUTPUT")
+                .help("Output file")
+                .required(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("time_unit")
+                .short("t")
+                .long("time_unit")
+                .value_name("UNIT")
+                .help("Timestamp unit of the output file")
+                .possible_values(&["s", "ms", "us", "ns"])
+                .default_value("ms")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let exchange: Exchange = matches.value_of("exchange").unwrap().parse().unwrap();
+    let symbol = matches.value_of("symbol").unwrap();
+    let input = matches.value_of("input").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let opts = EncodeOptions {
+        time_unit: matches.value_of("time_unit").unwrap().parse().unwrap(),
+        ..Default::default()
+    };
+
+    let report = match import_file(exchange, input, output, symbol, &opts) {
+        Ok(report) => report,
+        Err(err) => {
+            println!("ERROR: {}", err);
+            exit(1);
+        }
+    };
+    for row in &report.invalid {
+        eprintln!("line {}: {}", row.line, row.reason);
+    }
+    println!(
+        "Imported {} updates into {}, skipped {} messages for other symbols, {} invalid",
+        report.imported,
+        output,
+        report.skipped,
+        report.invalid.len()
+    );
+}
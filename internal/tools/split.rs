@@ -41,8 +41,9 @@ fn main() {
 
     println!("Reading: {}", fname);
     let meta = read_meta(fname).unwrap();
-    let rdr = WSTFBufReader::new(fname, batch_size);
+    let rdr = WSTFBufReader::new(fname, batch_size).unwrap();
     for (i, batch) in rdr.enumerate() {
+        let batch = batch.unwrap();
         let outname = format!("{}-{}.wstf", file_stem, i);
         println!("Writing to {}", outname);
         encode(&outname, &meta.symbol, &batch).unwrap();
@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 
+use crate::protocol::time_unit::TimeUnit;
 use crate::update::Update;
-use crate::utils::{bigram, fill_digits};
+use crate::utils::{bigram, fill_digits_for};
 use rustc_hash::FxHashMap;
 use std::cmp::Ordering::{self, Equal, Greater, Less};
 use std::mem;
@@ -59,12 +60,22 @@ impl Histogram {
         step_bins: BinCount,
         tick_bins: BinCount,
         m: f64,
+    ) -> (Histogram, Histogram) {
+        Histogram::from_with_unit(ups, step_bins, tick_bins, m, TimeUnit::Millis)
+    }
+
+    pub fn from_with_unit(
+        ups: &[Update],
+        step_bins: BinCount,
+        tick_bins: BinCount,
+        m: f64,
+        unit: TimeUnit,
     ) -> (Histogram, Histogram) {
         let prices = ups.iter().map(|up| up.price as f64).collect::<Vec<f64>>();
         let price_hist = Histogram::new(&prices, tick_bins, m);
 
-        let min_ts = fill_digits(ups.iter().next().unwrap().ts) / 1000;
-        let max_ts = fill_digits(ups.iter().next_back().unwrap().ts) / 1000;
+        let min_ts = unit.to_seconds(fill_digits_for(ups.iter().next().unwrap().ts, unit));
+        let max_ts = unit.to_seconds(fill_digits_for(ups.iter().next_back().unwrap().ts, unit));
         let step_hist = Histogram::new_boundaries(min_ts, max_ts, step_bins);
 
         (price_hist, step_hist)
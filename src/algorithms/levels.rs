@@ -1,8 +1,9 @@
 #![allow(dead_code)]
 
 use crate::algorithms::histogram::{BinCount, Histogram};
+use crate::protocol::time_unit::TimeUnit;
 use crate::update::Update;
-use crate::utils::fill_digits;
+use crate::utils::fill_digits_for;
 use ordered_float::OrderedFloat;
 use rustc_hash::FxHashMap;
 use std::collections::BTreeMap;
@@ -18,11 +19,22 @@ pub struct Levels {
 
 impl Levels {
     pub fn from(ups: &[Update], step_bins: BinCount, tick_bins: BinCount, m: f64) -> Levels {
-        let (price_hist, step_hist) = Histogram::from(&ups, step_bins, tick_bins, m);
+        Levels::from_with_unit(ups, step_bins, tick_bins, m, TimeUnit::Millis)
+    }
+
+    pub fn from_with_unit(
+        ups: &[Update],
+        step_bins: BinCount,
+        tick_bins: BinCount,
+        m: f64,
+        unit: TimeUnit,
+    ) -> Levels {
+        let (price_hist, step_hist) =
+            Histogram::from_with_unit(&ups, step_bins, tick_bins, m, unit);
         let mut map = FxHashMap::default();
         for up in ups.iter() {
             let price = price_hist.to_bin(up.price as f64);
-            let time = step_hist.to_bin((fill_digits(up.ts) / 1000) as f64);
+            let time = step_hist.to_bin(unit.to_seconds(fill_digits_for(up.ts, unit)) as f64);
             match (price, time) {
                 (Some(p), Some(t)) => {
                     let price_level = map
@@ -1,5 +1,6 @@
 use crate::algorithms::histogram::{BinCount, Histogram};
-use crate::update::Update;
+use crate::protocol::time_unit::TimeUnit;
+use crate::update::{Flags, Update};
 use indexmap::IndexMap;
 use std::collections::BTreeMap;
 use std::f64;
@@ -18,7 +19,7 @@ pub struct Orderbook {
 
 impl Orderbook {
     pub fn discretize(&self, p: f32) -> Price {
-        (f64::from(p) * 10f64.powf(self.price_decimals as f64)) as Price
+        (f64::from(p) * 10f64.powf(self.price_decimals as f64)).round() as Price
     }
 
     pub fn undiscretize(&self, p: u64) -> f32 {
@@ -33,7 +34,14 @@ impl Orderbook {
         }
     }
 
+    /// Applies `up`, first clearing the book on a reset or at the start of a snapshot.
     pub fn process_update(&mut self, up: &Update) {
+        if up
+            .flags
+            .intersects(Flags::FLAG_RESET | Flags::FLAG_SNAPSHOT_START)
+        {
+            self.clear();
+        }
         if up.is_trade {
             let p = self.discretize(up.price);
             let book = if up.is_bid {
@@ -156,7 +164,26 @@ impl RebinnedOrderbook {
         tick_bins: BinCount,
         m: f64,
     ) -> RebinnedOrderbook {
-        let (price_hist, step_hist) = Histogram::from(&ups, step_bins, tick_bins, m);
+        RebinnedOrderbook::from_with_unit(
+            price_decimals,
+            ups,
+            step_bins,
+            tick_bins,
+            m,
+            TimeUnit::Millis,
+        )
+    }
+
+    pub fn from_with_unit(
+        price_decimals: u8,
+        ups: &[Update],
+        step_bins: BinCount,
+        tick_bins: BinCount,
+        m: f64,
+        unit: TimeUnit,
+    ) -> RebinnedOrderbook {
+        let (price_hist, step_hist) =
+            Histogram::from_with_unit(&ups, step_bins, tick_bins, m, unit);
         let mut fine_level = Orderbook::with_precision(price_decimals);
         let mut temp_ob = Orderbook::with_precision(price_decimals);
         let mut ob_across_time = IndexMap::<Time, Orderbook>::new();
@@ -166,7 +193,7 @@ impl RebinnedOrderbook {
                 continue;
             }
 
-            let ts = step_hist.to_bin((up.ts / 1000) as Size);
+            let ts = step_hist.to_bin(unit.to_seconds(up.ts) as Size);
             let price = price_hist.to_bin(up.price as f64);
 
             if ts == None || price == None {
@@ -265,4 +292,38 @@ mod tests {
             assert!(v.asks.values().len() < tick_bins);
         }
     }
+
+    #[test]
+    fn test_reset_clears_book() {
+        let level = |price: f32, is_bid: bool, flags: Flags| Update {
+            ts: 0,
+            seq: 0,
+            is_trade: false,
+            is_bid,
+            flags,
+            price,
+            size: 1.,
+        };
+        let mut ob = Orderbook::with_precision(2);
+        ob.process_update(&level(100., true, Flags::FLAG_EMPTY));
+        ob.process_update(&level(101., false, Flags::FLAG_IMPLIED));
+        assert_eq!((ob.bids.len(), ob.asks.len()), (1, 1));
+
+        ob.process_update(&level(99., true, Flags::FLAG_SNAPSHOT_START));
+        ob.process_update(&level(102., false, Flags::FLAG_SNAPSHOT_END));
+        assert_eq!(ob.bids.keys().collect::<Vec<_>>(), [&9900]);
+        assert_eq!(ob.asks.keys().collect::<Vec<_>>(), [&10200]);
+
+        ob.process_update(&level(98., true, Flags::FLAG_RESET));
+        assert_eq!(ob.bids.keys().collect::<Vec<_>>(), [&9800]);
+        assert!(ob.asks.is_empty());
+    }
+
+    #[test]
+    fn test_discretize_rounds() {
+        let ob = Orderbook::with_precision(2);
+        // 0.29f32 is slightly below 0.29, so truncating would file it under 28 ticks.
+        assert_eq!(ob.discretize(0.29), 29);
+        assert_eq!(ob.undiscretize(ob.discretize(0.29)), 0.29);
+    }
 }
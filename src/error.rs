@@ -0,0 +1,118 @@
+use std::{error, fmt, io};
+
+#[derive(Debug)]
+pub enum WstfError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    InvalidHeader(String),
+    InvalidFlags(u32),
+    Truncated {
+        offset: u64,
+    },
+    ChecksumMismatch {
+        offset: u64,
+        expected: u32,
+        found: u32,
+    },
+    Corrupt(String),
+    OutOfOrder {
+        ts: u64,
+        seq: u32,
+    },
+    SymbolTooLong(String),
+    Unsupported(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for WstfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WstfError::BadMagic => write!(f, "magic value incorrect"),
+            WstfError::UnsupportedVersion(version) => {
+                write!(f, "unsupported WSTF version {}", version)
+            }
+            WstfError::InvalidHeader(reason) => write!(f, "invalid header: {}", reason),
+            WstfError::InvalidFlags(flags) => write!(f, "invalid flags {:#x}", flags),
+            WstfError::Truncated { offset } => {
+                write!(f, "batch at offset {} is truncated", offset)
+            }
+            WstfError::ChecksumMismatch {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "batch at offset {} failed checksum: expected {:08x}, found {:08x}",
+                offset, expected, found
+            ),
+            WstfError::Corrupt(reason) => write!(f, "corrupt data: {}", reason),
+            WstfError::OutOfOrder { ts, seq } => {
+                write!(f, "update at ts {} seq {} is out of order", ts, seq)
+            }
+            WstfError::SymbolTooLong(symbol) => write!(f, "symbol {} is too long", symbol),
+            WstfError::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+            WstfError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for WstfError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            WstfError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WstfError {
+    fn from(err: io::Error) -> WstfError {
+        WstfError::Io(err)
+    }
+}
+
+impl From<WstfError> for io::Error {
+    fn from(err: WstfError) -> io::Error {
+        match err {
+            WstfError::Io(err) => err,
+            WstfError::Unsupported(_) => io::Error::new(io::ErrorKind::Unsupported, err),
+            WstfError::SymbolTooLong(_) | WstfError::OutOfOrder { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }
+            _ => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+impl From<csv::Error> for WstfError {
+    fn from(err: csv::Error) -> WstfError {
+        let reason = err.to_string();
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => WstfError::Io(err),
+            _ => WstfError::Corrupt(reason),
+        }
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl From<arrow::error::ArrowError> for WstfError {
+    fn from(err: arrow::error::ArrowError) -> WstfError {
+        match err {
+            arrow::error::ArrowError::IoError(_, err) => WstfError::Io(err),
+            err => WstfError::Corrupt(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl From<parquet::errors::ParquetError> for WstfError {
+    fn from(err: parquet::errors::ParquetError) -> WstfError {
+        match err {
+            parquet::errors::ParquetError::External(err) => match err.downcast::<io::Error>() {
+                Ok(err) => WstfError::Io(*err),
+                Err(err) => WstfError::Corrupt(err.to_string()),
+            },
+            err => WstfError::Corrupt(err.to_string()),
+        }
+    }
+}
@@ -10,12 +10,13 @@ extern crate uuid;
 #[macro_use]
 extern crate bitflags;
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 
 pub mod algorithms;
+pub mod error;
 pub mod parser;
 pub mod protocol;
+#[cfg(feature = "python")]
+mod python;
 pub mod update;
 pub mod utils;
 
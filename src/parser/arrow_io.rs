@@ -0,0 +1,276 @@
+//! Arrow `RecordBatch` conversion and Parquet / Arrow IPC export and import, behind the
+//! `arrow` feature.
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow::array::{
+    Array, ArrayRef, AsArray, BooleanArray, Float32Array, UInt32Array, UInt64Array, UInt8Array,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{
+    DataType, Field, Float32Type, Schema, SchemaRef, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+
+use crate::error::WstfError;
+use crate::protocol::file_format::{decode, encode_with_options, get_range_in_file, EncodeOptions};
+use crate::update::{Flags, Update};
+
+/// Rows per record batch, and so per Parquet row group, when exporting.
+pub(crate) const ROWS_PER_BATCH: usize = 65_536;
+
+pub fn update_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("ts", DataType::UInt64, false),
+        Field::new("seq", DataType::UInt32, false),
+        Field::new("is_trade", DataType::Boolean, false),
+        Field::new("is_bid", DataType::Boolean, false),
+        Field::new("flags", DataType::UInt8, false),
+        Field::new("price", DataType::Float32, false),
+        Field::new("size", DataType::Float32, false),
+    ]))
+}
+
+pub fn to_record_batch(ups: &[Update]) -> Result<RecordBatch, WstfError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from_iter_values(ups.iter().map(|up| up.ts))),
+        Arc::new(UInt32Array::from_iter_values(ups.iter().map(|up| up.seq))),
+        Arc::new(BooleanArray::from_iter(
+            ups.iter().map(|up| Some(up.is_trade)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            ups.iter().map(|up| Some(up.is_bid)),
+        )),
+        Arc::new(UInt8Array::from_iter_values(
+            ups.iter().map(|up| up.flags.bits()),
+        )),
+        Arc::new(Float32Array::from_iter_values(
+            ups.iter().map(|up| up.price),
+        )),
+        Arc::new(Float32Array::from_iter_values(ups.iter().map(|up| up.size))),
+    ];
+    Ok(RecordBatch::try_new(update_schema(), columns)?)
+}
+
+/// Looks up a column by name and casts it to `data_type`, so e.g. `Int64` timestamps or
+/// `Float64` prices are accepted.
+fn column(
+    batch: &RecordBatch,
+    name: &str,
+    data_type: &DataType,
+) -> Result<Option<ArrayRef>, WstfError> {
+    let col = match batch.column_by_name(name) {
+        Some(col) => col,
+        None => return Ok(None),
+    };
+    if col.null_count() > 0 {
+        return Err(WstfError::Corrupt(format!(
+            "column {} contains nulls",
+            name
+        )));
+    }
+    Ok(Some(cast(col, data_type)?))
+}
+
+fn required(batch: &RecordBatch, name: &str, data_type: &DataType) -> Result<ArrayRef, WstfError> {
+    column(batch, name, data_type)?
+        .ok_or_else(|| WstfError::Corrupt(format!("missing column {}", name)))
+}
+
+/// Reads the columns of `update_schema` by name. `seq` and `flags` may be missing.
+pub fn from_record_batch(batch: &RecordBatch) -> Result<Vec<Update>, WstfError> {
+    let ts = required(batch, "ts", &DataType::UInt64)?;
+    let is_trade = required(batch, "is_trade", &DataType::Boolean)?;
+    let is_bid = required(batch, "is_bid", &DataType::Boolean)?;
+    let price = required(batch, "price", &DataType::Float32)?;
+    let size = required(batch, "size", &DataType::Float32)?;
+    let seq = column(batch, "seq", &DataType::UInt32)?;
+    let flags = column(batch, "flags", &DataType::UInt8)?;
+
+    let ts = ts.as_primitive::<UInt64Type>();
+    let is_trade = is_trade.as_boolean();
+    let is_bid = is_bid.as_boolean();
+    let price = price.as_primitive::<Float32Type>();
+    let size = size.as_primitive::<Float32Type>();
+    let seq = seq.as_ref().map(|seq| seq.as_primitive::<UInt32Type>());
+    let flags = flags
+        .as_ref()
+        .map(|flags| flags.as_primitive::<UInt8Type>());
+    Ok((0..batch.num_rows())
+        .map(|i| Update {
+            ts: ts.value(i),
+            seq: seq.map_or(0, |seq| seq.value(i)),
+            is_trade: is_trade.value(i),
+            is_bid: is_bid.value(i),
+            flags: flags.map_or(Flags::FLAG_EMPTY, |flags| {
+                Flags::from_byte(flags.value(i)).split().2
+            }),
+            price: price.value(i),
+            size: size.value(i),
+        })
+        .collect())
+}
+
+pub fn write_parquet<W: Write + Send>(wtr: W, ups: &[Update]) -> Result<(), WstfError> {
+    let mut writer = ArrowWriter::try_new(wtr, update_schema(), None)?;
+    for chunk in ups.chunks(ROWS_PER_BATCH) {
+        writer.write(&to_record_batch(chunk)?)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+pub fn write_ipc<W: Write>(wtr: W, ups: &[Update]) -> Result<(), WstfError> {
+    let mut writer = FileWriter::try_new(wtr, &update_schema())?;
+    for chunk in ups.chunks(ROWS_PER_BATCH) {
+        writer.write(&to_record_batch(chunk)?)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+pub fn wstf_to_parquet(fname: &str, out: &str) -> Result<(), WstfError> {
+    write_parquet(File::create(out)?, &decode(fname, None)?)
+}
+
+pub fn wstf_range_to_parquet(
+    fname: &str,
+    out: &str,
+    min_ts: u64,
+    max_ts: u64,
+) -> Result<(), WstfError> {
+    write_parquet(
+        File::create(out)?,
+        &get_range_in_file(fname, min_ts, max_ts)?,
+    )
+}
+
+pub fn read_parquet(fname: &str) -> Result<Vec<Update>, WstfError> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(fname)?)?.build()?;
+    let mut ups = vec![];
+    for batch in reader {
+        ups.extend(from_record_batch(&batch?)?);
+    }
+    Ok(ups)
+}
+
+pub fn read_ipc(fname: &str) -> Result<Vec<Update>, WstfError> {
+    let mut ups = vec![];
+    for batch in FileReader::try_new(File::open(fname)?, None)? {
+        ups.extend(from_record_batch(&batch?)?);
+    }
+    Ok(ups)
+}
+
+/// Encodes the rows of a Parquet file, sorted by `ts` and `seq`, into a WSTF file.
+pub fn parquet_to_wstf(
+    input: &str,
+    fname: &str,
+    symbol: &str,
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let mut ups = read_parquet(input)?;
+    ups.sort();
+    encode_with_options(fname, symbol, &ups, opts)
+}
+
+/// Encodes the rows of an Arrow IPC file, sorted by `ts` and `seq`, into a WSTF file.
+pub fn ipc_to_wstf(
+    input: &str,
+    fname: &str,
+    symbol: &str,
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let mut ups = read_ipc(input)?;
+    ups.sort();
+    encode_with_options(fname, symbol, &ups, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::file_format::{decode, encode};
+    use arrow::array::{Float64Array, Int64Array};
+    use serial_test::serial;
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+    static FNAME_PARQUET: &str = "./internal/mocks/tmp.parquet";
+    static FNAME_IPC: &str = "./internal/mocks/tmp.arrow";
+
+    fn updates(n: u64) -> Vec<Update> {
+        (0..n)
+            .map(|i| Update {
+                ts: 1_000 + i * 10,
+                seq: (i % 3) as u32,
+                is_trade: i % 4 == 0,
+                is_bid: i % 2 == 0,
+                flags: if i % 5 == 0 {
+                    Flags::FLAG_RESET
+                } else {
+                    Flags::FLAG_EMPTY
+                },
+                price: 100. + (i % 17) as f32 * 0.25,
+                size: (i % 5) as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    #[serial]
+    fn should_round_trip_through_parquet_and_ipc() {
+        let ups = updates(150_000);
+        encode(FNAME, "BTC-USD", &ups).unwrap();
+
+        wstf_to_parquet(FNAME, FNAME_PARQUET).unwrap();
+        assert_eq!(read_parquet(FNAME_PARQUET).unwrap(), ups);
+        parquet_to_wstf(FNAME_PARQUET, FNAME, "BTC-USD", &Default::default()).unwrap();
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+
+        wstf_range_to_parquet(FNAME, FNAME_PARQUET, 2_000, 2_990).unwrap();
+        assert_eq!(read_parquet(FNAME_PARQUET).unwrap(), ups[100..200].to_vec());
+
+        write_ipc(File::create(FNAME_IPC).unwrap(), &ups).unwrap();
+        ipc_to_wstf(FNAME_IPC, FNAME, "BTC-USD", &Default::default()).unwrap();
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+    }
+
+    #[test]
+    fn should_cast_foreign_columns() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("ts", DataType::Int64, false),
+            Field::new("is_trade", DataType::Boolean, false),
+            Field::new("is_bid", DataType::Boolean, false),
+            Field::new("price", DataType::Float64, false),
+            Field::new("size", DataType::Float64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1_000, 1_001])),
+                Arc::new(BooleanArray::from(vec![true, false])),
+                Arc::new(BooleanArray::from(vec![false, true])),
+                Arc::new(Float64Array::from(vec![10.5, 10.25])),
+                Arc::new(Float64Array::from(vec![1., 2.])),
+            ],
+        )
+        .unwrap();
+        let ups = from_record_batch(&batch).unwrap();
+        assert_eq!(ups.len(), 2);
+        assert_eq!(ups[1].ts, 1_001);
+        assert_eq!(ups[1].seq, 0);
+        assert!(ups[1].is_bid && !ups[1].is_trade);
+        assert_eq!(ups[1].price, 10.25);
+
+        let batch = batch.project(&[0, 1, 2, 3]).unwrap();
+        assert!(matches!(
+            from_record_batch(&batch),
+            Err(WstfError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,502 @@
+//! Imports vendor CSV files into WSTF through a column mapping.
+//!
+//! Rows are streamed into a `WstfWriter` in file order. A row that cannot be mapped, or that is
+//! older than the previous imported row, is recorded in the `ImportReport` with its line number
+//! and skipped.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+
+use chrono::{DateTime, NaiveDateTime};
+use csv::{ReaderBuilder, StringRecord};
+
+use crate::error::WstfError;
+use crate::protocol::file_format::EncodeOptions;
+use crate::protocol::time_unit::TimeUnit;
+use crate::protocol::writer::WstfWriter;
+use crate::update::{Flags, Update};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// An integer or decimal count of `TimeUnit` since the epoch, e.g. `1700000000.123` seconds.
+    Epoch(TimeUnit),
+    /// RFC 3339, e.g. `2023-11-14T22:13:20.123Z`.
+    Iso8601,
+    /// A `chrono` format string without an offset, read as UTC.
+    Pattern(String),
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat::Epoch(TimeUnit::Millis)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SideEncoding {
+    /// A column whose values are matched, ignoring case, against the bid and ask lists.
+    Column {
+        column: String,
+        bid: Vec<String>,
+        ask: Vec<String>,
+    },
+    /// A negative size is an ask or a sell; the absolute size is stored.
+    SignedSize,
+}
+
+impl Default for SideEncoding {
+    fn default() -> Self {
+        SideEncoding::Column {
+            column: "side".to_owned(),
+            bid: vec!["bid".to_owned(), "buy".to_owned(), "b".to_owned()],
+            ask: vec![
+                "ask".to_owned(),
+                "sell".to_owned(),
+                "a".to_owned(),
+                "s".to_owned(),
+            ],
+        }
+    }
+}
+
+/// Tells trade rows from book rows.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RowKind {
+    Trades,
+    #[default]
+    Book,
+    /// A column whose values are matched, ignoring case, against the trade and book lists.
+    Column {
+        column: String,
+        trade: Vec<String>,
+        book: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvMapping {
+    /// Symbol of the output file.
+    pub symbol: String,
+    /// When set, rows whose value in this column is not `symbol` are skipped.
+    pub symbol_column: Option<String>,
+    pub ts: String,
+    /// Timestamps finer than `EncodeOptions::time_unit` are truncated.
+    pub ts_format: TimestampFormat,
+    pub seq: Option<String>,
+    /// Gives rows whose `seq` is not a number a `seq` of zero instead of reporting them.
+    pub lenient_seq: bool,
+    pub price: String,
+    pub size: String,
+    pub side: SideEncoding,
+    pub kind: RowKind,
+    pub delimiter: u8,
+}
+
+impl Default for CsvMapping {
+    fn default() -> Self {
+        CsvMapping {
+            symbol: String::new(),
+            symbol_column: None,
+            ts: "ts".to_owned(),
+            ts_format: TimestampFormat::default(),
+            seq: None,
+            lenient_seq: false,
+            price: "price".to_owned(),
+            size: "size".to_owned(),
+            side: SideEncoding::default(),
+            kind: RowKind::default(),
+            delimiter: b',',
+        }
+    }
+}
+
+impl CsvMapping {
+    /// Kaiko trade exports, `id,date,price,amount,sell`. Some venues have non-numeric trade
+    /// ids, which get a `seq` of zero.
+    pub fn kaiko(symbol: &str) -> CsvMapping {
+        CsvMapping {
+            symbol: symbol.to_owned(),
+            ts: "date".to_owned(),
+            seq: Some("id".to_owned()),
+            lenient_seq: true,
+            size: "amount".to_owned(),
+            side: SideEncoding::Column {
+                column: "sell".to_owned(),
+                bid: vec!["false".to_owned(), String::new()],
+                ask: vec!["true".to_owned()],
+            },
+            kind: RowKind::Trades,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRow {
+    pub line: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ImportReport {
+    pub imported: u64,
+    /// Rows of other symbols, see `CsvMapping::symbol_column`.
+    pub skipped: u64,
+    pub invalid: Vec<InvalidRow>,
+}
+
+/// Column positions resolved from the header row.
+struct Columns {
+    symbol: Option<usize>,
+    ts: usize,
+    seq: Option<usize>,
+    price: usize,
+    size: usize,
+    side: Option<usize>,
+    kind: Option<usize>,
+}
+
+impl Columns {
+    fn new(headers: &StringRecord, mapping: &CsvMapping) -> Result<Columns, WstfError> {
+        let find = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim() == name)
+                .ok_or_else(|| WstfError::Corrupt(format!("missing column {}", name)))
+        };
+        let find_opt = |name: Option<&String>| name.map(|name| find(name)).transpose();
+        Ok(Columns {
+            symbol: find_opt(mapping.symbol_column.as_ref())?,
+            ts: find(&mapping.ts)?,
+            seq: find_opt(mapping.seq.as_ref())?,
+            price: find(&mapping.price)?,
+            size: find(&mapping.size)?,
+            side: match &mapping.side {
+                SideEncoding::Column { column, .. } => Some(find(column)?),
+                SideEncoding::SignedSize => None,
+            },
+            kind: match &mapping.kind {
+                RowKind::Column { column, .. } => Some(find(column)?),
+                _ => None,
+            },
+        })
+    }
+}
+
+fn matches_any(value: &str, names: &[String]) -> bool {
+    names.iter().any(|name| name.eq_ignore_ascii_case(value))
+}
+
+/// Parses a decimal count of `unit` into nanoseconds, dropping digits below a nanosecond.
+fn parse_epoch(s: &str, unit: TimeUnit) -> Option<u128> {
+    let nanos_per_unit = u128::from(1_000_000_000 / unit.per_second());
+    let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+    if !frac.bytes().all(|b| b.is_ascii_digit()) || frac.len() > 18 {
+        return None;
+    }
+    let whole: u128 = whole.parse().ok()?;
+    let frac_nanos = if frac.is_empty() {
+        0
+    } else {
+        frac.parse::<u128>().ok()? * nanos_per_unit / 10u128.pow(frac.len() as u32)
+    };
+    whole.checked_mul(nanos_per_unit)?.checked_add(frac_nanos)
+}
+
+pub(crate) fn parse_timestamp(
+    s: &str,
+    format: &TimestampFormat,
+    unit: TimeUnit,
+) -> Result<u64, String> {
+    let invalid = || format!("invalid ts {:?}", s);
+    let nanos = match format {
+        TimestampFormat::Epoch(src) => parse_epoch(s, *src).ok_or_else(invalid)?,
+        TimestampFormat::Iso8601 => {
+            let datetime = DateTime::parse_from_rfc3339(s).map_err(|_| invalid())?;
+            u128::try_from(datetime.timestamp_nanos_opt().ok_or_else(invalid)?)
+                .map_err(|_| invalid())?
+        }
+        TimestampFormat::Pattern(pattern) => {
+            let datetime = NaiveDateTime::parse_from_str(s, pattern).map_err(|_| invalid())?;
+            u128::try_from(
+                datetime
+                    .and_utc()
+                    .timestamp_nanos_opt()
+                    .ok_or_else(invalid)?,
+            )
+            .map_err(|_| invalid())?
+        }
+    };
+    u64::try_from(nanos / u128::from(1_000_000_000 / unit.per_second())).map_err(|_| invalid())
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str, name: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid {} {:?}", name, s))
+}
+
+/// Maps a record to an update, or to `None` when it belongs to another symbol.
+fn parse_row(
+    record: &StringRecord,
+    cols: &Columns,
+    mapping: &CsvMapping,
+    unit: TimeUnit,
+) -> Result<Option<Update>, String> {
+    let field = |idx: usize| record.get(idx).map(str::trim).unwrap_or("");
+    if let Some(idx) = cols.symbol {
+        if field(idx) != mapping.symbol {
+            return Ok(None);
+        }
+    }
+
+    let mut size: f32 = parse_number(field(cols.size), "size")?;
+    let is_bid = match (&mapping.side, cols.side) {
+        (SideEncoding::Column { bid, ask, .. }, Some(idx)) => {
+            let side = field(idx);
+            if matches_any(side, bid) {
+                true
+            } else if matches_any(side, ask) {
+                false
+            } else {
+                return Err(format!("unknown side {:?}", side));
+            }
+        }
+        _ => {
+            let is_bid = size.is_sign_positive();
+            size = size.abs();
+            is_bid
+        }
+    };
+    let is_trade = match (&mapping.kind, cols.kind) {
+        (RowKind::Trades, _) => true,
+        (RowKind::Column { trade, book, .. }, Some(idx)) => {
+            let kind = field(idx);
+            if matches_any(kind, trade) {
+                true
+            } else if matches_any(kind, book) {
+                false
+            } else {
+                return Err(format!("unknown row kind {:?}", kind));
+            }
+        }
+        _ => false,
+    };
+
+    Ok(Some(Update {
+        ts: parse_timestamp(field(cols.ts), &mapping.ts_format, unit)?,
+        seq: match cols.seq {
+            Some(idx) if mapping.lenient_seq => field(idx).parse().unwrap_or(0),
+            Some(idx) => parse_number(field(idx), "seq")?,
+            None => 0,
+        },
+        is_trade,
+        is_bid,
+        flags: Flags::FLAG_EMPTY,
+        price: parse_number(field(cols.price), "price")?,
+        size,
+    }))
+}
+
+/// Streams the rows of a CSV with a header row into `wtr`.
+///
+/// Fails on I/O errors and missing columns; every other bad row ends up in the report.
+pub fn import_csv<R: Read, W: Write + Seek>(
+    rdr: R,
+    wtr: W,
+    mapping: &CsvMapping,
+    opts: &EncodeOptions,
+) -> Result<(ImportReport, W), WstfError> {
+    let mut csv = ReaderBuilder::new()
+        .delimiter(mapping.delimiter)
+        .flexible(true)
+        .from_reader(rdr);
+    let cols = Columns::new(csv.headers()?, mapping)?;
+    let mut writer = WstfWriter::with_options(wtr, &mapping.symbol, opts)?;
+    let mut report = ImportReport::default();
+    let mut last_ts = 0;
+
+    let mut record = StringRecord::new();
+    loop {
+        let line = csv.position().line();
+        match csv.read_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) if err.is_io_error() => return Err(err.into()),
+            Err(err) => {
+                report.invalid.push(InvalidRow {
+                    line,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+        }
+        let line = record.position().map_or(line, |pos| pos.line());
+        let up = match parse_row(&record, &cols, mapping, opts.time_unit) {
+            Ok(Some(up)) if up.ts >= last_ts => up,
+            Ok(Some(up)) => {
+                report.invalid.push(InvalidRow {
+                    line,
+                    reason: format!("ts {} is older than the previous row", up.ts),
+                });
+                continue;
+            }
+            Ok(None) => {
+                report.skipped += 1;
+                continue;
+            }
+            Err(reason) => {
+                report.invalid.push(InvalidRow { line, reason });
+                continue;
+            }
+        };
+        writer.push(&up)?;
+        last_ts = up.ts;
+        report.imported += 1;
+    }
+
+    Ok((report, writer.finish()?))
+}
+
+pub fn import_csv_file(
+    input: &str,
+    fname: &str,
+    mapping: &CsvMapping,
+    opts: &EncodeOptions,
+) -> Result<ImportReport, WstfError> {
+    let wtr = BufWriter::new(File::create(fname)?);
+    let (report, mut wtr) = import_csv(File::open(input)?, wtr, mapping, opts)?;
+    wtr.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::filetype::parse_kaiko_csv_to_wstf_inner;
+    use crate::protocol::file_format::{decode, range, Version};
+    use serial_test::serial;
+    use std::io::Cursor;
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+    static FNAME_CSV: &str = "./internal/mocks/tmp.csv";
+
+    #[test]
+    fn should_map_columns_and_report_invalid_rows() {
+        let csv = "\
+time;instrument;type;side;px;qty;id
+2023-11-14 22:13:20.001;BTC-USD;l2;buy;100.5;1;7
+2023-11-14 22:13:20.002;ETH-USD;l2;buy;20.5;1;8
+2023-11-14 22:13:20.003;BTC-USD;match;SELL;100.25;0.5;9
+2023-11-14 22:13:20.004;BTC-USD;l2;hold;100.5;1;10
+2023-11-14 22:13:20.000;BTC-USD;l2;sell;101;2;11
+garbage;BTC-USD;l2;sell;101;2;12
+2023-11-14 22:13:20.005;BTC-USD;l2;sell;101;0;13
+";
+        let mapping = CsvMapping {
+            symbol: "BTC-USD".to_owned(),
+            symbol_column: Some("instrument".to_owned()),
+            ts: "time".to_owned(),
+            ts_format: TimestampFormat::Pattern("%Y-%m-%d %H:%M:%S%.f".to_owned()),
+            seq: Some("id".to_owned()),
+            price: "px".to_owned(),
+            size: "qty".to_owned(),
+            kind: RowKind::Column {
+                column: "type".to_owned(),
+                trade: vec!["match".to_owned()],
+                book: vec!["l2".to_owned()],
+            },
+            delimiter: b';',
+            ..Default::default()
+        };
+        let opts = EncodeOptions {
+            version: Version::V2,
+            ..Default::default()
+        };
+        let (report, wtr) =
+            import_csv(csv.as_bytes(), Cursor::new(vec![]), &mapping, &opts).unwrap();
+
+        assert_eq!(report.imported, 3);
+        assert_eq!(report.skipped, 1);
+        let lines: Vec<u64> = report.invalid.iter().map(|row| row.line).collect();
+        assert_eq!(lines, vec![5, 6, 7]);
+        assert!(report.invalid[0].reason.contains("hold"));
+
+        let ups = range(&mut Cursor::new(wtr.into_inner()), 0, u64::MAX).unwrap();
+        assert_eq!(ups.len(), 3);
+        assert_eq!(ups[0].ts, 1_700_000_000_001);
+        assert!(ups[0].is_bid && !ups[0].is_trade);
+        assert!(!ups[1].is_bid && ups[1].is_trade);
+        assert_eq!((ups[1].seq, ups[1].price, ups[1].size), (9, 100.25, 0.5));
+        assert_eq!(ups[2].size, 0.);
+    }
+
+    #[test]
+    fn should_parse_epoch_timestamps() {
+        let seconds = TimestampFormat::Epoch(TimeUnit::Seconds);
+        assert_eq!(
+            parse_timestamp("1700000000.123456", &seconds, TimeUnit::Micros),
+            Ok(1_700_000_000_123_456)
+        );
+        assert_eq!(
+            parse_timestamp("1700000000.123456", &seconds, TimeUnit::Millis),
+            Ok(1_700_000_000_123)
+        );
+        let micros = TimestampFormat::Epoch(TimeUnit::Micros);
+        assert_eq!(
+            parse_timestamp("1700000000123456", &micros, TimeUnit::Millis),
+            Ok(1_700_000_000_123)
+        );
+        assert_eq!(
+            parse_timestamp(
+                "2023-11-14T22:13:20.5Z",
+                &TimestampFormat::Iso8601,
+                TimeUnit::Millis
+            ),
+            Ok(1_700_000_000_500)
+        );
+        assert!(parse_timestamp("-1", &seconds, TimeUnit::Millis).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn should_import_kaiko_trades() {
+        let csv =
+            "id,date,price,amount,sell\n1,1000,10.5,1,false\n2,1001,10.25,2,\n3,1002,10,3,true\n";
+        std::fs::write(FNAME_CSV, csv).unwrap();
+        let _ = std::fs::remove_file(FNAME);
+        let report = import_csv_file(
+            FNAME_CSV,
+            FNAME,
+            &CsvMapping::kaiko("BTC-USD"),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(report.imported, 3);
+        assert!(report.invalid.is_empty());
+
+        let ups = decode(FNAME, None).unwrap();
+        assert!(ups.iter().all(|up| up.is_trade));
+        assert_eq!(
+            ups.iter().map(|up| up.is_bid).collect::<Vec<_>>(),
+            vec![true, true, false]
+        );
+        assert_eq!(ups[2].seq, 3);
+
+        let uuid = "id,date,price,amount,sell\n9b2f0c1e-5d4a,1003,10,1,true\n";
+        assert_eq!(parse_kaiko_csv_to_wstf_inner("BTC-USD", FNAME, uuid), None);
+        let appended = decode(FNAME, None).unwrap();
+        assert_eq!(appended[..3], ups[..]);
+        assert_eq!((appended[3].ts, appended[3].seq), (1003, 0));
+
+        std::fs::remove_file(FNAME).unwrap();
+        assert_eq!(parse_kaiko_csv_to_wstf_inner("BTC-USD", FNAME, csv), None);
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+        assert!(parse_kaiko_csv_to_wstf_inner("BTC-USD", FNAME, "id,date\n1,1000\n").is_some());
+        assert!(parse_kaiko_csv_to_wstf_inner(
+            "BTC-USD",
+            FNAME,
+            "id,date,price,amount,sell\n1,x,1,1,\n"
+        )
+        .is_some());
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+    }
+}
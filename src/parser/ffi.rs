@@ -1,15 +1,89 @@
+//! C ABI, declared in `include/wstf.h`.
+//!
+//! Every `wstf_*` function returns a `WstfStatus`, writes its result through `out` only on
+//! success and never unwinds into the caller. When `err` is not null it receives an owned
+//! message on failure, released with `wstf_str_free`, and null on success.
+
 use super::filetype::parse_kaiko_csv_to_wstf_inner;
-use crate::protocol::file_format::{decode, decode_buffer};
+use crate::error::WstfError;
+use crate::protocol::file_format::{decode, decode_buffer, get_range_in_file, read_meta};
 use crate::update::{Update, UpdateVecConvert};
 use libc::{c_char, c_uchar};
 
 use std::ffi::{CStr, CString};
-use std::{mem, ptr, slice};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WstfStatus {
+    Ok = 0,
+    NullArgument = 1,
+    InvalidString = 2,
+    Io = 3,
+    InvalidFile = 4,
+    Unsupported = 5,
+    Panic = 6,
+}
 
+/// An owned array of updates, released with `wstf_slice_free`.
 #[repr(C)]
 pub struct Slice {
-    ptr: *mut Update,
-    len: usize,
+    pub ptr: *mut Update,
+    pub len: usize,
+}
+
+impl Slice {
+    fn from_vec(ups: Vec<Update>) -> Slice {
+        let ups = Box::into_raw(ups.into_boxed_slice());
+        Slice {
+            ptr: ups as *mut Update,
+            len: ups.len(),
+        }
+    }
+
+    fn empty() -> Slice {
+        Slice::from_vec(vec![])
+    }
+}
+
+#[repr(C)]
+pub struct WstfMetadata {
+    /// Owned symbol, released with `wstf_metadata_free`.
+    pub symbol: *mut c_char,
+    pub nums: u64,
+    pub min_ts: u64,
+    pub max_ts: u64,
+    /// Timestamp units per second, 1000 for milliseconds.
+    pub ts_per_second: u64,
+}
+
+struct FfiError {
+    status: WstfStatus,
+    message: String,
+}
+
+impl FfiError {
+    fn new(status: WstfStatus, message: &str) -> FfiError {
+        FfiError {
+            status,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl From<WstfError> for FfiError {
+    fn from(err: WstfError) -> FfiError {
+        let status = match err {
+            WstfError::Io(_) => WstfStatus::Io,
+            WstfError::Unsupported(_) => WstfStatus::Unsupported,
+            _ => WstfStatus::InvalidFile,
+        };
+        FfiError {
+            status,
+            message: err.to_string(),
+        }
+    }
 }
 
 unsafe fn ptr_to_str<'a>(ptr: *const c_char) -> Result<&'a str, ()> {
@@ -19,121 +93,322 @@ unsafe fn ptr_to_str<'a>(ptr: *const c_char) -> Result<&'a str, ()> {
     CStr::from_ptr(ptr).to_str().map_err(|_| ())
 }
 
-#[no_mangle]
-pub extern "C" fn read_wstf_to_csv(fname: *const c_char) -> *mut c_char {
-    let c_str = unsafe {
-        assert!(!fname.is_null());
-        CStr::from_ptr(fname)
+unsafe fn arg_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, FfiError> {
+    if ptr.is_null() {
+        return Err(FfiError::new(
+            WstfStatus::NullArgument,
+            &format!("{} is null", name),
+        ));
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| {
+        FfiError::new(
+            WstfStatus::InvalidString,
+            &format!("{} is not valid UTF-8", name),
+        )
+    })
+}
+
+fn to_c_string(s: String) -> Result<*mut c_char, FfiError> {
+    CString::new(s)
+        .map(CString::into_raw)
+        .map_err(|_| FfiError::new(WstfStatus::InvalidString, "output contains a NUL byte"))
+}
+
+/// Copies `message` into a C string, dropping interior NULs so it is never lost.
+fn error_message(message: &str) -> *mut c_char {
+    CString::new(message.replace('\0', "")).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Runs `f`, storing its value in `out` or its error in `err`, and turns panics into
+/// `WstfStatus::Panic`.
+unsafe fn call<T, F: FnOnce() -> Result<T, FfiError>>(
+    out: *mut T,
+    err: *mut *mut c_char,
+    f: F,
+) -> WstfStatus {
+    if !err.is_null() {
+        *err = ptr::null_mut();
+    }
+    let result = if out.is_null() {
+        Err(FfiError::new(WstfStatus::NullArgument, "out is null"))
+    } else {
+        panic::catch_unwind(AssertUnwindSafe(f))
+            .unwrap_or_else(|_| Err(FfiError::new(WstfStatus::Panic, "wstf panicked")))
     };
-    let fname = c_str.to_str().unwrap();
+    match result {
+        Ok(value) => {
+            out.write(value);
+            WstfStatus::Ok
+        }
+        Err(e) => {
+            if !err.is_null() {
+                *err = error_message(&e.message);
+            }
+            e.status
+        }
+    }
+}
 
-    let ups = decode(fname, None).unwrap();
-    let data = ups.as_csv();
+/// Decodes every update of `fname` into `out`.
+///
+/// # Safety
+///
+/// `fname` must be null or a NUL terminated string, `out` and `err` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_decode(
+    fname: *const c_char,
+    out: *mut Slice,
+    err: *mut *mut c_char,
+) -> WstfStatus {
+    call(out, err, || {
+        Ok(Slice::from_vec(decode(arg_str(fname, "fname")?, None)?))
+    })
+}
 
-    let ret = String::from(data);
-    let c_str_song = CString::new(ret).unwrap();
-    c_str_song.into_raw()
+/// Decodes the first `num` batches of `fname` into `out`.
+///
+/// # Safety
+///
+/// See `wstf_decode`.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_decode_with_limit(
+    fname: *const c_char,
+    num: u32,
+    out: *mut Slice,
+    err: *mut *mut c_char,
+) -> WstfStatus {
+    call(out, err, || {
+        Ok(Slice::from_vec(decode(
+            arg_str(fname, "fname")?,
+            Some(num),
+        )?))
+    })
 }
 
+/// Decodes the updates of `fname` with `min_ts <= ts <= max_ts` into `out`.
+///
+/// # Safety
+///
+/// See `wstf_decode`.
 #[no_mangle]
-pub extern "C" fn read_wstf_to_csv_with_limit(fname: *const c_char, num: u32) -> *mut c_char {
-    let c_str = unsafe {
-        assert!(!fname.is_null());
-        CStr::from_ptr(fname)
-    };
-    let fname = c_str.to_str().unwrap();
+pub unsafe extern "C" fn wstf_range(
+    fname: *const c_char,
+    min_ts: u64,
+    max_ts: u64,
+    out: *mut Slice,
+    err: *mut *mut c_char,
+) -> WstfStatus {
+    call(out, err, || {
+        let ups = get_range_in_file(arg_str(fname, "fname")?, min_ts, max_ts)?;
+        Ok(Slice::from_vec(ups))
+    })
+}
 
-    let ups = decode(fname, Some(num)).unwrap();
-    let data = ups.as_csv();
+/// Decodes the batches of an in-memory stream into `out`.
+///
+/// # Safety
+///
+/// `buf` must be null or point to `len` readable bytes, `out` and `err` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_decode_buffer(
+    buf: *const c_uchar,
+    len: usize,
+    out: *mut Slice,
+    err: *mut *mut c_char,
+) -> WstfStatus {
+    call(out, err, || {
+        if buf.is_null() {
+            return Err(FfiError::new(WstfStatus::NullArgument, "buf is null"));
+        }
+        let mut bytes = slice::from_raw_parts(buf, len);
+        Ok(Slice::from_vec(decode_buffer(&mut bytes)?))
+    })
+}
 
-    let ret = String::from(data);
-    let c_str_song = CString::new(ret).unwrap();
-    c_str_song.into_raw()
+/// Renders every update of `fname` as CSV into `out`, released with `wstf_str_free`.
+///
+/// # Safety
+///
+/// See `wstf_decode`.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_to_csv(
+    fname: *const c_char,
+    out: *mut *mut c_char,
+    err: *mut *mut c_char,
+) -> WstfStatus {
+    call(out, err, || {
+        to_c_string(decode(arg_str(fname, "fname")?, None)?.as_csv())
+    })
 }
 
+/// Reads the header summary of `fname` into `out`, released with `wstf_metadata_free`.
+///
+/// # Safety
+///
+/// See `wstf_decode`.
 #[no_mangle]
-pub extern "C" fn read_wstf_to_arr(fname: *const c_char) -> Slice {
-    let c_str = unsafe {
-        assert!(!fname.is_null());
-        CStr::from_ptr(fname)
-    };
+pub unsafe extern "C" fn wstf_metadata(
+    fname: *const c_char,
+    out: *mut WstfMetadata,
+    err: *mut *mut c_char,
+) -> WstfStatus {
+    call(out, err, || {
+        let meta = read_meta(arg_str(fname, "fname")?)?;
+        Ok(WstfMetadata {
+            symbol: to_c_string(meta.symbol)?,
+            nums: meta.nums,
+            min_ts: meta.min_ts,
+            max_ts: meta.max_ts,
+            ts_per_second: meta.time_unit.per_second(),
+        })
+    })
+}
 
-    let fname = c_str.to_str().unwrap();
-    let mut ups = decode(fname, None).unwrap();
-    let p = ups.as_mut_ptr();
-    let len = ups.len();
+/// Releases the updates of a slice returned by this library.
+///
+/// # Safety
+///
+/// `slice` must come from this library and must not be used or released again.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_slice_free(slice: Slice) {
+    if !slice.ptr.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            slice.ptr, slice.len,
+        )));
+    }
+}
 
-    mem::forget(ups);
+/// Releases the symbol of `meta` and resets it to null.
+///
+/// # Safety
+///
+/// `meta` must be null or filled by `wstf_metadata`.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_metadata_free(meta: *mut WstfMetadata) {
+    if let Some(meta) = meta.as_mut() {
+        wstf_str_free(meta.symbol);
+        meta.symbol = ptr::null_mut();
+    }
+}
 
-    Slice { ptr: p, len }
+/// Releases a string returned by this library.
+///
+/// # Safety
+///
+/// `s` must be null or come from this library, and must not be used or released again.
+#[no_mangle]
+pub unsafe extern "C" fn wstf_str_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
 }
 
+/// Superseded by `wstf_to_csv`, returns null on failure.
+///
+/// # Safety
+///
+/// `fname` must be null or a NUL terminated string.
 #[no_mangle]
-pub extern "C" fn read_wstf_to_arr_with_limit(fname: *const c_char, num: u32) -> Slice {
-    let c_str = unsafe {
-        assert!(!fname.is_null());
-        CStr::from_ptr(fname)
-    };
+pub unsafe extern "C" fn read_wstf_to_csv(fname: *const c_char) -> *mut c_char {
+    let mut csv = ptr::null_mut();
+    wstf_to_csv(fname, &mut csv, ptr::null_mut());
+    csv
+}
 
-    let fname = c_str.to_str().unwrap();
-    let mut ups = decode(fname, Some(num)).unwrap();
-    let p = ups.as_mut_ptr();
-    let len = ups.len();
+/// Superseded by `wstf_decode_with_limit` and `wstf_to_csv`, returns null on failure.
+///
+/// # Safety
+///
+/// `fname` must be null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn read_wstf_to_csv_with_limit(
+    fname: *const c_char,
+    num: u32,
+) -> *mut c_char {
+    let mut csv = ptr::null_mut();
+    call(&mut csv, ptr::null_mut(), || {
+        to_c_string(decode(arg_str(fname, "fname")?, Some(num))?.as_csv())
+    });
+    csv
+}
 
-    mem::forget(ups);
+/// Superseded by `wstf_decode`, returns an empty slice on failure.
+///
+/// # Safety
+///
+/// `fname` must be null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn read_wstf_to_arr(fname: *const c_char) -> Slice {
+    let mut ups = Slice::empty();
+    wstf_decode(fname, &mut ups, ptr::null_mut());
+    ups
+}
 
-    Slice { ptr: p, len }
+/// Superseded by `wstf_decode_with_limit`, returns an empty slice on failure.
+///
+/// # Safety
+///
+/// `fname` must be null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn read_wstf_to_arr_with_limit(fname: *const c_char, num: u32) -> Slice {
+    let mut ups = Slice::empty();
+    wstf_decode_with_limit(fname, num, &mut ups, ptr::null_mut());
+    ups
 }
 
+/// Returns null on success, or an error message released with `wstf_str_free`.
+///
+/// # Safety
+///
+/// Every argument must be null or a NUL terminated string.
 #[no_mangle]
 pub unsafe extern "C" fn parse_kaiko_csv_to_wstf(
     symbol: *const c_char,
     fname: *const c_char,
     csv_str: *const c_char,
-) -> *const c_char {
-    let symbol = match ptr_to_str(symbol) {
-        Ok(symbol) => symbol,
-        Err(()) => return CString::new("Symbol was invalid.").unwrap().into_raw(),
-    };
+) -> *mut c_char {
+    let result = panic::catch_unwind(|| {
+        let symbol = match ptr_to_str(symbol) {
+            Ok(symbol) => symbol,
+            Err(()) => return error_message("Symbol was invalid."),
+        };
 
-    let fname = match ptr_to_str(fname) {
-        Ok(fname) => fname,
-        Err(()) => return CString::new("Filename was invalid.").unwrap().into_raw(),
-    };
+        let fname = match ptr_to_str(fname) {
+            Ok(fname) => fname,
+            Err(()) => return error_message("Filename was invalid."),
+        };
 
-    let csv_str = match ptr_to_str(csv_str) {
-        Ok(csv_str) => csv_str,
-        Err(()) => return CString::new("CSV String was invalid.").unwrap().into_raw(),
-    };
+        let csv_str = match ptr_to_str(csv_str) {
+            Ok(csv_str) => csv_str,
+            Err(()) => return error_message("CSV String was invalid."),
+        };
 
-    match parse_kaiko_csv_to_wstf_inner(symbol, fname, csv_str) {
-        Some(err) => CString::new(err).unwrap().into_raw(),
-        None => ptr::null(),
-    }
+        match parse_kaiko_csv_to_wstf_inner(symbol, fname, csv_str) {
+            Some(err) => error_message(&err),
+            None => ptr::null_mut(),
+        }
+    });
+    result.unwrap_or_else(|_| error_message("wstf panicked"))
 }
 
+/// Superseded by `wstf_decode_buffer`, returns an empty slice on failure.
+///
+/// # Safety
+///
+/// `n` must be null or point to `len` readable bytes.
 #[no_mangle]
-pub extern "C" fn parse_stream(n: *mut c_uchar, len: u32) -> Slice {
-    let mut byte_arr = unsafe {
-        assert!(!n.is_null());
-        slice::from_raw_parts(n, len as usize)
-    };
-
-    let mut v = decode_buffer(&mut byte_arr);
-
-    let p = v.as_mut_ptr();
-    let len = v.len();
-    mem::forget(v);
-    Slice { ptr: p, len }
+pub unsafe extern "C" fn parse_stream(n: *mut c_uchar, len: u32) -> Slice {
+    let mut ups = Slice::empty();
+    wstf_decode_buffer(n, len as usize, &mut ups, ptr::null_mut());
+    ups
 }
 
+/// Superseded by `wstf_str_free`.
+///
+/// # Safety
+///
+/// See `wstf_str_free`.
 #[no_mangle]
-pub extern "C" fn str_free(s: *mut c_char) {
-    unsafe {
-        if s.is_null() {
-            return;
-        }
-
-        let _ = CString::from_raw(s);
-    };
+pub unsafe extern "C" fn str_free(s: *mut c_char) {
+    wstf_str_free(s)
 }
@@ -1,8 +1,8 @@
-use crate::protocol::file_format::{append, encode, read_magic_value};
-use crate::update::Update;
-use csv::{DeserializeRecordsIntoIter, ReaderBuilder};
+use crate::error::WstfError;
+use crate::parser::csv_import::{import_csv, CsvMapping};
+use crate::protocol::file_format::{append, range, read_magic_value, EncodeOptions};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Cursor};
 use std::path::Path;
 
 #[derive(Serialize)]
@@ -17,67 +17,40 @@ impl Default for FileType {
 }
 
 impl FileType {
-    pub fn from_fname(fname: &str) -> FileType {
-        let file = File::open(fname).expect("OPENING FILE");
+    pub fn from_fname(fname: &str) -> Result<FileType, WstfError> {
+        let file = File::open(fname)?;
         let mut rdr = BufReader::new(file);
 
-        if read_magic_value(&mut rdr).unwrap() {
-            return FileType::RawWstf;
-        }
-
-        unreachable!()
-    }
-}
-
-#[derive(Deserialize)]
-struct KaikoCsvEntry {
-    pub id: String,
-    pub date: u64,
-    pub price: f32,
-    pub amount: f32,
-    pub sell: Option<bool>,
-}
-
-impl Into<Update> for KaikoCsvEntry {
-    fn into(self) -> Update {
-        Update {
-            ts: self.date,
-            seq: self.id.parse().unwrap_or(0),
-            is_trade: true,
-            is_bid: !self.sell.unwrap_or(false),
-            price: self.price,
-            size: self.amount,
+        match read_magic_value(&mut rdr)? {
+            Some(_version) => Ok(FileType::RawWstf),
+            None => Err(WstfError::BadMagic),
         }
     }
 }
 
+/// Imports a Kaiko trade export with `import_csv`, appending to `filename` when it exists.
+///
+/// Returns the first error, or the first invalid row, without writing anything.
 pub fn parse_kaiko_csv_to_wstf_inner(
     symbol: &str,
     filename: &str,
     csv_str: &str,
 ) -> Option<String> {
-    let csv_reader = ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(csv_str.as_bytes());
-
-    let iter: DeserializeRecordsIntoIter<_, KaikoCsvEntry> = csv_reader.into_deserialize();
-    let size_hint = iter.size_hint().0;
-    let mut updates: Vec<Update> = Vec::with_capacity(size_hint);
-
-    for kaiko_entry_res in iter {
-        match kaiko_entry_res {
-            Ok(kaiko_entry) => updates.push(kaiko_entry.into()),
-            Err(err) => {
-                return Some(format!("{:?}", err));
-            }
-        }
+    let mapping = CsvMapping::kaiko(symbol);
+    let opts = EncodeOptions::default();
+    let (report, mut buf) =
+        match import_csv(csv_str.as_bytes(), Cursor::new(vec![]), &mapping, &opts) {
+            Ok(res) => res,
+            Err(err) => return Some(format!("{:?}", err)),
+        };
+    if let Some(row) = report.invalid.first() {
+        return Some(format!("line {}: {}", row.line, row.reason));
     }
 
-    let fpath = Path::new(&filename);
-    let res = if fpath.exists() {
-        append(filename, &updates)
+    let res = if Path::new(filename).exists() {
+        range(&mut buf, 0, u64::MAX).and_then(|updates| append(filename, &updates))
     } else {
-        encode(filename, symbol, &updates)
+        std::fs::write(filename, buf.into_inner()).map_err(WstfError::from)
     };
 
     match res {
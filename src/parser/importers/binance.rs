@@ -0,0 +1,156 @@
+//! Binance `depthUpdate`, `trade` and `aggTrade` stream messages, raw or wrapped by combined
+//! streams, and `{lastUpdateId, bids, asks}` REST depth snapshots.
+//!
+//! Timestamps come from the event time `E`, or the transaction time `T` when it is missing.
+//! Spot `/api/v3/depth` snapshots carry neither, so they are read with `parse_snapshot` and a
+//! timestamp supplied by the caller.
+
+use serde_json::Value;
+
+use super::{
+    decimal, from_millis, mark_snapshot, parse_json, push_levels, seq, str_field, u64_field,
+    Message,
+};
+use crate::protocol::time_unit::TimeUnit;
+use crate::update::{Flags, Update};
+
+fn event_ts(msg: &Value, unit: TimeUnit) -> Result<u64, String> {
+    u64_field(msg, "E")
+        .or_else(|| u64_field(msg, "T"))
+        .map(|ms| from_millis(ms, unit))
+        .ok_or_else(|| "missing event time".to_owned())
+}
+
+fn depth(msg: &Value, ts: Result<u64, String>) -> Result<Vec<Update>, String> {
+    let bids = msg.get("b").or_else(|| msg.get("bids"));
+    let asks = msg.get("a").or_else(|| msg.get("asks"));
+    if bids.is_none() && asks.is_none() {
+        return Ok(vec![]);
+    }
+    let snapshot = msg.get("lastUpdateId").is_some();
+    let update_id = u64_field(msg, "u")
+        .or_else(|| u64_field(msg, "lastUpdateId"))
+        .ok_or_else(|| "missing update id".to_owned())?;
+    let ts = ts?;
+
+    let mut ups = vec![];
+    push_levels(&mut ups, bids, ts, seq(update_id), true)?;
+    push_levels(&mut ups, asks, ts, seq(update_id), false)?;
+    if snapshot {
+        mark_snapshot(&mut ups);
+    }
+    Ok(ups)
+}
+
+fn trade(msg: &Value, unit: TimeUnit) -> Result<Vec<Update>, String> {
+    let field = |key: &str| msg.get(key).ok_or_else(|| format!("missing {}", key));
+    let trade_id = match str_field(msg, "e") {
+        Some("aggTrade") => u64_field(msg, "a"),
+        _ => u64_field(msg, "t"),
+    };
+    Ok(vec![Update {
+        ts: event_ts(msg, unit)?,
+        seq: seq(trade_id.unwrap_or(0)),
+        is_trade: true,
+        // `m` is set when the buyer is the maker, so the seller initiated the trade.
+        is_bid: !field("m")?.as_bool().unwrap_or(false),
+        flags: Flags::FLAG_EMPTY,
+        price: decimal(field("p")?)?,
+        size: decimal(field("q")?)?,
+    }])
+}
+
+pub fn parse_message(line: &str, unit: TimeUnit) -> Result<Message, String> {
+    let msg = parse_json(line)?;
+    let msg = msg.get("data").unwrap_or(&msg);
+    let updates = match str_field(msg, "e") {
+        Some("trade") | Some("aggTrade") => trade(msg, unit)?,
+        Some("depthUpdate") | None => depth(msg, event_ts(msg, unit))?,
+        Some(_) => vec![],
+    };
+    Ok(Message {
+        symbol: str_field(msg, "s").map(str::to_owned),
+        updates,
+    })
+}
+
+/// Reads a depth snapshot taken at `ts`, in the unit of the output file. Spot snapshots have no
+/// event time, so `ts` is usually the time the snapshot was fetched.
+pub fn parse_snapshot(line: &str, ts: u64) -> Result<Message, String> {
+    let msg = parse_json(line)?;
+    if msg.get("lastUpdateId").is_none() {
+        return Err("missing lastUpdateId".to_owned());
+    }
+    Ok(Message {
+        symbol: None,
+        updates: depth(&msg, Ok(ts))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_depth_and_trades() {
+        let msg = parse_message(
+            r#"{"stream":"btcusdt@depth","data":{"e":"depthUpdate","E":1700000000123,"s":"BTCUSDT","U":4294967290,"u":4294967300,"b":[["100.5","1.25"],["100","0"]],"a":[["101","3"]]}}"#,
+            TimeUnit::Micros,
+        )
+        .unwrap();
+        assert_eq!(msg.symbol.as_deref(), Some("BTCUSDT"));
+        assert_eq!(msg.updates.len(), 3);
+        assert!(msg
+            .updates
+            .iter()
+            .all(|up| up.ts == 1_700_000_000_123_000 && up.seq == 4));
+        assert_eq!(
+            msg.updates.iter().map(|up| up.is_bid).collect::<Vec<_>>(),
+            vec![true, true, false]
+        );
+        assert_eq!((msg.updates[0].price, msg.updates[0].size), (100.5, 1.25));
+
+        let snapshot = parse_message(
+            r#"{"lastUpdateId":160,"E":1000,"bids":[["1","2"]],"asks":[["3","4"]]}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        assert_eq!(
+            snapshot.updates[0].flags,
+            Flags::FLAG_SNAPSHOT_START | Flags::FLAG_RESET
+        );
+        assert_eq!(snapshot.updates[1].flags, Flags::FLAG_SNAPSHOT_END);
+
+        let spot = r#"{"lastUpdateId":1027024,"bids":[["4.00000000","431.00000000"]],"asks":[["4.00000200","12.00000000"]]}"#;
+        assert_eq!(
+            parse_message(spot, TimeUnit::Millis),
+            Err("missing event time".to_owned())
+        );
+        let snapshot = parse_snapshot(spot, 1_700_000_000_000).unwrap();
+        assert_eq!(snapshot.updates.len(), 2);
+        assert!(snapshot
+            .updates
+            .iter()
+            .all(|up| up.ts == 1_700_000_000_000 && up.seq == 1027024));
+        assert_eq!(
+            (snapshot.updates[1].price, snapshot.updates[1].size),
+            (4.000002, 12.)
+        );
+        assert!(parse_snapshot(r#"{"e":"depthUpdate","E":1,"u":1,"b":[]}"#, 1).is_err());
+
+        let agg = parse_message(
+            r#"{"e":"aggTrade","E":1000,"s":"BTCUSDT","a":26129,"p":"0.01633102","q":"4.70443515","f":100,"l":105,"T":999,"m":false}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        let trade = agg.updates[0];
+        assert!(trade.is_trade && trade.is_bid);
+        assert_eq!((trade.ts, trade.seq), (1000, 26129));
+
+        assert!(parse_message(r#"{"result":null,"id":1}"#, TimeUnit::Millis)
+            .unwrap()
+            .updates
+            .is_empty());
+        assert!(parse_message(r#"{"e":"depthUpdate","u":1,"b":[]}"#, TimeUnit::Millis).is_err());
+    }
+}
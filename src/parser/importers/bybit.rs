@@ -0,0 +1,120 @@
+//! Bybit v5 public `orderbook.*` and `publicTrade.*` messages.
+//!
+//! Timestamps come from the message `ts`.
+
+use serde_json::Value;
+
+use super::{
+    decimal, from_millis, mark_snapshot, parse_json, push_levels, seq, str_field, u64_field,
+    Message,
+};
+use crate::protocol::time_unit::TimeUnit;
+use crate::update::{Flags, Update};
+
+fn orderbook(msg: &Value, data: &Value, ts: u64) -> Result<Vec<Update>, String> {
+    let update_id = u64_field(data, "u").ok_or_else(|| "missing update id".to_owned())?;
+    let mut ups = vec![];
+    push_levels(&mut ups, data.get("b"), ts, seq(update_id), true)?;
+    push_levels(&mut ups, data.get("a"), ts, seq(update_id), false)?;
+    if str_field(msg, "type") == Some("snapshot") {
+        mark_snapshot(&mut ups);
+    }
+    Ok(ups)
+}
+
+fn trades(data: &Value, ts: u64) -> Result<Vec<Update>, String> {
+    let trades = data
+        .as_array()
+        .ok_or_else(|| "trade data is not an array".to_owned())?;
+    trades
+        .iter()
+        .map(|trade| {
+            let field = |key: &str| trade.get(key).ok_or_else(|| format!("missing {}", key));
+            Ok(Update {
+                ts,
+                // Trade ids are UUIDs on derivatives, so prefer the cross sequence.
+                seq: seq(u64_field(trade, "seq")
+                    .or_else(|| u64_field(trade, "i"))
+                    .unwrap_or(0)),
+                is_trade: true,
+                is_bid: str_field(trade, "S") == Some("Buy"),
+                flags: Flags::FLAG_EMPTY,
+                price: decimal(field("p")?)?,
+                size: decimal(field("v")?)?,
+            })
+        })
+        .collect()
+}
+
+pub fn parse_message(line: &str, unit: TimeUnit) -> Result<Message, String> {
+    let msg = parse_json(line)?;
+    let topic = match str_field(&msg, "topic") {
+        Some(topic) => topic,
+        None => return Ok(Message::default()),
+    };
+    let data = msg.get("data").ok_or_else(|| "missing data".to_owned())?;
+    let ts = u64_field(&msg, "ts")
+        .map(|ms| from_millis(ms, unit))
+        .ok_or_else(|| "missing ts".to_owned())?;
+    let (symbol, updates) = if topic.starts_with("orderbook.") {
+        (str_field(data, "s"), orderbook(&msg, data, ts)?)
+    } else if topic.starts_with("publicTrade.") {
+        (
+            data.get(0).and_then(|trade| str_field(trade, "s")),
+            trades(data, ts)?,
+        )
+    } else {
+        (None, vec![])
+    };
+    Ok(Message {
+        symbol: symbol.map(str::to_owned),
+        updates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_orderbook_and_trades() {
+        let snapshot = parse_message(
+            r#"{"topic":"orderbook.50.BTCUSDT","type":"snapshot","ts":1672304484978,"data":{"s":"BTCUSDT","b":[["16493.50","0.006"],["16493.00","0.100"]],"a":[["16611.00","0.029"]],"u":18521288,"seq":7961638724},"cts":1672304484976}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        assert_eq!(snapshot.symbol.as_deref(), Some("BTCUSDT"));
+        let ups = &snapshot.updates;
+        assert_eq!(ups.len(), 3);
+        assert!(ups
+            .iter()
+            .all(|up| up.ts == 1672304484978 && up.seq == 18521288));
+        assert_eq!(ups[0].flags, Flags::FLAG_SNAPSHOT_START | Flags::FLAG_RESET);
+        assert_eq!(ups[2].flags, Flags::FLAG_SNAPSHOT_END);
+        assert!(!ups[2].is_bid);
+
+        let delta = parse_message(
+            r#"{"topic":"orderbook.50.BTCUSDT","type":"delta","ts":1672304484979,"data":{"s":"BTCUSDT","b":[],"a":[["16611.00","0"]],"u":18521289}}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        assert_eq!(delta.updates[0].flags, Flags::FLAG_EMPTY);
+        assert_eq!(delta.updates[0].size, 0.);
+
+        let trades = parse_message(
+            r#"{"topic":"publicTrade.BTCUSDT","type":"snapshot","ts":1672304486868,"data":[{"T":1672304486865,"s":"BTCUSDT","S":"Buy","v":"0.001","p":"16578.50","L":"PlusTick","i":"20f43950-d8dd-5b31-9112-a178eb6023af","BT":false,"seq":1783284617}]}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        let trade = trades.updates[0];
+        assert!(trade.is_trade && trade.is_bid);
+        assert_eq!((trade.seq, trade.price), (1783284617, 16578.5));
+
+        assert!(
+            parse_message(r#"{"success":true,"op":"subscribe"}"#, TimeUnit::Millis)
+                .unwrap()
+                .updates
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Coinbase Exchange `snapshot`, `l2update`, `match` and `last_match` feed messages.
+//!
+//! `l2update` messages carry no sequence number, so their levels keep a `seq` of zero, and
+//! snapshots without a `time` are reported as invalid.
+
+use serde_json::Value;
+
+use super::{
+    decimal, from_iso8601, mark_snapshot, parse_json, push_levels, seq, str_field, u64_field,
+    Message,
+};
+use crate::protocol::time_unit::TimeUnit;
+use crate::update::{Flags, Update};
+
+fn time(msg: &Value, unit: TimeUnit) -> Result<u64, String> {
+    let time = str_field(msg, "time").ok_or_else(|| "missing time".to_owned())?;
+    from_iso8601(time, unit)
+}
+
+fn field<'a>(msg: &'a Value, key: &str) -> Result<&'a Value, String> {
+    msg.get(key).ok_or_else(|| format!("missing {}", key))
+}
+
+fn l2update(msg: &Value, unit: TimeUnit) -> Result<Vec<Update>, String> {
+    let ts = time(msg, unit)?;
+    let changes = field(msg, "changes")?
+        .as_array()
+        .ok_or_else(|| "changes is not an array".to_owned())?;
+    changes
+        .iter()
+        .map(|change| match change.as_array().map(Vec::as_slice) {
+            Some([side, price, size, ..]) => Ok(Update {
+                ts,
+                seq: 0,
+                is_trade: false,
+                is_bid: side.as_str() == Some("buy"),
+                flags: Flags::FLAG_EMPTY,
+                price: decimal(price)?,
+                size: decimal(size)?,
+            }),
+            _ => Err(format!("invalid change {}", change)),
+        })
+        .collect()
+}
+
+fn snapshot(msg: &Value, unit: TimeUnit) -> Result<Vec<Update>, String> {
+    let ts = time(msg, unit)?;
+    let update_id = u64_field(msg, "sequence").unwrap_or(0);
+    let mut ups = vec![];
+    push_levels(&mut ups, msg.get("bids"), ts, seq(update_id), true)?;
+    push_levels(&mut ups, msg.get("asks"), ts, seq(update_id), false)?;
+    mark_snapshot(&mut ups);
+    Ok(ups)
+}
+
+fn trade(msg: &Value, unit: TimeUnit) -> Result<Vec<Update>, String> {
+    Ok(vec![Update {
+        ts: time(msg, unit)?,
+        seq: seq(u64_field(msg, "sequence").unwrap_or(0)),
+        is_trade: true,
+        // `side` is the maker side, so a resting sell was lifted by a buyer.
+        is_bid: str_field(msg, "side") == Some("sell"),
+        flags: Flags::FLAG_EMPTY,
+        price: decimal(field(msg, "price")?)?,
+        size: decimal(field(msg, "size")?)?,
+    }])
+}
+
+pub fn parse_message(line: &str, unit: TimeUnit) -> Result<Message, String> {
+    let msg = parse_json(line)?;
+    let updates = match str_field(&msg, "type") {
+        Some("l2update") => l2update(&msg, unit)?,
+        Some("snapshot") => snapshot(&msg, unit)?,
+        Some("match") | Some("last_match") => trade(&msg, unit)?,
+        _ => vec![],
+    };
+    Ok(Message {
+        symbol: str_field(&msg, "product_id").map(str::to_owned),
+        updates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_level2_and_matches() {
+        let update = parse_message(
+            r#"{"type":"l2update","product_id":"BTC-USD","changes":[["buy","10101.80000000","0.162567"],["sell","10102.5","0"]],"time":"2019-08-14T20:42:27.265Z"}"#,
+            TimeUnit::Micros,
+        )
+        .unwrap();
+        assert_eq!(update.symbol.as_deref(), Some("BTC-USD"));
+        let ups = &update.updates;
+        assert_eq!(ups.len(), 2);
+        assert_eq!(ups[0].ts, 1_565_815_347_265_000);
+        assert!(ups[0].is_bid && !ups[1].is_bid);
+        assert_eq!(ups[1].size, 0.);
+
+        let matched = parse_message(
+            r#"{"type":"match","trade_id":10,"sequence":50,"maker_order_id":"ac928c66-ca53-498f-9c13-a110027a60e8","taker_order_id":"132fb6ae-456b-4654-b4e0-d681ac05cea1","time":"2014-11-07T08:19:27.028459Z","product_id":"BTC-USD","size":"5.23512","side":"sell","price":"400.23"}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        let trade = matched.updates[0];
+        assert!(trade.is_trade && trade.is_bid);
+        assert_eq!(
+            (trade.ts, trade.seq, trade.price),
+            (1_415_348_367_028, 50, 400.23)
+        );
+
+        let snapshot = parse_message(
+            r#"{"type":"snapshot","product_id":"BTC-USD","time":"2019-08-14T20:42:27.000Z","bids":[["10101.10","0.45054140"]],"asks":[["10102.55","0.57753524"]]}"#,
+            TimeUnit::Millis,
+        )
+        .unwrap();
+        assert_eq!(snapshot.updates.len(), 2);
+        assert!(snapshot.updates[1].flags.contains(Flags::FLAG_SNAPSHOT_END));
+
+        assert!(
+            parse_message(r#"{"type":"heartbeat","sequence":90}"#, TimeUnit::Millis)
+                .unwrap()
+                .updates
+                .is_empty()
+        );
+        assert!(parse_message(r#"{"type":"l2update","changes":[]}"#, TimeUnit::Millis).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Converters for public exchange market-data dumps with one JSON message per line.
+//!
+//! Depth messages expand into one `Update` per level, with `seq` set to the low 32 bits of the
+//! exchange update id. A size of zero removes the level. Snapshots are marked with
+//! `FLAG_SNAPSHOT_START` and `FLAG_RESET` on their first level and `FLAG_SNAPSHOT_END` on
+//! their last.
+//!
+//! Timestamps come from the exchange rather than from the time a line was recorded, so a
+//! recorded stream stays in order.
+
+pub mod binance;
+pub mod bybit;
+pub mod coinbase;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use super::csv_import::{parse_timestamp, ImportReport, InvalidRow, TimestampFormat};
+use crate::error::WstfError;
+use crate::protocol::file_format::EncodeOptions;
+use crate::protocol::time_unit::TimeUnit;
+use crate::protocol::writer::WstfWriter;
+use crate::update::{Flags, Update};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exchange {
+    Binance,
+    Bybit,
+    Coinbase,
+}
+
+impl Exchange {
+    pub fn parse_message(self, line: &str, unit: TimeUnit) -> Result<Message, String> {
+        match self {
+            Exchange::Binance => binance::parse_message(line, unit),
+            Exchange::Bybit => bybit::parse_message(line, unit),
+            Exchange::Coinbase => coinbase::parse_message(line, unit),
+        }
+    }
+}
+
+impl FromStr for Exchange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binance" => Ok(Exchange::Binance),
+            "bybit" => Ok(Exchange::Bybit),
+            "coinbase" => Ok(Exchange::Coinbase),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The updates of one message. Messages without market data, such as subscription
+/// acknowledgements and heartbeats, have none.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Message {
+    pub symbol: Option<String>,
+    pub updates: Vec<Update>,
+}
+
+pub(crate) fn parse_json(line: &str) -> Result<Value, String> {
+    serde_json::from_str(line).map_err(|e| e.to_string())
+}
+
+pub(crate) fn str_field<'a>(msg: &'a Value, key: &str) -> Option<&'a str> {
+    msg.get(key).and_then(Value::as_str)
+}
+
+/// Reads an id or timestamp stored either as a number or as a numeric string.
+pub(crate) fn u64_field(msg: &Value, key: &str) -> Option<u64> {
+    match msg.get(key)? {
+        Value::String(s) => s.parse().ok(),
+        value => value.as_u64(),
+    }
+}
+
+/// Reads a price or size stored either as a decimal string or as a number.
+pub(crate) fn decimal(value: &Value) -> Result<f32, String> {
+    match value {
+        Value::String(s) => s.parse().map_err(|_| format!("invalid decimal {:?}", s)),
+        Value::Number(n) => n
+            .as_f64()
+            .map(|n| n as f32)
+            .ok_or_else(|| format!("invalid decimal {}", n)),
+        _ => Err(format!("invalid decimal {}", value)),
+    }
+}
+
+pub(crate) fn seq(update_id: u64) -> u32 {
+    update_id as u32
+}
+
+pub(crate) fn from_millis(ms: u64, unit: TimeUnit) -> u64 {
+    (u128::from(ms) * u128::from(unit.per_second()) / 1_000) as u64
+}
+
+pub(crate) fn from_iso8601(s: &str, unit: TimeUnit) -> Result<u64, String> {
+    parse_timestamp(s, &TimestampFormat::Iso8601, unit)
+}
+
+/// Appends one book update per `[price, size, ...]` level of `levels`.
+pub(crate) fn push_levels(
+    out: &mut Vec<Update>,
+    levels: Option<&Value>,
+    ts: u64,
+    seq: u32,
+    is_bid: bool,
+) -> Result<(), String> {
+    let levels = match levels {
+        Some(Value::Array(levels)) => levels,
+        Some(Value::Null) | None => return Ok(()),
+        Some(levels) => return Err(format!("invalid levels {}", levels)),
+    };
+    for level in levels {
+        match level.as_array().map(Vec::as_slice) {
+            Some([price, size, ..]) => out.push(Update {
+                ts,
+                seq,
+                is_trade: false,
+                is_bid,
+                flags: Flags::FLAG_EMPTY,
+                price: decimal(price)?,
+                size: decimal(size)?,
+            }),
+            _ => return Err(format!("invalid level {}", level)),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn mark_snapshot(ups: &mut [Update]) {
+    if let Some(first) = ups.first_mut() {
+        first.flags |= Flags::FLAG_SNAPSHOT_START | Flags::FLAG_RESET;
+    }
+    if let Some(last) = ups.last_mut() {
+        last.flags |= Flags::FLAG_SNAPSHOT_END;
+    }
+}
+
+/// Streams the messages of `rdr` into `wtr`.
+///
+/// Messages for symbols other than `symbol` are counted as skipped. Messages that fail to parse,
+/// or that are older than the previous imported message, are reported by line number.
+pub fn import_lines<R: BufRead, W: Write + Seek>(
+    exchange: Exchange,
+    rdr: R,
+    wtr: W,
+    symbol: &str,
+    opts: &EncodeOptions,
+) -> Result<(ImportReport, W), WstfError> {
+    let mut writer = WstfWriter::with_options(wtr, symbol, opts)?;
+    let mut report = ImportReport::default();
+    let mut last_ts = 0;
+
+    for (i, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let line_no = i as u64 + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = match exchange.parse_message(&line, opts.time_unit) {
+            Ok(msg) => msg,
+            Err(reason) => {
+                report.invalid.push(InvalidRow {
+                    line: line_no,
+                    reason,
+                });
+                continue;
+            }
+        };
+        if msg.updates.is_empty() {
+            continue;
+        }
+        if msg
+            .symbol
+            .as_deref()
+            .is_some_and(|msg_symbol| !msg_symbol.eq_ignore_ascii_case(symbol))
+        {
+            report.skipped += 1;
+            continue;
+        }
+        let ts = msg.updates.iter().map(|up| up.ts).min().unwrap_or(0);
+        if ts < last_ts {
+            report.invalid.push(InvalidRow {
+                line: line_no,
+                reason: format!("ts {} is older than the previous message", ts),
+            });
+            continue;
+        }
+        for up in &msg.updates {
+            writer.push(up)?;
+            last_ts = last_ts.max(up.ts);
+        }
+        report.imported += msg.updates.len() as u64;
+    }
+
+    Ok((report, writer.finish()?))
+}
+
+pub fn import_file(
+    exchange: Exchange,
+    input: &str,
+    fname: &str,
+    symbol: &str,
+    opts: &EncodeOptions,
+) -> Result<ImportReport, WstfError> {
+    let rdr = BufReader::new(File::open(input)?);
+    let wtr = BufWriter::new(File::create(fname)?);
+    let (report, mut wtr) = import_lines(exchange, rdr, wtr, symbol, opts)?;
+    wtr.flush()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::file_format::decode;
+    use serial_test::serial;
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+    static FNAME_JSON: &str = "./internal/mocks/tmp.jsonl";
+
+    #[test]
+    #[serial]
+    fn should_import_lines_and_report_invalid_messages() {
+        let lines = [
+            r#"{"result":null,"id":1}"#,
+            r#"{"e":"depthUpdate","E":1000,"s":"BTCUSDT","U":1,"u":5,"b":[["100.5","1"]],"a":[["101","2"],["102","0"]]}"#,
+            r#"{"e":"depthUpdate","E":1001,"s":"ETHUSDT","U":1,"u":5,"b":[["10","1"]],"a":[]}"#,
+            r#"{"e":"depthUpdate","E":999,"s":"BTCUSDT","U":6,"u":6,"b":[["100","1"]],"a":[]}"#,
+            r#"{"e":"depthUpdate","E":1002,"s":"BTCUSDT","U":6,"u":7,"b":[["x","1"]],"a":[]}"#,
+            "",
+            r#"{"e":"trade","E":1003,"s":"BTCUSDT","t":42,"p":"101","q":"0.5","T":1002,"m":true}"#,
+        ];
+        std::fs::write(FNAME_JSON, lines.join("\n")).unwrap();
+        let report = import_file(
+            Exchange::Binance,
+            FNAME_JSON,
+            FNAME,
+            "BTCUSDT",
+            &Default::default(),
+        )
+        .unwrap();
+
+        assert_eq!(report.imported, 4);
+        assert_eq!(report.skipped, 1);
+        let invalid: Vec<u64> = report.invalid.iter().map(|row| row.line).collect();
+        assert_eq!(invalid, vec![4, 5]);
+
+        let ups = decode(FNAME, None).unwrap();
+        assert_eq!(ups.len(), 4);
+        assert_eq!((ups[2].price, ups[2].size), (102., 0.));
+        assert!(ups[3].is_trade && !ups[3].is_bid);
+        assert_eq!(ups[3].seq, 42);
+    }
+}
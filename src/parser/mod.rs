@@ -1,5 +1,10 @@
+#[cfg(feature = "arrow")]
+pub mod arrow_io;
+pub mod csv_import;
 pub mod ffi;
 pub mod file_metadata;
 pub mod filetype;
+pub mod importers;
+pub mod text;
 pub mod utils;
 pub mod wstf_file_metadata;
@@ -0,0 +1,518 @@
+//! Streaming CSV and newline-delimited JSON writers and readers for updates.
+//!
+//! Rows carry `ts,seq,is_trade,is_bid,flags,price,size`. Prices and sizes are written with the
+//! shortest representation that parses back to the same `f32`, and timestamps are never
+//! rounded, so every format reads back to the updates it was written from.
+
+use std::io::{BufRead, Lines, Read, Write};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter};
+use serde_json::value::RawValue;
+
+use crate::error::WstfError;
+use crate::protocol::file_format::{decode_for_each, file_reader, read_header};
+use crate::protocol::time_unit::TimeUnit;
+use crate::update::{Flags, Update};
+
+pub const CSV_HEADER: &str = "ts,seq,is_trade,is_bid,flags,price,size";
+
+/// How the `ts` column is rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TsFormat {
+    /// The stored integer, e.g. `1700000000123`.
+    #[default]
+    Raw,
+    /// Decimal seconds with one digit per sub-second place of the unit, e.g. `1700000000.123`.
+    Seconds,
+    /// RFC 3339 in UTC, e.g. `2023-11-14T22:13:20.123Z`.
+    Iso8601,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextOptions {
+    pub ts_format: TsFormat,
+    /// Unit of the stored timestamps, used by `Seconds` and `Iso8601`.
+    pub time_unit: TimeUnit,
+    /// Write, or expect, a `CSV_HEADER` row. Ignored by NDJSON.
+    pub header: bool,
+}
+
+fn fraction_digits(unit: TimeUnit) -> usize {
+    (unit.digits() - TimeUnit::Seconds.digits()) as usize
+}
+
+pub fn format_ts(ts: u64, opts: &TextOptions) -> Result<String, WstfError> {
+    let per_second = opts.time_unit.per_second();
+    let (secs, frac) = (ts / per_second, ts % per_second);
+    match opts.ts_format {
+        TsFormat::Raw => Ok(ts.to_string()),
+        TsFormat::Seconds if per_second == 1 => Ok(secs.to_string()),
+        TsFormat::Seconds => Ok(format!(
+            "{}.{:0width$}",
+            secs,
+            frac,
+            width = fraction_digits(opts.time_unit)
+        )),
+        TsFormat::Iso8601 => {
+            let nanos = frac * (1_000_000_000 / per_second);
+            let datetime = DateTime::<Utc>::from_timestamp(secs as i64, nanos as u32)
+                .ok_or_else(|| WstfError::Corrupt(format!("ts {} is out of range", ts)))?;
+            let precision = match opts.time_unit {
+                TimeUnit::Seconds => SecondsFormat::Secs,
+                TimeUnit::Millis => SecondsFormat::Millis,
+                TimeUnit::Micros => SecondsFormat::Micros,
+                TimeUnit::Nanos => SecondsFormat::Nanos,
+            };
+            Ok(datetime.to_rfc3339_opts(precision, true))
+        }
+    }
+}
+
+/// Parses a timestamp written by `format_ts`, rejecting values finer than `time_unit`.
+pub fn parse_ts(s: &str, opts: &TextOptions) -> Result<u64, String> {
+    let per_second = opts.time_unit.per_second();
+    let invalid = || format!("invalid ts {:?}", s);
+    match opts.ts_format {
+        TsFormat::Raw => s.parse().map_err(|_| invalid()),
+        TsFormat::Seconds => {
+            let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+            let width = fraction_digits(opts.time_unit);
+            if frac.len() > width || !frac.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            let secs: u64 = secs.parse().map_err(|_| invalid())?;
+            let frac: u64 = format!("{:0<width$}", frac, width = width)
+                .parse()
+                .unwrap_or(0);
+            secs.checked_mul(per_second)
+                .and_then(|ts| ts.checked_add(frac))
+                .ok_or_else(invalid)
+        }
+        TsFormat::Iso8601 => {
+            let datetime = DateTime::parse_from_rfc3339(s).map_err(|_| invalid())?;
+            let secs = u64::try_from(datetime.timestamp()).map_err(|_| invalid())?;
+            let nanos_per_tick = 1_000_000_000 / per_second;
+            let nanos = u64::from(datetime.timestamp_subsec_nanos());
+            if nanos % nanos_per_tick != 0 {
+                return Err(invalid());
+            }
+            secs.checked_mul(per_second)
+                .and_then(|ts| ts.checked_add(nanos / nanos_per_tick))
+                .ok_or_else(invalid)
+        }
+    }
+}
+
+pub struct CsvWriter<W: Write> {
+    wtr: W,
+    opts: TextOptions,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(mut wtr: W, opts: TextOptions) -> Result<CsvWriter<W>, WstfError> {
+        if opts.header {
+            writeln!(wtr, "{}", CSV_HEADER)?;
+        }
+        Ok(CsvWriter { wtr, opts })
+    }
+
+    pub fn write(&mut self, up: &Update) -> Result<(), WstfError> {
+        writeln!(
+            self.wtr,
+            "{},{},{},{},{},{},{}",
+            format_ts(up.ts, &self.opts)?,
+            up.seq,
+            up.is_trade,
+            up.is_bid,
+            up.flags.bits(),
+            up.price,
+            up.size
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), WstfError> {
+        Ok(self.wtr.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.wtr
+    }
+}
+
+/// Renders `value` as a JSON number, or as a string such as `"NaN"` or `"inf"` when JSON has no
+/// number for it.
+fn json_float(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Writes one JSON object per line. `Iso8601` timestamps are strings, the rest numbers, and
+/// non-finite prices and sizes are strings.
+pub struct NdjsonWriter<W: Write> {
+    wtr: W,
+    opts: TextOptions,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(wtr: W, opts: TextOptions) -> NdjsonWriter<W> {
+        NdjsonWriter { wtr, opts }
+    }
+
+    pub fn write(&mut self, up: &Update) -> Result<(), WstfError> {
+        let ts = format_ts(up.ts, &self.opts)?;
+        let quote = if self.opts.ts_format == TsFormat::Iso8601 {
+            "\""
+        } else {
+            ""
+        };
+        writeln!(
+            self.wtr,
+            r#"{{"ts":{q}{}{q},"seq":{},"is_trade":{},"is_bid":{},"flags":{},"price":{},"size":{}}}"#,
+            ts,
+            up.seq,
+            up.is_trade,
+            up.is_bid,
+            up.flags.bits(),
+            json_float(up.price),
+            json_float(up.size),
+            q = quote
+        )?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), WstfError> {
+        Ok(self.wtr.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.wtr
+    }
+}
+
+/// Streams every update of `fname` through `write`, stopping at its first error.
+fn decode_to<F>(fname: &str, write: &mut F) -> Result<(), WstfError>
+where
+    F: FnMut(&Update) -> Result<(), WstfError>,
+{
+    let mut res = Ok(());
+    decode_for_each(fname, None, &mut |up| {
+        if res.is_ok() {
+            res = write(up);
+        }
+    })?;
+    res
+}
+
+fn file_time_unit(fname: &str) -> Result<TimeUnit, WstfError> {
+    Ok(read_header(&mut file_reader(fname)?)?.time_unit)
+}
+
+/// Writes every update of `fname` as CSV. `opts.time_unit` is taken from the file.
+pub fn decode_to_csv<W: Write>(fname: &str, wtr: W, opts: &TextOptions) -> Result<(), WstfError> {
+    let opts = TextOptions {
+        time_unit: file_time_unit(fname)?,
+        ..*opts
+    };
+    let mut writer = CsvWriter::new(wtr, opts)?;
+    decode_to(fname, &mut |up| writer.write(up))?;
+    writer.flush()
+}
+
+/// Writes every update of `fname` as NDJSON. `opts.time_unit` is taken from the file.
+pub fn decode_to_ndjson<W: Write>(
+    fname: &str,
+    wtr: W,
+    opts: &TextOptions,
+) -> Result<(), WstfError> {
+    let opts = TextOptions {
+        time_unit: file_time_unit(fname)?,
+        ..*opts
+    };
+    let mut writer = NdjsonWriter::new(wtr, opts);
+    decode_to(fname, &mut |up| writer.write(up))?;
+    writer.flush()
+}
+
+fn parse_field<T: std::str::FromStr>(s: &str, name: &str) -> Result<T, String> {
+    s.trim()
+        .parse()
+        .map_err(|_| format!("invalid {} {:?}", name, s))
+}
+
+fn extra_flags(bits: u8) -> Flags {
+    Flags::from_byte(bits).split().2
+}
+
+fn invalid_line(line: u64, reason: String) -> WstfError {
+    WstfError::Corrupt(format!("line {}: {}", line, reason))
+}
+
+/// Reads rows written by `CsvWriter` with the same options.
+pub struct CsvReader<R: Read> {
+    records: StringRecordsIntoIter<R>,
+    opts: TextOptions,
+}
+
+impl<R: Read> CsvReader<R> {
+    pub fn new(rdr: R, opts: TextOptions) -> CsvReader<R> {
+        let records = ReaderBuilder::new()
+            .has_headers(opts.header)
+            .from_reader(rdr)
+            .into_records();
+        CsvReader { records, opts }
+    }
+
+    fn parse(&self, record: &StringRecord) -> Result<Update, String> {
+        if record.len() != 7 {
+            return Err(format!("expected 7 fields, found {}", record.len()));
+        }
+        Ok(Update {
+            ts: parse_ts(record[0].trim(), &self.opts)?,
+            seq: parse_field(&record[1], "seq")?,
+            is_trade: parse_field(&record[2], "is_trade")?,
+            is_bid: parse_field(&record[3], "is_bid")?,
+            flags: extra_flags(parse_field(&record[4], "flags")?),
+            price: parse_field(&record[5], "price")?,
+            size: parse_field(&record[6], "size")?,
+        })
+    }
+}
+
+impl<R: Read> Iterator for CsvReader<R> {
+    type Item = Result<Update, WstfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err.into())),
+        };
+        let line = record.position().map_or(0, |pos| pos.line());
+        Some(self.parse(&record).map_err(|e| invalid_line(line, e)))
+    }
+}
+
+#[derive(Deserialize)]
+struct JsonRow<'a> {
+    #[serde(borrow)]
+    ts: &'a RawValue,
+    seq: u32,
+    is_trade: bool,
+    is_bid: bool,
+    #[serde(default)]
+    flags: u8,
+    #[serde(borrow)]
+    price: &'a RawValue,
+    #[serde(borrow)]
+    size: &'a RawValue,
+}
+
+/// Reads lines written by `NdjsonWriter` with the same options, skipping blank lines.
+pub struct NdjsonReader<R: BufRead> {
+    lines: Lines<R>,
+    line: u64,
+    opts: TextOptions,
+}
+
+impl<R: BufRead> NdjsonReader<R> {
+    pub fn new(rdr: R, opts: TextOptions) -> NdjsonReader<R> {
+        NdjsonReader {
+            lines: rdr.lines(),
+            line: 0,
+            opts,
+        }
+    }
+
+    fn parse(&self, line: &str) -> Result<Update, String> {
+        let row: JsonRow = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        Ok(Update {
+            ts: parse_ts(unquote(row.ts.get()), &self.opts)?,
+            seq: row.seq,
+            is_trade: row.is_trade,
+            is_bid: row.is_bid,
+            flags: extra_flags(row.flags),
+            price: parse_field(unquote(row.price.get()), "price")?,
+            size: parse_field(unquote(row.size.get()), "size")?,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for NdjsonReader<R> {
+    type Item = Result<Update, WstfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.line += 1;
+            if !line.trim().is_empty() {
+                return Some(self.parse(&line).map_err(|e| invalid_line(self.line, e)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::file_format::{decode, encode_with_options, EncodeOptions, Version};
+    use serial_test::serial;
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+
+    fn updates() -> Vec<Update> {
+        (0..100u64)
+            .map(|i| Update {
+                ts: 1_700_000_000_000_000 + i * 1_001,
+                seq: i as u32,
+                is_trade: i % 3 == 0,
+                is_bid: i % 2 == 0,
+                flags: if i % 7 == 0 {
+                    Flags::FLAG_IMPLIED
+                } else {
+                    Flags::FLAG_EMPTY
+                },
+                price: 0.1 + i as f32 * 1.0e-3,
+                size: 1.0 / (i + 1) as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_format_and_parse_timestamps() {
+        let opts = |ts_format, time_unit| TextOptions {
+            ts_format,
+            time_unit,
+            header: false,
+        };
+        let cases = [
+            (TsFormat::Raw, TimeUnit::Millis, "1700000000005"),
+            (TsFormat::Seconds, TimeUnit::Millis, "1700000000.005"),
+            (TsFormat::Seconds, TimeUnit::Seconds, "1700000000"),
+            (
+                TsFormat::Iso8601,
+                TimeUnit::Millis,
+                "2023-11-14T22:13:20.005Z",
+            ),
+        ];
+        for (ts_format, time_unit, expected) in cases {
+            let opts = opts(ts_format, time_unit);
+            let ts = 1_700_000_000 * time_unit.per_second() + 5 % time_unit.per_second();
+            assert_eq!(format_ts(ts, &opts).unwrap(), expected);
+            assert_eq!(parse_ts(expected, &opts).unwrap(), ts);
+        }
+
+        let nanos = opts(TsFormat::Seconds, TimeUnit::Nanos);
+        assert_eq!(
+            parse_ts("1700000000.5", &nanos).unwrap(),
+            1_700_000_000_500_000_000
+        );
+        let millis = opts(TsFormat::Seconds, TimeUnit::Millis);
+        assert!(parse_ts("1700000000.0001", &millis).is_err());
+        let iso = opts(TsFormat::Iso8601, TimeUnit::Millis);
+        assert_eq!(
+            parse_ts("2023-11-14T23:13:20.005+01:00", &iso).unwrap(),
+            1_700_000_000_005
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn should_round_trip_through_csv_and_ndjson() {
+        let ups = updates();
+        let enc = EncodeOptions {
+            version: Version::V2,
+            time_unit: TimeUnit::Micros,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, "BTC-USD", &ups, &enc).unwrap();
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+
+        for ts_format in [TsFormat::Raw, TsFormat::Seconds, TsFormat::Iso8601] {
+            let opts = TextOptions {
+                ts_format,
+                time_unit: TimeUnit::Micros,
+                header: true,
+            };
+
+            let mut csv = vec![];
+            decode_to_csv(FNAME, &mut csv, &opts).unwrap();
+            assert!(csv.starts_with(CSV_HEADER.as_bytes()));
+            let read: Result<Vec<_>, _> = CsvReader::new(&csv[..], opts).collect();
+            assert_eq!(read.unwrap(), ups);
+
+            let mut ndjson = vec![];
+            decode_to_ndjson(FNAME, &mut ndjson, &opts).unwrap();
+            let read: Result<Vec<_>, _> = NdjsonReader::new(&ndjson[..], opts).collect();
+            assert_eq!(read.unwrap(), ups);
+        }
+    }
+
+    #[test]
+    fn should_round_trip_non_finite_values() {
+        let ups = [f32::NAN, f32::INFINITY, f32::NEG_INFINITY].map(|value| Update {
+            ts: 1000,
+            seq: 0,
+            is_trade: false,
+            is_bid: true,
+            flags: Flags::FLAG_EMPTY,
+            price: value,
+            size: -value,
+        });
+        let mut writer = NdjsonWriter::new(vec![], TextOptions::default());
+        for up in &ups {
+            writer.write(up).unwrap();
+        }
+        let ndjson = writer.into_inner();
+        for line in ndjson
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+        {
+            serde_json::from_slice::<serde_json::Value>(line).unwrap();
+        }
+
+        let read = NdjsonReader::new(&ndjson[..], TextOptions::default())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(read[0].price.is_nan() && read[0].size.is_nan());
+        assert_eq!(read[1..], ups[1..]);
+    }
+
+    #[test]
+    fn should_report_invalid_lines() {
+        let opts = TextOptions {
+            header: true,
+            ..Default::default()
+        };
+        let csv = format!(
+            "{}\n1000,0,true,false,0,1.5,2\n1001,0,maybe,false,0,1.5,2\n",
+            CSV_HEADER
+        );
+        let rows: Vec<_> = CsvReader::new(csv.as_bytes(), opts).collect();
+        assert!(rows[0].is_ok());
+        match &rows[1] {
+            Err(WstfError::Corrupt(reason)) => assert!(reason.starts_with("line 3:")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let ndjson = "\n{\"ts\":1000,\"seq\":0,\"is_trade\":true,\"is_bid\":false,\"price\":1.5}\n";
+        let rows: Vec<_> = NdjsonReader::new(ndjson.as_bytes(), opts).collect();
+        match &rows[0] {
+            Err(WstfError::Corrupt(reason)) => assert!(reason.starts_with("line 2:")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,9 +1,10 @@
 use super::{file_metadata::FileMetadata, filetype::FileType};
+use crate::error::WstfError;
 use crate::protocol::{
     file_format::{read_meta, Metadata},
-    symbol::{AssetType, Symbol},
+    symbol::{AssetType, InstrumentMetadata, Symbol},
 };
-use std::{env, fs, io, str::FromStr};
+use std::{env, fs, str::FromStr};
 
 fn key_or_default(key: &str, default: &str) -> String {
     match env::var(key) {
@@ -39,21 +40,30 @@ pub struct WSTFFileMetadata {
     pub filename: String,
     pub tags: Vec<String>,
     pub errors: Vec<String>,
+    pub instrument: Option<InstrumentMetadata>,
 }
 
 impl FileMetadata for WSTFFileMetadata {}
 
 impl WSTFFileMetadata {
-    pub fn new(fname: &str) -> Result<WSTFFileMetadata, io::Error> {
+    pub fn new(fname: &str) -> Result<WSTFFileMetadata, WstfError> {
         let metadata: Metadata = read_meta(fname)?;
         let file_size = fs::metadata(fname)?.len();
-        let symbol = match Symbol::from_str(&metadata.symbol) {
-            Ok(sym) => sym,
-            Err(()) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Unable to parse symbol {}", metadata.symbol),
-                ));
+        let symbol = match (Symbol::from_str(&metadata.symbol), &metadata.instrument) {
+            (Ok(sym), _) => sym,
+            (Err(()), Some(instrument)) => {
+                let tag = |key| instrument.tag(key).unwrap_or_default().to_owned();
+                Symbol {
+                    exchange: tag("exchange"),
+                    currency: tag("currency"),
+                    asset: tag("asset"),
+                }
+            }
+            (Err(()), None) => {
+                return Err(WstfError::InvalidHeader(format!(
+                    "Unable to parse symbol {}",
+                    metadata.symbol
+                )));
             }
         };
 
@@ -67,7 +77,10 @@ impl WSTFFileMetadata {
             exchange: symbol.exchange,
             currency: symbol.currency,
             asset: symbol.asset,
-            asset_type: AssetType::SPOT,
+            asset_type: metadata
+                .instrument
+                .as_ref()
+                .map_or(AssetType::SPOT, |instrument| instrument.asset_type),
             first_epoch,
             last_epoch,
             total_updates,
@@ -76,6 +89,7 @@ impl WSTFFileMetadata {
             continuation_candles: false,
             filename: fname.to_owned(),
             tags: parse_wstf_metadata_tags(),
+            instrument: metadata.instrument,
             ..Default::default()
         })
     }
@@ -0,0 +1,182 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::WstfError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_byte(byte: u8) -> Option<Compression> {
+        match byte {
+            0x00 => Some(Compression::None),
+            0x01 => Some(Compression::Lz4),
+            0x02 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            Compression::None => 0x00,
+            Compression::Lz4 => 0x01,
+            Compression::Zstd => 0x02,
+        }
+    }
+
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    pub fn available() -> Vec<Compression> {
+        [Compression::None, Compression::Lz4, Compression::Zstd]
+            .into_iter()
+            .filter(|c| c.is_available())
+            .collect()
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+fn unavailable(codec: Compression) -> WstfError {
+    WstfError::Unsupported(format!(
+        "{} compression requires the `{}` feature",
+        codec, codec
+    ))
+}
+
+pub fn compress(codec: Compression, payload: &[u8]) -> Result<Vec<u8>, WstfError> {
+    match codec {
+        Compression::None => Ok(payload.to_vec()),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::block::compress(payload)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(zstd::bulk::compress(payload, 0)?),
+        #[allow(unreachable_patterns)]
+        _ => Err(unavailable(codec)),
+    }
+}
+
+/// The largest payload `codec` produces from `raw_len` bytes. Incompressible input grows by
+/// at most 1/255 plus a small block or frame overhead with both LZ4 and Zstandard.
+pub fn max_compressed_len(codec: Compression, raw_len: usize) -> usize {
+    match codec {
+        Compression::None => raw_len,
+        _ => raw_len + raw_len / 255 + 64,
+    }
+}
+
+/// Decompresses `payload` into `raw_len` bytes, which the caller bounds before calling since
+/// the output buffer is allocated up front.
+pub fn decompress(
+    codec: Compression,
+    payload: &[u8],
+    raw_len: usize,
+) -> Result<Vec<u8>, WstfError> {
+    if payload.len() > max_compressed_len(codec, raw_len) {
+        return Err(WstfError::Corrupt(format!(
+            "{} compressed bytes are too many for {} bytes",
+            payload.len(),
+            raw_len
+        )));
+    }
+    let raw = match codec {
+        Compression::None => payload.to_vec(),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4_flex::block::decompress(payload, raw_len)
+            .map_err(|e| WstfError::Corrupt(e.to_string()))?,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::bulk::decompress(payload, raw_len)?,
+        #[allow(unreachable_patterns)]
+        _ => return Err(unavailable(codec)),
+    };
+
+    if raw.len() != raw_len {
+        return Err(WstfError::Corrupt(format!(
+            "decompressed {} bytes, expected {}",
+            raw.len(),
+            raw_len
+        )));
+    }
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_round_trip_available_codecs() {
+        let payload = (0..4096u32)
+            .flat_map(|i| (i / 16).to_be_bytes())
+            .collect::<Vec<u8>>();
+
+        for codec in Compression::available() {
+            let compressed = compress(codec, &payload).unwrap();
+            if codec != Compression::None {
+                assert!(compressed.len() < payload.len());
+            }
+            assert_eq!(
+                decompress(codec, &compressed, payload.len()).unwrap(),
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn should_bound_incompressible_payloads() {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let payload = (0..100_000)
+            .map(|_i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<u8>>();
+
+        for codec in Compression::available() {
+            let compressed = compress(codec, &payload).unwrap();
+            assert!(compressed.len() <= max_compressed_len(codec, payload.len()));
+            assert!(decompress(codec, &compressed, 16).is_err());
+        }
+    }
+
+    #[test]
+    fn should_parse_codec_names() {
+        for codec in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            assert_eq!(codec.to_string().parse(), Ok(codec));
+            assert_eq!(Compression::from_byte(codec.as_byte()), Some(codec));
+        }
+        assert_eq!(Compression::from_byte(0xFF), None);
+    }
+}
@@ -0,0 +1,394 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::borrow::Cow;
+use std::{fmt, str::FromStr};
+
+use crate::error::WstfError;
+use crate::protocol::file_format::BYTES_PER_ROW;
+use crate::protocol::time_unit::TimeUnit;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BatchEncoding {
+    #[default]
+    Raw,
+    Xor,
+}
+
+impl BatchEncoding {
+    pub fn from_byte(byte: u8) -> Option<BatchEncoding> {
+        match byte {
+            0x00 => Some(BatchEncoding::Raw),
+            0x01 => Some(BatchEncoding::Xor),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            BatchEncoding::Raw => 0x00,
+            BatchEncoding::Xor => 0x01,
+        }
+    }
+}
+
+impl fmt::Display for BatchEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchEncoding::Raw => write!(f, "raw"),
+            BatchEncoding::Xor => write!(f, "xor"),
+        }
+    }
+}
+
+impl FromStr for BatchEncoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(BatchEncoding::Raw),
+            "xor" => Ok(BatchEncoding::Xor),
+            _ => Err(()),
+        }
+    }
+}
+
+fn truncated() -> WstfError {
+    WstfError::Corrupt("encoded batch is truncated".to_owned())
+}
+
+pub fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+pub fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+pub fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, WstfError> {
+    let mut v = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos).ok_or_else(truncated)?;
+        *pos += 1;
+        if shift > 63 {
+            return Err(WstfError::Corrupt("varint is too long".to_owned()));
+        }
+        v |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+        shift += 7;
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    buf: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (u64::from(value) & ((1u64 << bits) - 1));
+        self.len += bits;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.buf.push((self.acc >> self.len) as u8);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.buf.push((self.acc << (8 - self.len)) as u8);
+        }
+        self.buf
+    }
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    acc: u64,
+    len: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        BitReader {
+            buf,
+            pos: 0,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32, WstfError> {
+        if bits == 0 {
+            return Ok(0);
+        }
+        while self.len < bits {
+            let byte = *self.buf.get(self.pos).ok_or_else(truncated)?;
+            self.pos += 1;
+            self.acc = (self.acc << 8) | u64::from(byte);
+            self.len += 8;
+        }
+        self.len -= bits;
+        Ok(((self.acc >> self.len) & ((1u64 << bits) - 1)) as u32)
+    }
+}
+
+#[derive(Default)]
+struct XorState {
+    prev: u32,
+    window: Option<(u32, u32)>,
+}
+
+impl XorState {
+    fn write(&mut self, bits: &mut BitWriter, value: u32) {
+        let xor = value ^ self.prev;
+        self.prev = value;
+        if xor == 0 {
+            bits.write(0, 1);
+            return;
+        }
+        bits.write(1, 1);
+
+        let lz = xor.leading_zeros().min(31);
+        let tz = xor.trailing_zeros();
+        match self.window {
+            Some((prev_lz, prev_tz)) if lz >= prev_lz && tz >= prev_tz => {
+                bits.write(0, 1);
+                bits.write(xor >> prev_tz, 32 - prev_lz - prev_tz);
+            }
+            _ => {
+                let meaningful = 32 - lz - tz;
+                bits.write(1, 1);
+                bits.write(lz, 5);
+                bits.write(meaningful - 1, 5);
+                bits.write(xor >> tz, meaningful);
+                self.window = Some((lz, tz));
+            }
+        }
+    }
+
+    fn read(&mut self, bits: &mut BitReader) -> Result<u32, WstfError> {
+        if bits.read(1)? == 0 {
+            return Ok(self.prev);
+        }
+
+        let (lz, tz) = match (bits.read(1)?, self.window) {
+            (0, Some(window)) => window,
+            (0, None) => return Err(WstfError::Corrupt("xor window is missing".to_owned())),
+            _ => {
+                let lz = bits.read(5)?;
+                let meaningful = bits.read(5)? + 1;
+                if lz + meaningful > 32 {
+                    return Err(WstfError::Corrupt("xor window is invalid".to_owned()));
+                }
+                let window = (lz, 32 - lz - meaningful);
+                self.window = Some(window);
+                window
+            }
+        };
+
+        let xor = bits.read(32 - lz - tz)? << tz;
+        self.prev ^= xor;
+        Ok(self.prev)
+    }
+}
+
+// Rows start with a 2 or 4 byte timestamp delta, so column offsets shift by `delta_len - 2`.
+fn encode_xor(raw: &[u8], time_unit: TimeUnit) -> Vec<u8> {
+    let d = time_unit.delta_len();
+    let row_len = BYTES_PER_ROW + d - 2;
+    let mut out = Vec::with_capacity(raw.len() / 2);
+    let (mut prev_ts, mut prev_seq) = (0i64, 0i64);
+    for row in raw.chunks_exact(row_len) {
+        let ts = BigEndian::read_uint(&row[..d], d) as i64;
+        let seq = i64::from(row[d]);
+        write_varint(&mut out, zigzag(ts - prev_ts));
+        write_varint(&mut out, zigzag(seq - prev_seq));
+        out.push(row[d + 1]);
+        prev_ts = ts;
+        prev_seq = seq;
+    }
+
+    let mut bits = BitWriter::default();
+    let mut price = XorState::default();
+    let mut size = XorState::default();
+    for row in raw.chunks_exact(row_len) {
+        price.write(&mut bits, BigEndian::read_u32(&row[d + 2..d + 6]));
+        size.write(&mut bits, BigEndian::read_u32(&row[d + 6..d + 10]));
+    }
+    out.extend(bits.finish());
+    out
+}
+
+fn decode_xor(encoded: &[u8], count: u16, time_unit: TimeUnit) -> Result<Vec<u8>, WstfError> {
+    let d = time_unit.delta_len();
+    let row_len = BYTES_PER_ROW + d - 2;
+    let max_ts = time_unit.max_batch_span() as i64;
+    let mut raw = vec![0u8; count as usize * row_len];
+    let mut pos = 0;
+    let (mut prev_ts, mut prev_seq) = (0i64, 0i64);
+    for row in raw.chunks_exact_mut(row_len) {
+        let out_of_range = || WstfError::Corrupt("xor delta out of range".to_owned());
+        let ts = prev_ts
+            .checked_add(unzigzag(read_varint(encoded, &mut pos)?))
+            .ok_or_else(out_of_range)?;
+        let seq = prev_seq
+            .checked_add(unzigzag(read_varint(encoded, &mut pos)?))
+            .ok_or_else(out_of_range)?;
+        if !(0..=max_ts).contains(&ts) || !(0..=i64::from(u8::MAX)).contains(&seq) {
+            return Err(out_of_range());
+        }
+        BigEndian::write_uint(&mut row[..d], ts as u64, d);
+        row[d] = seq as u8;
+        row[d + 1] = *encoded.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+        prev_ts = ts;
+        prev_seq = seq;
+    }
+
+    let mut bits = BitReader::new(&encoded[pos..]);
+    let mut price = XorState::default();
+    let mut size = XorState::default();
+    for row in raw.chunks_exact_mut(row_len) {
+        BigEndian::write_u32(&mut row[d + 2..d + 6], price.read(&mut bits)?);
+        BigEndian::write_u32(&mut row[d + 6..d + 10], size.read(&mut bits)?);
+    }
+    Ok(raw)
+}
+
+pub fn encode_payload(encoding: BatchEncoding, raw: &[u8], time_unit: TimeUnit) -> Cow<'_, [u8]> {
+    match encoding {
+        BatchEncoding::Raw => Cow::Borrowed(raw),
+        BatchEncoding::Xor => Cow::Owned(encode_xor(raw, time_unit)),
+    }
+}
+
+pub fn decode_payload(
+    encoding: BatchEncoding,
+    encoded: Cow<'_, [u8]>,
+    count: u16,
+    time_unit: TimeUnit,
+) -> Result<Cow<'_, [u8]>, WstfError> {
+    match encoding {
+        BatchEncoding::Raw => Ok(encoded),
+        BatchEncoding::Xor => Ok(Cow::Owned(decode_xor(&encoded, count, time_unit)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::update::{Flags, Update};
+
+    fn raw_rows(ups: &[Update]) -> Vec<u8> {
+        let mut raw = vec![];
+        for up in ups {
+            up.serialize_to_buffer(&mut raw, ups[0].ts, ups[0].seq)
+                .unwrap();
+        }
+        raw
+    }
+
+    #[test]
+    fn should_round_trip_varints() {
+        for v in [0i64, 1, -1, 63, -64, 300, -300, i64::MAX, i64::MIN] {
+            let mut buf = vec![];
+            write_varint(&mut buf, zigzag(v));
+            let mut pos = 0;
+            assert_eq!(unzigzag(read_varint(&buf, &mut pos).unwrap()), v);
+            assert_eq!(pos, buf.len());
+        }
+    }
+
+    #[test]
+    fn should_round_trip_xor_encoding() {
+        let ups = (0..1000)
+            .map(|i| Update {
+                ts: 1_000_000 + i * 7,
+                seq: 10 + (i % 200) as u32,
+                is_trade: i % 5 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 5100.01 + ((i / 10) % 7) as f32 * 0.5,
+                size: [0., 0.5, 1.25, 10.][(i / 3 % 4) as usize],
+            })
+            .collect::<Vec<_>>();
+        let raw = raw_rows(&ups);
+
+        let encoded = encode_payload(BatchEncoding::Xor, &raw, TimeUnit::Millis);
+        assert!(encoded.len() < raw.len() / 2);
+        let decoded = decode_payload(BatchEncoding::Xor, encoded, 1000, TimeUnit::Millis).unwrap();
+        assert_eq!(decoded, raw);
+    }
+
+    #[test]
+    fn should_round_trip_special_floats() {
+        let values = [0., -0., f32::MAX, f32::MIN, f32::EPSILON, f32::NAN, 1e-40];
+        let ups = values
+            .iter()
+            .map(|&v| Update {
+                ts: 0,
+                seq: 0,
+                is_trade: false,
+                is_bid: false,
+                flags: Flags::FLAG_EMPTY,
+                price: v,
+                size: -v,
+            })
+            .collect::<Vec<_>>();
+        let raw = raw_rows(&ups);
+
+        let encoded = encode_payload(BatchEncoding::Xor, &raw, TimeUnit::Millis).into_owned();
+        let count = values.len() as u16;
+        assert_eq!(
+            decode_payload(
+                BatchEncoding::Xor,
+                Cow::Borrowed(&encoded),
+                count,
+                TimeUnit::Millis
+            )
+            .unwrap(),
+            raw
+        );
+        assert!(decode_payload(
+            BatchEncoding::Xor,
+            Cow::Borrowed(&encoded[..encoded.len() - 2]),
+            count,
+            TimeUnit::Millis
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_reject_overflowing_xor_deltas() {
+        // A first row at ts 1 and seq 1, then deltas that overflow i64 when added.
+        for (ts_delta, seq_delta) in [(i64::MAX, 0), (0, i64::MAX)] {
+            let mut encoded = vec![];
+            write_varint(&mut encoded, zigzag(1));
+            write_varint(&mut encoded, zigzag(1));
+            encoded.push(0);
+            write_varint(&mut encoded, zigzag(ts_delta));
+            write_varint(&mut encoded, zigzag(seq_delta));
+            encoded.push(0);
+            assert!(matches!(
+                decode_payload(BatchEncoding::Xor, Cow::Owned(encoded), 2, TimeUnit::Millis),
+                Err(WstfError::Corrupt(_))
+            ));
+        }
+    }
+}
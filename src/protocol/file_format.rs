@@ -1,36 +1,354 @@
 #[cfg(feature = "count_alloc")]
 use alloc_counter::count_alloc;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::cell::RefCell;
-use std::io::Cursor;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use ordered_float::OrderedFloat;
+use std::borrow::Cow;
 use std::iter::Peekable;
 use std::ops::Deref;
-use std::ops::DerefMut;
-use std::sync::Mutex;
 use std::{
     cmp, fmt,
     fs::{File, OpenOptions},
-    io::{
-        self, BufRead, BufReader, BufWriter, ErrorKind::InvalidData, Read, Seek, SeekFrom, Write,
-    },
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     str,
 };
 
+use crate::error::WstfError;
+use crate::protocol::compression::{compress, decompress, max_compressed_len, Compression};
+use crate::protocol::encoding::{decode_payload, encode_payload, BatchEncoding};
+use crate::protocol::index::{
+    index_len, read_index, search_index, write_index, BatchIndexEntry, INDEX_MARKER,
+};
+use crate::protocol::mmap::Batches;
+use crate::protocol::symbol::{AssetType, InstrumentMetadata};
+use crate::protocol::time_unit::TimeUnit;
+use crate::protocol::writer::{ContainerWriter, WstfWriter};
 use crate::update::*;
 use crate::utils::epoch_to_human;
 
 const SYMBOL_LEN: usize = 20;
-static MAGIC_VALUE: &[u8] = &[0x57, 0x53, 0x54, 0x46, 0x01];
+static MAGIC_VALUE: &[u8] = &[0x57, 0x53, 0x54, 0x46];
+pub(crate) static BYTES_PER_ROW: usize = 12;
+pub(crate) static FIXED_BYTES_PER_ROW: usize = 20;
+pub(crate) static ORDER_BYTES_PER_ROW: usize = 29;
+pub(crate) static EVENT_BYTES_PER_ROW: usize = 21;
+static BATCH_REF_LEN: u64 = 15;
+
 static SYMBOL_OFFSET: u64 = 5;
 static LEN_OFFSET: u64 = 25;
 static MAX_TS_OFFSET: u64 = 33;
 static MAIN_OFFSET: u64 = 80;
-static BYTES_PER_ROW: usize = 12;
+
+// v2: magic | header_len | flags | len | min_ts | max_ts | symbol | index_offset | compression
+//     | encoding | reserved (zeroed)
+static V2_HEADER_LEN_OFFSET: u64 = 5;
+static V2_FLAGS_OFFSET: u64 = 9;
+static V2_LEN_OFFSET: u64 = 13;
+static V2_MIN_TS_OFFSET: u64 = 21;
+static V2_MAX_TS_OFFSET: u64 = 29;
+static V2_SYMBOL_OFFSET: u64 = 37;
+static V2_INDEX_OFFSET: u64 = 57;
+static V2_COMPRESSION_OFFSET: u64 = 65;
+static V2_ENCODING_OFFSET: u64 = 66;
+static V2_FIXED_LEN: u64 = 67;
+static V2_HEADER_LEN: u64 = 128;
+static MAX_SEQ_DELTA: u32 = 0xFF;
+// Payloads longer than this grow as they are read instead of being allocated up front.
+static PAYLOAD_PREALLOC: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Default)]
+pub enum Version {
+    V1,
+    #[default]
+    V2,
+}
+
+impl Version {
+    pub fn from_byte(byte: u8) -> Option<Version> {
+        match byte {
+            0x01 => Some(Version::V1),
+            0x02 => Some(Version::V2),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            Version::V1 => 0x01,
+            Version::V2 => 0x02,
+        }
+    }
+
+    fn len_offset(self) -> u64 {
+        match self {
+            Version::V1 => LEN_OFFSET,
+            Version::V2 => V2_LEN_OFFSET,
+        }
+    }
+
+    fn max_ts_offset(self) -> u64 {
+        match self {
+            Version::V1 => MAX_TS_OFFSET,
+            Version::V2 => V2_MAX_TS_OFFSET,
+        }
+    }
+}
+
+/// The type of row stored in a batch.
+///
+/// The header names the kind of every batch, except `Event` batches which files created with
+/// `EncodeOptions::events` interleave with them.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord, Default)]
+pub enum RecordKind {
+    #[default]
+    Update,
+    Order,
+    Event,
+}
+
+impl RecordKind {
+    pub fn from_byte(byte: u8) -> Option<RecordKind> {
+        match byte {
+            0x00 => Some(RecordKind::Update),
+            0x01 => Some(RecordKind::Order),
+            0x02 => Some(RecordKind::Event),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            RecordKind::Update => 0x00,
+            RecordKind::Order => 0x01,
+            RecordKind::Event => 0x02,
+        }
+    }
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordKind::Update => write!(f, "update"),
+            RecordKind::Order => write!(f, "order"),
+            RecordKind::Event => write!(f, "event"),
+        }
+    }
+}
+
+bitflags! {
+    pub struct HeaderFlags: u32 {
+        const FLAG_EMPTY = 0;
+        const FLAG_CHECKSUM = 0b0000_0001;
+        const FLAG_CONTAINER = 0b0000_0010;
+        const FLAG_METADATA = 0b0000_0100;
+        const FLAG_FIXED_POINT = 0b0000_1000;
+        const FLAG_TIME_UNIT = 0b0001_0000;
+        const FLAG_RECORD_KIND = 0b0010_0000;
+        const FLAG_EVENTS = 0b0100_0000;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: Version,
+    pub header_len: u64,
+    pub flags: HeaderFlags,
+    pub symbol: String,
+    /// The number of updates or orders, auxiliary events are counted by `events`.
+    pub nums: u64,
+    pub min_ts: Option<u64>,
+    pub max_ts: u64,
+    pub index_offset: Option<u64>,
+    pub compression: Compression,
+    pub encoding: BatchEncoding,
+    pub symbols: Vec<String>,
+    pub instrument: Option<InstrumentMetadata>,
+    pub scale: Option<FixedScale>,
+    pub time_unit: TimeUnit,
+    pub record_kind: RecordKind,
+    pub events: u64,
+}
+
+impl Header {
+    pub fn new(version: Version, symbol: &str) -> Header {
+        Header {
+            version,
+            header_len: match version {
+                Version::V1 => MAIN_OFFSET,
+                Version::V2 => V2_HEADER_LEN,
+            },
+            flags: HeaderFlags::FLAG_EMPTY,
+            symbol: symbol.to_owned(),
+            nums: 0,
+            min_ts: None,
+            max_ts: 0,
+            index_offset: None,
+            compression: Compression::None,
+            encoding: BatchEncoding::Raw,
+            symbols: vec![],
+            instrument: None,
+            scale: None,
+            time_unit: TimeUnit::Millis,
+            record_kind: RecordKind::Update,
+            events: 0,
+        }
+    }
+
+    pub fn is_container(&self) -> bool {
+        self.flags.contains(HeaderFlags::FLAG_CONTAINER)
+    }
+
+    pub fn has_events(&self) -> bool {
+        self.flags.contains(HeaderFlags::FLAG_EVENTS)
+    }
+
+    pub fn symbol_id(&self, symbol: &str) -> Option<u16> {
+        self.symbols
+            .iter()
+            .position(|s| s == symbol)
+            .map(|id| id as u16)
+    }
+
+    /// Stores `instrument` in the metadata block, which also carries symbols longer than
+    /// the fixed header field.
+    pub fn set_instrument(&mut self, instrument: InstrumentMetadata) {
+        self.symbol = instrument.symbol.clone();
+        self.flags |= HeaderFlags::FLAG_METADATA;
+        self.instrument = Some(instrument);
+        self.fit_header_len();
+    }
+
+    /// Grows `header_len` so the symbol dictionary, metadata block and the optional scale,
+    /// time unit and record kind fit in front of the first batch.
+    pub fn fit_header_len(&mut self) {
+        if self.version == Version::V2 {
+            self.header_len = self.header_len.max(V2_FIXED_LEN + self.extension_len());
+        }
+    }
+
+    fn extension_len(&self) -> u64 {
+        self.events_offset() + if self.has_events() { 8 } else { 0 }
+    }
+
+    fn events_offset(&self) -> u64 {
+        self.record_kind_offset() + u64::from(self.record_kind != RecordKind::Update)
+    }
+
+    fn record_kind_offset(&self) -> u64 {
+        self.time_unit_offset() + u64::from(self.time_unit != TimeUnit::Millis)
+    }
+
+    fn time_unit_offset(&self) -> u64 {
+        self.scale_offset() + self.scale.map_or(0, |_| 2)
+    }
+
+    fn scale_offset(&self) -> u64 {
+        self.symbols_len() + self.instrument.as_ref().map_or(0, instrument_len)
+    }
+
+    fn symbols_len(&self) -> u64 {
+        if !self.is_container() {
+            return 0;
+        }
+        2 + self
+            .symbols
+            .iter()
+            .map(|symbol| 1 + symbol.len() as u64)
+            .sum::<u64>()
+    }
+
+    pub fn main_offset(&self) -> u64 {
+        self.header_len
+    }
+
+    pub fn batch_format(&self) -> BatchFormat {
+        BatchFormat {
+            checksum: self.flags.contains(HeaderFlags::FLAG_CHECKSUM),
+            compression: self.compression,
+            encoding: self.encoding,
+            symbol_id: self.is_container(),
+            kind_byte: self.has_events(),
+            scale: self.scale,
+            time_unit: self.time_unit,
+            record_kind: self.record_kind,
+        }
+    }
+
+    /// The format of the auxiliary event batches of a file created with events.
+    pub fn event_format(&self) -> BatchFormat {
+        BatchFormat {
+            record_kind: RecordKind::Event,
+            ..self.batch_format()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncodeOptions {
+    pub version: Version,
+    pub checksum: bool,
+    pub compression: Compression,
+    pub encoding: BatchEncoding,
+    /// Stores prices and sizes as integer ticks of this scale instead of `f32`.
+    pub scale: Option<FixedScale>,
+    pub time_unit: TimeUnit,
+    pub record_kind: RecordKind,
+    /// Tags every batch with its record kind so `MarketEvent` batches can follow the updates.
+    pub events: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendPolicy {
+    Strict,
+    #[default]
+    DropLate,
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatchFormat {
+    pub checksum: bool,
+    pub compression: Compression,
+    pub encoding: BatchEncoding,
+    pub symbol_id: bool,
+    pub kind_byte: bool,
+    pub scale: Option<FixedScale>,
+    pub time_unit: TimeUnit,
+    pub record_kind: RecordKind,
+}
+
+impl BatchFormat {
+    pub fn row_len(&self) -> usize {
+        row_len(self.record_kind, self.scale, self.time_unit)
+    }
+
+    pub fn ref_len(&self) -> u64 {
+        let mut len = BATCH_REF_LEN;
+        if self.checksum {
+            len += 4;
+        }
+        if self.compression != Compression::None {
+            len += 4;
+        }
+        if self.encoding != BatchEncoding::Raw {
+            len += 5;
+        }
+        if self.symbol_id {
+            len += 2;
+        }
+        if self.kind_byte {
+            len += 1;
+        }
+        len
+    }
+}
 
 #[derive(Debug, Eq, PartialEq, PartialOrd)]
 pub struct Metadata {
     pub symbol: String,
+    pub symbols: Vec<String>,
+    pub instrument: Option<InstrumentMetadata>,
+    pub time_unit: TimeUnit,
     pub nums: u64,
     pub max_ts: u64,
     pub min_ts: u64,
@@ -47,6 +365,70 @@ pub struct BatchMetadata {
     pub ref_ts: u64,
     pub ref_seq: u32,
     pub count: u16,
+    pub offset: u64,
+    pub checksum: Option<u32>,
+    pub compression: Compression,
+    pub compressed_len: Option<u32>,
+    pub encoding: BatchEncoding,
+    pub encoded_len: Option<u32>,
+    pub symbol_id: Option<u16>,
+    pub kind_byte: bool,
+    pub scale: Option<FixedScale>,
+    pub time_unit: TimeUnit,
+    pub record_kind: RecordKind,
+}
+
+// The row constants assume a 2 byte timestamp delta.
+fn row_len(record_kind: RecordKind, scale: Option<FixedScale>, time_unit: TimeUnit) -> usize {
+    let len = match (record_kind, scale) {
+        (RecordKind::Order, _) => ORDER_BYTES_PER_ROW,
+        (RecordKind::Event, _) => EVENT_BYTES_PER_ROW,
+        (RecordKind::Update, Some(_)) => FIXED_BYTES_PER_ROW,
+        (RecordKind::Update, None) => BYTES_PER_ROW,
+    };
+    len + time_unit.delta_len() - 2
+}
+
+impl BatchMetadata {
+    pub fn row_len(&self) -> usize {
+        row_len(self.record_kind, self.scale, self.time_unit)
+    }
+
+    pub fn raw_len(&self) -> usize {
+        self.count as usize * self.row_len()
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match self.encoded_len {
+            Some(len) => len as usize,
+            None => self.raw_len(),
+        }
+    }
+
+    pub fn payload_len(&self) -> usize {
+        match self.compressed_len {
+            Some(len) => len as usize,
+            None => self.encoded_len(),
+        }
+    }
+
+    /// Rejects stored lengths that cannot belong to `count` rows, before anything is allocated
+    /// for them.
+    fn check_lens(&self) -> Result<(), WstfError> {
+        let max_len = match self.compressed_len {
+            Some(_) => max_compressed_len(self.compression, self.encoded_len()),
+            None => self.raw_len(),
+        };
+        if self.encoded_len() > self.raw_len() || self.payload_len() > max_len {
+            return Err(WstfError::Corrupt(format!(
+                "batch at offset {} claims {} bytes for {} rows",
+                self.offset,
+                self.payload_len(),
+                self.count
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for Metadata {
@@ -57,18 +439,18 @@ impl fmt::Display for Metadata {
             self.symbol,
             self.nums,
             self.max_ts,
-            epoch_to_human(self.max_ts / 1000),
+            epoch_to_human(self.time_unit.to_seconds(self.max_ts)),
             self.min_ts,
-            epoch_to_human(self.min_ts / 1000)
+            epoch_to_human(self.time_unit.to_seconds(self.min_ts))
         )
     }
 }
 
 pub fn get_max_ts_sorted(updates: &[Update]) -> u64 {
-    updates.last().unwrap().ts
+    updates.last().map_or(0, |up| up.ts)
 }
 
-fn file_writer(fname: &str, create: bool) -> Result<BufWriter<File>, io::Error> {
+fn file_writer(fname: &str, create: bool) -> Result<BufWriter<File>, WstfError> {
     let new_file = if create {
         File::create(fname)?
     } else {
@@ -78,987 +460,4149 @@ fn file_writer(fname: &str, create: bool) -> Result<BufWriter<File>, io::Error>
     Ok(BufWriter::new(new_file))
 }
 
-fn write_magic_value(wtr: &mut dyn Write) -> Result<usize, io::Error> {
-    wtr.write(MAGIC_VALUE)
+fn write_magic_value(wtr: &mut dyn Write, version: Version) -> Result<(), WstfError> {
+    wtr.write_all(MAGIC_VALUE)?;
+    Ok(wtr.write_u8(version.as_byte())?)
 }
 
-fn write_symbol(wtr: &mut dyn Write, symbol: &str) -> Result<usize, io::Error> {
+fn write_symbol(wtr: &mut dyn Write, symbol: &str) -> Result<usize, WstfError> {
     if symbol.len() > SYMBOL_LEN {
-        return Err(io::Error::new(
-            InvalidData,
-            format!("Symbol length is longer than {}", SYMBOL_LEN),
-        ));
+        return Err(WstfError::SymbolTooLong(symbol.to_owned()));
     }
     let padded_symbol = format!("{:width$}", symbol, width = SYMBOL_LEN);
-    assert_eq!(padded_symbol.len(), SYMBOL_LEN);
-    wtr.write(padded_symbol.as_bytes())
+    wtr.write_all(padded_symbol.as_bytes())?;
+    Ok(SYMBOL_LEN)
 }
 
-fn write_len<T: Write + Seek>(wtr: &mut T, len: u64) -> Result<(), io::Error> {
-    let _ = wtr.seek(SeekFrom::Start(LEN_OFFSET));
-    wtr.write_u64::<BigEndian>(len)
+fn write_len<T: Write + Seek>(wtr: &mut T, version: Version, len: u64) -> Result<(), WstfError> {
+    wtr.seek(SeekFrom::Start(version.len_offset()))?;
+    Ok(wtr.write_u64::<BigEndian>(len)?)
 }
 
-fn write_max_ts<T: Write + Seek>(wtr: &mut T, max_ts: u64) -> Result<(), io::Error> {
-    let _ = wtr.seek(SeekFrom::Start(MAX_TS_OFFSET));
-    wtr.write_u64::<BigEndian>(max_ts)
+fn write_events_len<T: Write + Seek>(wtr: &mut T, header: &Header) -> Result<(), WstfError> {
+    wtr.seek(SeekFrom::Start(V2_FIXED_LEN + header.events_offset()))?;
+    Ok(wtr.write_u64::<BigEndian>(header.events)?)
 }
 
-fn write_metadata<T: Write + Seek>(wtr: &mut T, ups: &[Update]) -> Result<(), io::Error> {
-    write_len(wtr, ups.len() as u64)?;
-    write_max_ts(wtr, get_max_ts_sorted(ups))
+fn write_min_ts<T: Write + Seek>(wtr: &mut T, min_ts: u64) -> Result<(), WstfError> {
+    wtr.seek(SeekFrom::Start(V2_MIN_TS_OFFSET))?;
+    Ok(wtr.write_u64::<BigEndian>(min_ts)?)
 }
 
-fn write_reference(
-    wtr: &mut dyn Write,
-    ref_ts: u64,
-    ref_seq: u32,
-    len: u16,
-) -> Result<(), io::Error> {
-    wtr.write_u8(true as u8)?;
-    wtr.write_u64::<BigEndian>(ref_ts)?;
-    wtr.write_u32::<BigEndian>(ref_seq)?;
-    wtr.write_u16::<BigEndian>(len)
+fn write_max_ts<T: Write + Seek>(
+    wtr: &mut T,
+    version: Version,
+    max_ts: u64,
+) -> Result<(), WstfError> {
+    wtr.seek(SeekFrom::Start(version.max_ts_offset()))?;
+    Ok(wtr.write_u64::<BigEndian>(max_ts)?)
 }
 
-#[cfg_attr(feature = "count_alloc", count_alloc)]
-pub fn write_batches<U: Deref<Target = Update>, I: Iterator<Item = U>>(
-    mut wtr: &mut dyn Write,
-    mut ups: Peekable<I>,
-) -> Result<(), io::Error> {
-    lazy_static! {
-        static ref BUF: Mutex<RefCell<Vec<u8>>> = Mutex::new(RefCell::new(vec![0; 100_000_000]));
-    }
-    let mut b = BUF.lock().unwrap();
-    let mut c = b.deref_mut().borrow_mut();
-    let mut buf = Cursor::new(&mut c[..]);
-    let head = ups.peek().unwrap();
-    let mut ref_ts = head.ts;
-    let mut ref_seq = head.seq;
-    let mut count: u16 = 0;
-
-    for elem in ups {
-        if count != 0
-            && (elem.ts >= ref_ts + 0xFFFF
-                || elem.seq >= ref_seq + 0xF
-                || elem.seq < ref_seq
-                || elem.ts < ref_ts
-                || count == 0xFFFF)
-        {
-            write_reference(&mut wtr, ref_ts, ref_seq, count)?;
-            let _ = wtr.write(&buf.get_ref()[0..(buf.position() as usize)]);
-            buf.set_position(0);
-
-            ref_ts = elem.ts;
-            ref_seq = elem.seq;
-            count = 0;
+pub fn write_header<T: Write + Seek>(wtr: &mut T, header: &Header) -> Result<(), WstfError> {
+    wtr.seek(SeekFrom::Start(0))?;
+    write_magic_value(wtr, header.version)?;
+    match header.version {
+        Version::V1 => {
+            write_symbol(wtr, &header.symbol)?;
+            wtr.write_u64::<BigEndian>(header.nums)?;
+            wtr.write_u64::<BigEndian>(header.max_ts)?;
+        }
+        Version::V2 => {
+            let mut flags = header.flags;
+            flags.set(HeaderFlags::FLAG_METADATA, header.instrument.is_some());
+            flags.set(HeaderFlags::FLAG_FIXED_POINT, header.scale.is_some());
+            flags.set(
+                HeaderFlags::FLAG_TIME_UNIT,
+                header.time_unit != TimeUnit::Millis,
+            );
+            flags.set(
+                HeaderFlags::FLAG_RECORD_KIND,
+                header.record_kind != RecordKind::Update,
+            );
+            wtr.write_u32::<BigEndian>(header.header_len as u32)?;
+            wtr.write_u32::<BigEndian>(flags.bits())?;
+            wtr.write_u64::<BigEndian>(header.nums)?;
+            wtr.write_u64::<BigEndian>(header.min_ts.unwrap_or(0))?;
+            wtr.write_u64::<BigEndian>(header.max_ts)?;
+            match header.instrument {
+                Some(_) => write_symbol(wtr, truncate_symbol(&header.symbol))?,
+                None => write_symbol(wtr, &header.symbol)?,
+            };
+            wtr.write_u64::<BigEndian>(header.index_offset.unwrap_or(0))?;
+            wtr.write_u8(header.compression.as_byte())?;
+            wtr.write_u8(header.encoding.as_byte())?;
+            if header.is_container() {
+                write_symbols(wtr, &header.symbols)?;
+            }
+            if let Some(instrument) = &header.instrument {
+                write_instrument(wtr, instrument)?;
+            }
+            if let Some(scale) = header.scale {
+                wtr.write_u8(scale.price_decimals)?;
+                wtr.write_u8(scale.size_decimals)?;
+            }
+            if header.time_unit != TimeUnit::Millis {
+                wtr.write_u8(header.time_unit.as_byte())?;
+            }
+            if header.record_kind != RecordKind::Update {
+                wtr.write_u8(header.record_kind.as_byte())?;
+            }
+            if header.has_events() {
+                wtr.write_u64::<BigEndian>(header.events)?;
+            }
+            let used = V2_FIXED_LEN + header.extension_len();
+            if header.header_len < used {
+                return Err(WstfError::InvalidHeader(format!(
+                    "header length {} is shorter than its {} bytes of content",
+                    header.header_len, used
+                )));
+            }
+            let reserved = vec![0u8; (header.header_len - used) as usize];
+            wtr.write_all(&reserved)?;
         }
-
-        elem.serialize_to_buffer(&mut buf, ref_ts, ref_seq);
-
-        count += 1;
     }
-
-    write_reference(&mut wtr, ref_ts, ref_seq, count)?;
-    wtr.write_all(&buf.get_ref()[0..(buf.position() as usize)])
-}
-
-pub fn write_main<'a, D: Deref<Target = Update>, T: Write + Seek, I: Iterator<Item = D>>(
-    wtr: &mut T,
-    ups: Peekable<I>,
-) -> Result<(), io::Error> {
-    wtr.seek(SeekFrom::Start(MAIN_OFFSET))?;
-    write_batches(wtr, ups)?;
     Ok(())
 }
 
-pub fn encode(fname: &str, symbol: &str, ups: &[Update]) -> Result<(), io::Error> {
-    let mut wtr = file_writer(fname, true)?;
-    encode_buffer(&mut wtr, symbol, ups)?;
-    wtr.flush()
-}
-
-pub fn encode_buffer<T: Write + Seek>(
-    wtr: &mut T,
-    symbol: &str,
-    ups: &[Update],
-) -> Result<(), io::Error> {
-    if !ups.is_empty() {
-        write_magic_value(wtr)?;
-        write_symbol(wtr, symbol)?;
-        write_metadata(wtr, ups)?;
-        write_main(wtr, ups.iter().peekable())?;
+fn write_symbols(wtr: &mut dyn Write, symbols: &[String]) -> Result<(), WstfError> {
+    if symbols.len() > u16::MAX as usize {
+        return Err(WstfError::Unsupported(format!(
+            "containers hold at most {} symbols",
+            u16::MAX
+        )));
+    }
+    wtr.write_u16::<BigEndian>(symbols.len() as u16)?;
+    for symbol in symbols {
+        if symbol.len() > u8::MAX as usize {
+            return Err(WstfError::SymbolTooLong(symbol.to_owned()));
+        }
+        wtr.write_u8(symbol.len() as u8)?;
+        wtr.write_all(symbol.as_bytes())?;
     }
     Ok(())
 }
 
-pub fn is_wstf(fname: &str) -> Result<bool, io::Error> {
-    let file = File::open(fname)?;
-    let mut rdr = BufReader::new(file);
-    read_magic_value(&mut rdr)
+fn truncate_symbol(symbol: &str) -> &str {
+    let mut end = symbol.len().min(SYMBOL_LEN);
+    while !symbol.is_char_boundary(end) {
+        end -= 1;
+    }
+    &symbol[..end]
 }
 
-pub fn read_magic_value<T: BufRead + Seek>(rdr: &mut T) -> Result<bool, io::Error> {
-    rdr.seek(SeekFrom::Start(0))?;
-    let mut buf = vec![0u8; 5];
-    rdr.read_exact(&mut buf)?;
-    Ok(buf == MAGIC_VALUE)
+fn instrument_len(instrument: &InstrumentMetadata) -> u64 {
+    let fields = [
+        instrument.tick_size.map(|_| 8),
+        instrument.lot_size.map(|_| 8),
+        instrument.price_decimals.map(|_| 1),
+    ];
+    let tags = instrument
+        .tags
+        .iter()
+        .map(|(key, value)| 4 + key.len() + value.len())
+        .sum::<usize>();
+    (4 + 2 + instrument.symbol.len() + 2 + fields.iter().flatten().sum::<usize>() + 2 + tags) as u64
 }
 
-pub fn file_reader(fname: &str) -> Result<BufReader<File>, io::Error> {
-    let file = File::open(fname)?;
-    let mut rdr = BufReader::new(file);
+fn write_short_str(wtr: &mut dyn Write, s: &str) -> Result<(), WstfError> {
+    if s.len() > u16::MAX as usize {
+        return Err(WstfError::InvalidHeader(format!(
+            "metadata field of {} bytes is too long",
+            s.len()
+        )));
+    }
+    wtr.write_u16::<BigEndian>(s.len() as u16)?;
+    Ok(wtr.write_all(s.as_bytes())?)
+}
 
-    if !read_magic_value(&mut rdr)? {
-        Err(io::Error::new(InvalidData, "magic value incorrect"))
-    } else {
-        Ok(rdr)
+fn write_instrument(wtr: &mut dyn Write, instrument: &InstrumentMetadata) -> Result<(), WstfError> {
+    wtr.write_u32::<BigEndian>(instrument_len(instrument) as u32 - 4)?;
+    write_short_str(wtr, &instrument.symbol)?;
+    wtr.write_u8(instrument.asset_type.as_byte())?;
+    let present = u8::from(instrument.tick_size.is_some())
+        | u8::from(instrument.lot_size.is_some()) << 1
+        | u8::from(instrument.price_decimals.is_some()) << 2;
+    wtr.write_u8(present)?;
+    if let Some(tick_size) = instrument.tick_size {
+        wtr.write_f64::<BigEndian>(tick_size.0)?;
+    }
+    if let Some(lot_size) = instrument.lot_size {
+        wtr.write_f64::<BigEndian>(lot_size.0)?;
+    }
+    if let Some(price_decimals) = instrument.price_decimals {
+        wtr.write_u8(price_decimals)?;
     }
+    if instrument.tags.len() > u16::MAX as usize {
+        return Err(WstfError::InvalidHeader(
+            "too many metadata tags".to_owned(),
+        ));
+    }
+    wtr.write_u16::<BigEndian>(instrument.tags.len() as u16)?;
+    for (key, value) in &instrument.tags {
+        write_short_str(wtr, key)?;
+        write_short_str(wtr, value)?;
+    }
+    Ok(())
 }
 
-fn read_symbol<T: BufRead + Seek>(rdr: &mut T) -> Result<String, io::Error> {
-    rdr.seek(SeekFrom::Start(SYMBOL_OFFSET))?;
-    let mut buffer = [0; SYMBOL_LEN];
-    rdr.read_exact(&mut buffer)?;
-    let ret = str::from_utf8(&buffer).unwrap().trim().to_owned();
-    Ok(ret)
+fn write_index_offset<T: Write + Seek>(wtr: &mut T, index_offset: u64) -> Result<(), WstfError> {
+    wtr.seek(SeekFrom::Start(V2_INDEX_OFFSET))?;
+    Ok(wtr.write_u64::<BigEndian>(index_offset)?)
 }
 
-fn read_len<T: Read + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
-    rdr.seek(SeekFrom::Start(LEN_OFFSET))?;
-    rdr.read_u64::<BigEndian>()
+fn write_reference(wtr: &mut dyn Write, meta: &BatchMetadata) -> Result<(), WstfError> {
+    wtr.write_u8(true as u8)?;
+    wtr.write_u64::<BigEndian>(meta.ref_ts)?;
+    wtr.write_u32::<BigEndian>(meta.ref_seq)?;
+    wtr.write_u16::<BigEndian>(meta.count)?;
+    if let Some(checksum) = meta.checksum {
+        wtr.write_u32::<BigEndian>(checksum)?;
+    }
+    if let Some(compressed_len) = meta.compressed_len {
+        wtr.write_u32::<BigEndian>(compressed_len)?;
+    }
+    if let Some(encoded_len) = meta.encoded_len {
+        wtr.write_u8(meta.encoding.as_byte())?;
+        wtr.write_u32::<BigEndian>(encoded_len)?;
+    }
+    if let Some(symbol_id) = meta.symbol_id {
+        wtr.write_u16::<BigEndian>(symbol_id)?;
+    }
+    if meta.kind_byte {
+        wtr.write_u8(meta.record_kind.as_byte())?;
+    }
+    Ok(())
 }
 
-fn read_min_ts<T: BufRead + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
-    Ok(read_first(rdr)?.ts)
+/// Covers every byte of the reference after the marker, except the checksum itself, and the
+/// payload.
+fn batch_checksum(meta: &BatchMetadata, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&meta.ref_ts.to_be_bytes());
+    hasher.update(&meta.ref_seq.to_be_bytes());
+    hasher.update(&meta.count.to_be_bytes());
+    if let Some(compressed_len) = meta.compressed_len {
+        hasher.update(&compressed_len.to_be_bytes());
+    }
+    if let Some(encoded_len) = meta.encoded_len {
+        hasher.update(&[meta.encoding.as_byte()]);
+        hasher.update(&encoded_len.to_be_bytes());
+    }
+    if let Some(symbol_id) = meta.symbol_id {
+        hasher.update(&symbol_id.to_be_bytes());
+    }
+    if meta.kind_byte {
+        hasher.update(&[meta.record_kind.as_byte()]);
+    }
+    hasher.update(payload);
+    hasher.finalize()
 }
 
-fn read_max_ts<T: BufRead + Seek>(rdr: &mut T) -> Result<u64, io::Error> {
-    rdr.seek(SeekFrom::Start(MAX_TS_OFFSET))?;
-    rdr.read_u64::<BigEndian>()
+fn write_batch(
+    wtr: &mut dyn Write,
+    fmt: &BatchFormat,
+    symbol_id: Option<u16>,
+    ref_ts: u64,
+    ref_seq: u32,
+    count: u16,
+    payload: &[u8],
+) -> Result<u64, WstfError> {
+    if fmt.encoding != BatchEncoding::Raw
+        && (fmt.scale.is_some() || fmt.record_kind == RecordKind::Order)
+    {
+        return Err(WstfError::Unsupported(
+            "only f32 update batches can be encoded".to_owned(),
+        ));
+    }
+    let (encoding, payload, encoded_len) = match fmt.encoding {
+        BatchEncoding::Raw => (BatchEncoding::Raw, Cow::Borrowed(payload), None),
+        // Event batches of an encoded file are stored raw.
+        _ if fmt.record_kind == RecordKind::Event => {
+            let len = payload.len() as u32;
+            (BatchEncoding::Raw, Cow::Borrowed(payload), Some(len))
+        }
+        encoding => {
+            let encoded = encode_payload(encoding, payload, fmt.time_unit);
+            if encoded.len() < payload.len() {
+                let len = encoded.len() as u32;
+                (encoding, encoded, Some(len))
+            } else {
+                let len = payload.len() as u32;
+                (BatchEncoding::Raw, Cow::Borrowed(payload), Some(len))
+            }
+        }
+    };
+    let (payload, compressed_len) = match fmt.compression {
+        Compression::None => (payload, None),
+        codec => {
+            let compressed = compress(codec, &payload)?;
+            let len = compressed.len() as u32;
+            (Cow::Owned(compressed), Some(len))
+        }
+    };
+    let mut meta = BatchMetadata {
+        ref_ts,
+        ref_seq,
+        count,
+        offset: 0,
+        checksum: None,
+        compression: fmt.compression,
+        compressed_len,
+        encoding,
+        encoded_len,
+        symbol_id: symbol_id.filter(|_| fmt.symbol_id),
+        kind_byte: fmt.kind_byte,
+        scale: fmt.scale,
+        time_unit: fmt.time_unit,
+        record_kind: fmt.record_kind,
+    };
+    if fmt.symbol_id && meta.symbol_id.is_none() {
+        return Err(WstfError::Unsupported(
+            "container batches require a symbol id".to_owned(),
+        ));
+    }
+    if fmt.checksum {
+        meta.checksum = Some(batch_checksum(&meta, &payload));
+    }
+    write_reference(wtr, &meta)?;
+    wtr.write_all(&payload)?;
+    Ok(fmt.ref_len() + payload.len() as u64)
 }
 
-pub fn get_range_in_file(fname: &str, min_ts: u64, max_ts: u64) -> Result<Vec<Update>, io::Error> {
-    let mut rdr = file_reader(fname)?;
-    range(&mut rdr, min_ts, max_ts)
+pub fn write_batches<U: Deref<Target = Update>, I: Iterator<Item = U>>(
+    wtr: &mut dyn Write,
+    ups: Peekable<I>,
+) -> Result<Vec<BatchIndexEntry>, WstfError> {
+    write_batches_with_format(wtr, &BatchFormat::default(), ups)
 }
 
-pub fn range<T: Read + Seek>(
-    rdr: &mut T,
-    min_ts: u64,
-    max_ts: u64,
-) -> Result<Vec<Update>, io::Error> {
-    let mut v: Vec<Update> = Vec::with_capacity(2048);
-    range_for_each(rdr, min_ts, max_ts, &mut |up| v.push(*up))?;
-    Ok(v)
+pub(crate) struct BatchBuilder {
+    fmt: BatchFormat,
+    symbol_id: Option<u16>,
+    buf: Vec<u8>,
+    ref_ts: u64,
+    ref_seq: u32,
+    count: u16,
 }
 
-fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
-    rdr: &mut T,
-    min_ts: u64,
-    max_ts: u64,
-    f: &mut F,
-) -> Result<(), io::Error> {
-    if min_ts > max_ts {
-        return Ok(());
+impl BatchBuilder {
+    pub(crate) fn new(fmt: BatchFormat, symbol_id: Option<u16>) -> BatchBuilder {
+        BatchBuilder {
+            fmt,
+            symbol_id,
+            buf: Vec::with_capacity(fmt.row_len() * 1024),
+            ref_ts: 0,
+            ref_seq: 0,
+            count: 0,
+        }
     }
 
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+    pub(crate) fn ref_ts(&self) -> Option<u64> {
+        (self.count != 0).then_some(self.ref_ts)
+    }
 
-    loop {
-        match rdr.read_u8() {
-            Ok(byte) => {
-                if byte != 0x1 {
-                    return Ok(());
-                }
-            }
-            Err(_e) => {
-                return Ok(());
-            }
-        };
+    pub(crate) fn is_full<R: Record>(&self, row: &R) -> bool {
+        self.count != 0
+            && (row.ts() >= self.ref_ts + self.fmt.time_unit.max_batch_span()
+                || row.seq() > self.ref_seq.saturating_add(MAX_SEQ_DELTA)
+                || row.seq() < self.ref_seq
+                || row.ts() < self.ref_ts
+                || self.count == 0xFFFF)
+    }
 
-        let current_meta = read_one_batch_meta(rdr);
-        let current_ref_ts = current_meta.ref_ts;
+    /// Pushes `up`, converting it to ticks when the batch stores fixed-point rows.
+    pub(crate) fn push(&mut self, up: &Update) -> Result<(), WstfError> {
+        self.expect_kind(RecordKind::Update)?;
+        match self.fmt.scale {
+            Some(scale) => self.push_record(&up.to_fixed(scale)?),
+            None => self.push_record(up),
+        }
+    }
 
-        let bytes_to_skip = current_meta.count as usize * BYTES_PER_ROW;
-        rdr.seek(SeekFrom::Current(bytes_to_skip as i64))
-            .expect(&format!("Skipping {} rows", current_meta.count));
+    pub(crate) fn push_fixed(&mut self, up: &FixedUpdate) -> Result<(), WstfError> {
+        self.expect_kind(RecordKind::Update)?;
+        if self.fmt.scale.is_none() {
+            return Err(WstfError::Unsupported(
+                "fixed-point updates require a fixed-point file".to_owned(),
+            ));
+        }
+        self.push_record(up)
+    }
 
-        match rdr.read_u8() {
-            Ok(byte) => {
-                if byte != 0x1 {
-                    return Ok(());
-                }
-            }
-            Err(_e) => {
-                rdr.seek(SeekFrom::Current(-(bytes_to_skip as i64)))
-                    .expect("scrolling back");
-                read_one_batch_main_for_each(rdr, current_meta, &mut |up| {
-                    if up.ts <= max_ts && up.ts >= min_ts {
-                        f(up);
-                    }
-                })?;
+    pub(crate) fn push_order(&mut self, order: &OrderUpdate) -> Result<(), WstfError> {
+        self.expect_kind(RecordKind::Order)?;
+        self.push_record(order)
+    }
 
-                return Ok(());
-            }
-        };
+    pub(crate) fn push_event(&mut self, event: &MarketEvent) -> Result<(), WstfError> {
+        self.expect_kind(RecordKind::Event)?;
+        self.push_record(event)
+    }
 
-        let next_meta = read_one_batch_meta(rdr);
-        let next_ref_ts = next_meta.ref_ts;
+    fn expect_kind(&self, record_kind: RecordKind) -> Result<(), WstfError> {
+        if self.fmt.record_kind != record_kind {
+            return Err(WstfError::Unsupported(format!(
+                "cannot write {} records into a file of {} records",
+                record_kind, self.fmt.record_kind
+            )));
+        }
+        Ok(())
+    }
+
+    fn push_record<R: Record>(&mut self, row: &R) -> Result<(), WstfError> {
+        if self.count == 0 {
+            self.ref_ts = row.ts();
+            self.ref_seq = row.seq();
+        }
+        row.serialize_delta(
+            &mut self.buf,
+            self.ref_ts,
+            self.ref_seq,
+            self.fmt.time_unit.has_wide_deltas(),
+        )?;
+        self.count += 1;
+        Ok(())
+    }
 
-        if min_ts < current_ref_ts && max_ts < current_ref_ts {
+    pub(crate) fn flush(
+        &mut self,
+        wtr: &mut dyn Write,
+        index: &mut IndexBuilder,
+    ) -> Result<(), WstfError> {
+        if self.count == 0 {
             return Ok(());
-        } else if (min_ts <= current_ref_ts && max_ts <= next_ref_ts)
-            || (min_ts < next_ref_ts && max_ts >= next_ref_ts)
-            || (min_ts > current_ref_ts && max_ts < next_ref_ts)
-        {
-            let bytes_to_scrollback = -(bytes_to_skip as i64) - 14 - 1;
-            rdr.seek(SeekFrom::Current(bytes_to_scrollback))
-                .expect("scrolling back");
-            if min_ts <= current_ref_ts && max_ts >= next_ref_ts {
-                read_one_batch_main_for_each(rdr, current_meta, f)?;
-            } else {
-                read_one_batch_main_for_each(rdr, current_meta, &mut |up| {
-                    if up.ts <= max_ts && up.ts >= min_ts {
-                        f(up);
-                    }
-                })?;
-            }
-        } else if min_ts >= next_ref_ts {
-            let bytes_to_scrollback = -14 - 1;
-            rdr.seek(SeekFrom::Current(bytes_to_scrollback))
-                .expect("SKIPPING n ROWS");
-        } else {
-            panic!(
-                "{}, {}, {}, {}..... Should have covered all the cases.",
-                min_ts, max_ts, current_ref_ts, next_ref_ts
-            );
         }
+        let written = write_batch(
+            wtr,
+            &self.fmt,
+            self.symbol_id,
+            self.ref_ts,
+            self.ref_seq,
+            self.count,
+            &self.buf,
+        )?;
+        index.entries.push(BatchIndexEntry {
+            ref_ts: self.ref_ts,
+            offset: index.offset,
+            count: self.count,
+        });
+        index.offset += written;
+        self.buf.clear();
+        self.count = 0;
+        Ok(())
     }
 }
 
-pub fn read_one_batch(rdr: &mut impl Read) -> Result<Vec<Update>, io::Error> {
-    let is_ref = rdr.read_u8()? == 0x1;
-    if !is_ref {
-        Ok(vec![])
-    } else {
-        let meta = read_one_batch_meta(rdr);
-        read_one_batch_main(rdr, meta)
-    }
+pub(crate) struct IndexBuilder {
+    pub(crate) offset: u64,
+    pub(crate) entries: Vec<BatchIndexEntry>,
 }
 
-pub fn read_one_batch_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(
-    rdr: &mut R,
-    f: &mut F,
-) -> Result<(), io::Error> {
-    let is_ref = rdr.read_u8()? == 0x1;
-    if !is_ref {
-        Ok(())
-    } else {
-        let meta = read_one_batch_meta(rdr);
-        read_one_batch_main_for_each(rdr, meta, f)
+impl IndexBuilder {
+    pub(crate) fn new(offset: u64) -> IndexBuilder {
+        IndexBuilder {
+            offset,
+            entries: vec![],
+        }
     }
 }
 
-pub fn read_one_batch_meta(rdr: &mut impl Read) -> BatchMetadata {
-    let ref_ts = rdr.read_u64::<BigEndian>().unwrap();
-    let ref_seq = rdr.read_u32::<BigEndian>().unwrap();
-    let count = rdr.read_u16::<BigEndian>().unwrap();
+#[cfg_attr(feature = "count_alloc", count_alloc)]
+pub fn write_batches_with_format<U: Deref<Target = Update>, I: Iterator<Item = U>>(
+    wtr: &mut dyn Write,
+    fmt: &BatchFormat,
+    ups: Peekable<I>,
+) -> Result<Vec<BatchIndexEntry>, WstfError> {
+    let mut index = IndexBuilder::new(0);
+    let mut batch = BatchBuilder::new(*fmt, None);
+    for elem in ups {
+        if batch.is_full(&*elem) {
+            batch.flush(wtr, &mut index)?;
+        }
+        batch.push(&elem)?;
+    }
+    batch.flush(wtr, &mut index)?;
+    Ok(index.entries)
+}
 
-    BatchMetadata {
-        ref_ts,
-        ref_seq,
-        count,
+pub fn write_main<D: Deref<Target = Update>, T: Write + Seek, I: Iterator<Item = D>>(
+    wtr: &mut T,
+    header: &Header,
+    ups: Peekable<I>,
+) -> Result<Vec<BatchIndexEntry>, WstfError> {
+    let main_offset = header.main_offset();
+    wtr.seek(SeekFrom::Start(main_offset))?;
+    let mut entries = write_batches_with_format(wtr, &header.batch_format(), ups)?;
+    for entry in entries.iter_mut() {
+        entry.offset += main_offset;
     }
+    Ok(entries)
 }
 
-fn read_one_batch_main_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(
-    rdr: &mut R,
-    meta: BatchMetadata,
-    f: &mut F,
-) -> Result<(), io::Error> {
-    for _i in 0..meta.count {
-        let up = read_one_update(rdr, &meta)?;
-        f(&up);
+pub fn encode(fname: &str, symbol: &str, ups: &[Update]) -> Result<(), WstfError> {
+    encode_with_options(fname, symbol, ups, &EncodeOptions::default())
+}
+
+pub fn encode_with_options(
+    fname: &str,
+    symbol: &str,
+    ups: &[Update],
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let mut wtr = file_writer(fname, true)?;
+    encode_buffer_with_options(&mut wtr, symbol, ups, opts)?;
+    Ok(wtr.flush()?)
+}
+
+pub fn encode_with_metadata(
+    fname: &str,
+    instrument: &InstrumentMetadata,
+    ups: &[Update],
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let mut writer = WstfWriter::with_metadata(file_writer(fname, true)?, instrument, opts)?;
+    for up in ups {
+        writer.push(up)?;
     }
+    writer.finish()?;
     Ok(())
 }
 
-fn read_one_batch_main(rdr: &mut impl Read, meta: BatchMetadata) -> Result<Vec<Update>, io::Error> {
-    let mut v: Vec<Update> = vec![];
-    for _i in 0..meta.count {
-        let up = read_one_update(rdr, &meta)?;
-        v.push(up);
+pub fn encode_container(
+    fname: &str,
+    name: &str,
+    streams: &[(&str, &[Update])],
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let symbols = streams
+        .iter()
+        .map(|&(symbol, _ups)| symbol)
+        .collect::<Vec<_>>();
+    let mut merged = streams
+        .iter()
+        .enumerate()
+        .flat_map(|(id, &(_symbol, ups))| ups.iter().map(move |up| (id as u16, up)))
+        .collect::<Vec<_>>();
+    merged.sort_by_key(|&(_id, up)| up.ts);
+
+    let mut writer = ContainerWriter::new(file_writer(fname, true)?, name, &symbols, opts)?;
+    for (id, up) in merged {
+        writer.push(id, up)?;
     }
-    Ok(v)
+    writer.finish()?;
+    Ok(())
 }
 
-fn read_one_update(rdr: &mut dyn Read, meta: &BatchMetadata) -> Result<Update, io::Error> {
-    let ts = u64::from(rdr.read_u16::<BigEndian>()?) + meta.ref_ts;
-    let seq = u32::from(rdr.read_u8()?) + meta.ref_seq;
-    let flags = rdr.read_u8()?;
-    let is_trade = (Flags::from_bits(flags).ok_or(InvalidData)? & Flags::FLAG_IS_TRADE).to_bool();
-    let is_bid = (Flags::from_bits(flags).ok_or(InvalidData)? & Flags::FLAG_IS_BID).to_bool();
-    let price = rdr.read_f32::<BigEndian>()?;
-    let size = rdr.read_f32::<BigEndian>()?;
-    Ok(Update {
-        ts,
-        seq,
-        is_trade,
-        is_bid,
-        price,
-        size,
-    })
+pub fn encode_orders(
+    fname: &str,
+    symbol: &str,
+    orders: &[OrderUpdate],
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let opts = EncodeOptions {
+        record_kind: RecordKind::Order,
+        ..*opts
+    };
+    let mut writer = WstfWriter::with_options(file_writer(fname, true)?, symbol, &opts)?;
+    for order in orders {
+        writer.push_order(order)?;
+    }
+    writer.finish()?;
+    Ok(())
 }
 
-fn read_first_batch<T: BufRead + Seek>(mut rdr: &mut T) -> Result<Vec<Update>, io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    read_one_batch(&mut rdr)
+/// Encodes `ups` with `events` interleaved by timestamp, both slices must be sorted.
+pub fn encode_with_events(
+    fname: &str,
+    symbol: &str,
+    ups: &[Update],
+    events: &[MarketEvent],
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let opts = EncodeOptions {
+        events: true,
+        ..*opts
+    };
+    let mut writer = WstfWriter::with_options(file_writer(fname, true)?, symbol, &opts)?;
+    let mut events = events.iter().peekable();
+    for up in ups {
+        while let Some(event) = events.next_if(|event| event.ts <= up.ts) {
+            writer.push_event(event)?;
+        }
+        writer.push(up)?;
+    }
+    for event in events {
+        writer.push_event(event)?;
+    }
+    writer.finish()?;
+    Ok(())
 }
 
-fn read_first<T: BufRead + Seek>(mut rdr: &mut T) -> Result<Update, io::Error> {
-    let batch = read_first_batch(&mut rdr)?;
-    Ok(batch[0].clone())
+pub fn encode_buffer<T: Write + Seek>(
+    wtr: &mut T,
+    symbol: &str,
+    ups: &[Update],
+) -> Result<(), WstfError> {
+    encode_buffer_with_options(wtr, symbol, ups, &EncodeOptions::default())
 }
 
-pub fn get_size(fname: &str) -> Result<u64, io::Error> {
-    let mut rdr = file_reader(fname)?;
-    read_len(&mut rdr)
+pub fn encode_buffer_with_options<T: Write + Seek>(
+    wtr: &mut T,
+    symbol: &str,
+    ups: &[Update],
+    opts: &EncodeOptions,
+) -> Result<(), WstfError> {
+    let header = header_for_options(symbol, opts)?;
+    if !ups.is_empty() {
+        let mut writer = WstfWriter::from_header(wtr, header)?;
+        for up in ups {
+            writer.push(up)?;
+        }
+        writer.finish()?;
+    }
+    Ok(())
 }
 
-pub fn read_meta_from_buf<T: BufRead + Seek>(mut rdr: &mut T) -> Result<Metadata, io::Error> {
-    let symbol = read_symbol(&mut rdr)?;
-    let nums = read_len(&mut rdr)?;
-    let max_ts = read_max_ts(&mut rdr)?;
-    let min_ts = if nums > 0 {
-        read_min_ts(&mut rdr)?
-    } else {
-        max_ts
-    };
+pub(crate) fn header_for_options(symbol: &str, opts: &EncodeOptions) -> Result<Header, WstfError> {
+    if opts.version == Version::V1
+        && (opts.checksum
+            || opts.compression != Compression::None
+            || opts.encoding != BatchEncoding::Raw
+            || opts.scale.is_some()
+            || opts.time_unit != TimeUnit::Millis
+            || opts.record_kind != RecordKind::Update
+            || opts.events)
+    {
+        return Err(WstfError::Unsupported(
+            "batch checksums, compression, encodings, fixed-point rows, time units, record \
+             kinds and events require a v2 header"
+                .to_owned(),
+        ));
+    }
+    if let Some(scale) = opts.scale {
+        scale.validate()?;
+    }
+    if opts.scale.is_some() && opts.encoding != BatchEncoding::Raw {
+        return Err(WstfError::Unsupported(format!(
+            "{} encoding does not support fixed-point rows",
+            opts.encoding
+        )));
+    }
+    if opts.record_kind == RecordKind::Event {
+        return Err(WstfError::Unsupported(
+            "event records are stored alongside updates, see `EncodeOptions::events`".to_owned(),
+        ));
+    }
+    if opts.record_kind != RecordKind::Update
+        && (opts.scale.is_some() || opts.encoding != BatchEncoding::Raw)
+    {
+        return Err(WstfError::Unsupported(format!(
+            "{} records support neither fixed-point rows nor batch encodings",
+            opts.record_kind
+        )));
+    }
+    if !opts.compression.is_available() {
+        return Err(WstfError::Unsupported(format!(
+            "{} compression is not enabled",
+            opts.compression
+        )));
+    }
+    let mut header = Header::new(opts.version, symbol);
+    if opts.checksum {
+        header.flags |= HeaderFlags::FLAG_CHECKSUM;
+    }
+    header.compression = opts.compression;
+    header.encoding = opts.encoding;
+    header.scale = opts.scale;
+    header.time_unit = opts.time_unit;
+    header.record_kind = opts.record_kind;
+    header
+        .flags
+        .set(HeaderFlags::FLAG_FIXED_POINT, opts.scale.is_some());
+    header.flags.set(
+        HeaderFlags::FLAG_TIME_UNIT,
+        opts.time_unit != TimeUnit::Millis,
+    );
+    header.flags.set(
+        HeaderFlags::FLAG_RECORD_KIND,
+        opts.record_kind != RecordKind::Update,
+    );
+    header.flags.set(HeaderFlags::FLAG_EVENTS, opts.events);
+    header.fit_header_len();
+    if opts.version == Version::V2 && symbol.len() > SYMBOL_LEN {
+        header.set_instrument(InstrumentMetadata::new(symbol));
+    }
+    Ok(header)
+}
 
-    Ok(Metadata {
-        symbol,
-        nums,
-        max_ts,
-        min_ts,
-    })
+pub(crate) fn header_for_instrument(
+    instrument: &InstrumentMetadata,
+    opts: &EncodeOptions,
+) -> Result<Header, WstfError> {
+    if opts.version == Version::V1 {
+        return Err(WstfError::Unsupported(
+            "instrument metadata requires a v2 header".to_owned(),
+        ));
+    }
+    let mut header = header_for_options(&instrument.symbol, opts)?;
+    header.set_instrument(instrument.clone());
+    Ok(header)
 }
 
-pub fn read_meta(fname: &str) -> Result<Metadata, io::Error> {
-    let mut rdr = file_reader(fname)?;
-    read_meta_from_buf(&mut rdr)
+pub fn is_wstf(fname: &str) -> Result<bool, WstfError> {
+    let file = File::open(fname)?;
+    let mut rdr = BufReader::new(file);
+    Ok(read_magic_value(&mut rdr)?.is_some())
 }
 
-pub struct WSTFBufReader {
-    pub rdr: BufReader<File>,
-    batch_size: u32,
+pub fn read_magic_value<T: Read + Seek>(rdr: &mut T) -> Result<Option<Version>, WstfError> {
+    rdr.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0u8; 5];
+    rdr.read_exact(&mut buf)?;
+    if buf[..4] != *MAGIC_VALUE {
+        return Ok(None);
+    }
+    match Version::from_byte(buf[4]) {
+        Some(version) => Ok(Some(version)),
+        None => Err(WstfError::UnsupportedVersion(buf[4])),
+    }
 }
 
-impl WSTFBufReader {
-    pub fn new(fname: &str, batch_size: u32) -> Self {
-        let mut rdr = file_reader(fname).expect("Cannot open file");
-        rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-        WSTFBufReader { rdr, batch_size }
+pub fn file_reader(fname: &str) -> Result<BufReader<File>, WstfError> {
+    let file = File::open(fname)?;
+    let mut rdr = BufReader::new(file);
+
+    if read_magic_value(&mut rdr)?.is_none() {
+        Err(WstfError::BadMagic)
+    } else {
+        Ok(rdr)
     }
 }
 
-impl Iterator for WSTFBufReader {
-    type Item = Vec<Update>;
+pub fn read_header<T: Read + Seek>(rdr: &mut T) -> Result<Header, WstfError> {
+    let version = match read_magic_value(rdr)? {
+        Some(version) => version,
+        None => return Err(WstfError::BadMagic),
+    };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let v = read_n_batches(&mut self.rdr, self.batch_size).ok()?;
-        if 0 != v.len() {
-            Some(v)
-        } else {
-            None
+    match version {
+        Version::V1 => {
+            let symbol = read_symbol(rdr, SYMBOL_OFFSET)?;
+            rdr.seek(SeekFrom::Start(LEN_OFFSET))?;
+            let nums = rdr.read_u64::<BigEndian>()?;
+            rdr.seek(SeekFrom::Start(MAX_TS_OFFSET))?;
+            let max_ts = rdr.read_u64::<BigEndian>()?;
+            Ok(Header {
+                nums,
+                max_ts,
+                ..Header::new(version, &symbol)
+            })
+        }
+        Version::V2 => {
+            rdr.seek(SeekFrom::Start(V2_HEADER_LEN_OFFSET))?;
+            let header_len = u64::from(rdr.read_u32::<BigEndian>()?);
+            if header_len < V2_FIXED_LEN {
+                return Err(WstfError::InvalidHeader(format!(
+                    "header length {} is too short",
+                    header_len
+                )));
+            }
+            rdr.seek(SeekFrom::Start(V2_FLAGS_OFFSET))?;
+            let bits = rdr.read_u32::<BigEndian>()?;
+            let flags = HeaderFlags::from_bits(bits).ok_or(WstfError::InvalidFlags(bits))?;
+            rdr.seek(SeekFrom::Start(V2_LEN_OFFSET))?;
+            let nums = rdr.read_u64::<BigEndian>()?;
+            rdr.seek(SeekFrom::Start(V2_MIN_TS_OFFSET))?;
+            let min_ts = rdr.read_u64::<BigEndian>()?;
+            rdr.seek(SeekFrom::Start(V2_MAX_TS_OFFSET))?;
+            let max_ts = rdr.read_u64::<BigEndian>()?;
+            let symbol = read_symbol(rdr, V2_SYMBOL_OFFSET)?;
+            rdr.seek(SeekFrom::Start(V2_INDEX_OFFSET))?;
+            let index_offset = rdr.read_u64::<BigEndian>()?;
+            rdr.seek(SeekFrom::Start(V2_COMPRESSION_OFFSET))?;
+            let codec = rdr.read_u8()?;
+            let compression = Compression::from_byte(codec).ok_or_else(|| {
+                WstfError::InvalidHeader(format!("unknown compression {}", codec))
+            })?;
+            rdr.seek(SeekFrom::Start(V2_ENCODING_OFFSET))?;
+            let encoding = encoding_from_byte(rdr.read_u8()?)?;
+            let symbols = if flags.contains(HeaderFlags::FLAG_CONTAINER) {
+                read_symbols(rdr)?
+            } else {
+                vec![]
+            };
+            let mut header = Header {
+                version,
+                header_len,
+                flags,
+                symbol,
+                nums,
+                // An empty file has no minimum timestamp.
+                min_ts: (nums > 0 || min_ts > 0).then_some(min_ts),
+                max_ts,
+                index_offset: if index_offset > 0 {
+                    Some(index_offset)
+                } else {
+                    None
+                },
+                compression,
+                encoding,
+                symbols,
+                instrument: None,
+                scale: None,
+                time_unit: TimeUnit::Millis,
+                record_kind: RecordKind::Update,
+                events: 0,
+            };
+            if flags.contains(HeaderFlags::FLAG_METADATA) {
+                rdr.seek(SeekFrom::Start(V2_FIXED_LEN + header.symbols_len()))?;
+                let instrument = read_instrument(rdr, header_len)?;
+                header.symbol = instrument.symbol.clone();
+                header.instrument = Some(instrument);
+            }
+            if flags.contains(HeaderFlags::FLAG_FIXED_POINT) {
+                rdr.seek(SeekFrom::Start(V2_FIXED_LEN + header.scale_offset()))?;
+                let price_decimals = rdr.read_u8()?;
+                let size_decimals = rdr.read_u8()?;
+                let scale = FixedScale::new(price_decimals, size_decimals);
+                if scale.validate().is_err() {
+                    return Err(WstfError::InvalidHeader(format!(
+                        "fixed-point scale of {} and {} decimals",
+                        price_decimals, size_decimals
+                    )));
+                }
+                header.scale = Some(scale);
+            }
+            if flags.contains(HeaderFlags::FLAG_TIME_UNIT) {
+                rdr.seek(SeekFrom::Start(V2_FIXED_LEN + header.time_unit_offset()))?;
+                let byte = rdr.read_u8()?;
+                header.time_unit = TimeUnit::from_byte(byte).ok_or_else(|| {
+                    WstfError::InvalidHeader(format!("unknown time unit {}", byte))
+                })?;
+            }
+            if flags.contains(HeaderFlags::FLAG_RECORD_KIND) {
+                rdr.seek(SeekFrom::Start(V2_FIXED_LEN + header.record_kind_offset()))?;
+                let byte = rdr.read_u8()?;
+                header.record_kind = RecordKind::from_byte(byte).ok_or_else(|| {
+                    WstfError::InvalidHeader(format!("unknown record kind {}", byte))
+                })?;
+            }
+            if flags.contains(HeaderFlags::FLAG_EVENTS) {
+                rdr.seek(SeekFrom::Start(V2_FIXED_LEN + header.events_offset()))?;
+                header.events = rdr.read_u64::<BigEndian>()?;
+            }
+            if header.header_len < V2_FIXED_LEN + header.extension_len() {
+                return Err(WstfError::InvalidHeader(format!(
+                    "header length {} does not cover its metadata",
+                    header_len
+                )));
+            }
+            Ok(header)
         }
     }
 }
 
-fn read_n_batches<T: BufRead + Seek>(
-    mut rdr: &mut T,
-    num_rows: u32,
-) -> Result<Vec<Update>, io::Error> {
-    let mut v: Vec<Update> = vec![];
-    let mut count = 0;
-    if num_rows == 0 {
-        return Ok(v);
+fn encoding_from_byte(byte: u8) -> Result<BatchEncoding, WstfError> {
+    BatchEncoding::from_byte(byte)
+        .ok_or_else(|| WstfError::Corrupt(format!("unknown batch encoding {}", byte)))
+}
+
+fn read_symbol<T: Read + Seek>(rdr: &mut T, offset: u64) -> Result<String, WstfError> {
+    rdr.seek(SeekFrom::Start(offset))?;
+    let mut buffer = [0; SYMBOL_LEN];
+    rdr.read_exact(&mut buffer)?;
+    let ret = str::from_utf8(&buffer)
+        .map_err(|_| WstfError::InvalidHeader("symbol is not valid UTF-8".to_owned()))?;
+    Ok(ret.trim().to_owned())
+}
+
+fn read_symbols<T: Read + Seek>(rdr: &mut T) -> Result<Vec<String>, WstfError> {
+    rdr.seek(SeekFrom::Start(V2_FIXED_LEN))?;
+    let count = rdr.read_u16::<BigEndian>()?;
+    let mut symbols = Vec::with_capacity(count as usize);
+    for _i in 0..count {
+        let mut buffer = vec![0; rdr.read_u8()? as usize];
+        rdr.read_exact(&mut buffer)?;
+        let symbol = String::from_utf8(buffer)
+            .map_err(|_| WstfError::InvalidHeader("symbol is not valid UTF-8".to_owned()))?;
+        symbols.push(symbol);
     }
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            v.extend(read_one_batch(&mut rdr)?);
-        }
+    Ok(symbols)
+}
 
-        count += 1;
+fn read_short_str(rdr: &mut dyn Read) -> Result<String, WstfError> {
+    let mut buffer = vec![0; rdr.read_u16::<BigEndian>()? as usize];
+    rdr.read_exact(&mut buffer)?;
+    String::from_utf8(buffer)
+        .map_err(|_| WstfError::InvalidHeader("metadata is not valid UTF-8".to_owned()))
+}
 
-        if count > num_rows {
-            break;
-        }
+/// Reads the instrument block, which must end within the first `header_len` bytes.
+fn read_instrument<T: Read + Seek>(
+    rdr: &mut T,
+    header_len: u64,
+) -> Result<InstrumentMetadata, WstfError> {
+    let block_len = u64::from(rdr.read_u32::<BigEndian>()?);
+    let offset = rdr.stream_position()?;
+    if block_len > header_len.saturating_sub(offset) {
+        return Err(WstfError::InvalidHeader(format!(
+            "instrument block of {} bytes at offset {} overruns the {} byte header",
+            block_len, offset, header_len
+        )));
+    }
+    let mut block = vec![0; block_len as usize];
+    rdr.read_exact(&mut block)?;
+    let mut rdr = &block[..];
+
+    let symbol = read_short_str(&mut rdr)?;
+    let byte = rdr.read_u8()?;
+    let asset_type = AssetType::from_byte(byte)
+        .ok_or_else(|| WstfError::InvalidHeader(format!("unknown asset type {}", byte)))?;
+    let present = rdr.read_u8()?;
+    let tick_size = match present & 0b001 {
+        0 => None,
+        _ => Some(OrderedFloat(rdr.read_f64::<BigEndian>()?)),
+    };
+    let lot_size = match present & 0b010 {
+        0 => None,
+        _ => Some(OrderedFloat(rdr.read_f64::<BigEndian>()?)),
+    };
+    let price_decimals = match present & 0b100 {
+        0 => None,
+        _ => Some(rdr.read_u8()?),
+    };
+    let count = rdr.read_u16::<BigEndian>()?;
+    let mut tags = Vec::with_capacity(count as usize);
+    for _i in 0..count {
+        tags.push((read_short_str(&mut rdr)?, read_short_str(&mut rdr)?));
     }
+    Ok(InstrumentMetadata {
+        symbol,
+        asset_type,
+        tick_size,
+        lot_size,
+        price_decimals,
+        tags,
+    })
+}
+
+fn read_len<T: Read + Seek>(rdr: &mut T) -> Result<u64, WstfError> {
+    Ok(read_header(rdr)?.nums)
+}
+
+fn read_min_ts<T: BufRead + Seek>(rdr: &mut T) -> Result<u64, WstfError> {
+    Ok(read_first(rdr)?.ts)
+}
+
+pub fn get_range_in_file(fname: &str, min_ts: u64, max_ts: u64) -> Result<Vec<Update>, WstfError> {
+    get_range_in_file_with_symbol(fname, min_ts, max_ts, None)
+}
+
+pub fn get_range_in_file_with_symbol(
+    fname: &str,
+    min_ts: u64,
+    max_ts: u64,
+    symbol: Option<&str>,
+) -> Result<Vec<Update>, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    range_with_symbol(&mut rdr, min_ts, max_ts, symbol)
+}
+
+pub fn range<T: Read + Seek>(
+    rdr: &mut T,
+    min_ts: u64,
+    max_ts: u64,
+) -> Result<Vec<Update>, WstfError> {
+    let mut v: Vec<Update> = Vec::with_capacity(2048);
+    range_for_each(rdr, min_ts, max_ts, &mut |up| v.push(*up))?;
     Ok(v)
 }
 
-fn read_all<T: BufRead + Seek>(mut rdr: &mut T) -> Result<Vec<Update>, io::Error> {
-    let mut v: Vec<Update> = vec![];
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            v.extend(read_one_batch(&mut rdr)?);
-        }
-    }
+pub fn range_with_symbol<T: Read + Seek>(
+    rdr: &mut T,
+    min_ts: u64,
+    max_ts: u64,
+    symbol: Option<&str>,
+) -> Result<Vec<Update>, WstfError> {
+    let mut v: Vec<Update> = Vec::with_capacity(2048);
+    range_for_each_with_symbol(rdr, min_ts, max_ts, symbol, &mut |up| v.push(*up))?;
     Ok(v)
 }
 
-fn read_all_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
-    mut rdr: &mut T,
+fn range_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut T,
+    min_ts: u64,
+    max_ts: u64,
     f: &mut F,
-) -> Result<(), io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            read_one_batch_for_each(&mut rdr, f)?;
-        }
-    }
-    Ok(())
+) -> Result<(), WstfError> {
+    range_for_each_with_symbol(rdr, min_ts, max_ts, None, f)
 }
 
-fn read_n_batches_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
-    mut rdr: &mut T,
-    num_rows: u32,
+fn range_for_each_with_symbol<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut T,
+    min_ts: u64,
+    max_ts: u64,
+    symbol: Option<&str>,
     f: &mut F,
-) -> Result<(), io::Error> {
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
-    let mut count = 0;
-    if num_rows == 0 {
+) -> Result<(), WstfError> {
+    if min_ts > max_ts {
         return Ok(());
     }
-    while let Ok(is_ref) = rdr.read_u8() {
-        if is_ref == 0x1 {
-            rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-            read_one_batch_for_each(&mut rdr, f)?;
-        }
-        count += 1;
-        if count > num_rows {
+
+    let header = read_header(rdr)?;
+    let fmt = header.batch_format();
+    let filter = match BatchFilter::new(&header, symbol) {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+    let first = match first_batch_for(rdr, &header, min_ts)? {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+
+    rdr.seek(SeekFrom::Start(first.offset))?;
+    while let Some(meta) = read_next_batch_meta(rdr, &fmt, header.index_offset)? {
+        if meta.ref_ts > max_ts {
             break;
         }
+        if !filter.matches(&meta) {
+            skip_batch_payload(rdr, &meta)?;
+            continue;
+        }
+        read_one_batch_main_for_each(rdr, &meta, &mut |up| {
+            if up.ts <= max_ts && up.ts >= min_ts {
+                f(up);
+            }
+        })?;
     }
     Ok(())
 }
 
-pub fn decode(fname: &str, num_rows: Option<u32>) -> Result<Vec<Update>, io::Error> {
-    let mut rdr = file_reader(fname)?;
-    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+#[derive(Debug, Clone, Copy)]
+enum BatchFilter {
+    All,
+    Symbol(u16),
+}
 
-    match num_rows {
-        Some(num_rows) => read_n_batches(&mut rdr, num_rows),
-        None => read_all(&mut rdr),
+impl BatchFilter {
+    /// Returns `None` when no batch in the file can belong to `symbol`.
+    fn new(header: &Header, symbol: Option<&str>) -> Option<BatchFilter> {
+        match symbol {
+            None => Some(BatchFilter::All),
+            Some(symbol) if header.is_container() => {
+                header.symbol_id(symbol).map(BatchFilter::Symbol)
+            }
+            Some(symbol) if symbol == header.symbol => Some(BatchFilter::All),
+            Some(_) => None,
+        }
     }
-}
 
-pub fn decode_for_each<F: for<'a> FnMut(&'a Update)>(
-    fname: &str,
-    num_rows: Option<u32>,
-    f: &mut F,
-) -> Result<(), io::Error> {
-    let mut rdr = file_reader(fname)?;
-    match num_rows {
-        Some(num_rows) => read_n_batches_for_each(&mut rdr, num_rows, f),
-        None => read_all_for_each(&mut rdr, f),
+    /// Auxiliary event batches never match, readers of updates skip them.
+    fn matches(self, meta: &BatchMetadata) -> bool {
+        meta.record_kind != RecordKind::Event
+            && match self {
+                BatchFilter::All => true,
+                BatchFilter::Symbol(id) => meta.symbol_id == Some(id),
+            }
     }
 }
 
-pub fn decode_buffer(mut buf: &mut dyn Read) -> Vec<Update> {
-    let mut v = vec![];
-    let mut res = read_one_batch(&mut buf);
-    while let Ok(ups) = res {
-        v.extend(ups);
-        res = read_one_batch(&mut buf);
+pub(crate) fn first_batch_for<T: Read + Seek>(
+    rdr: &mut T,
+    header: &Header,
+    min_ts: u64,
+) -> Result<Option<BatchIndexEntry>, WstfError> {
+    // Container batches of different symbols, and event batches, overlap the other batches in
+    // time, so look back one batch span.
+    let min_ts = if header.is_container() || header.has_events() {
+        min_ts.saturating_sub(header.time_unit.max_batch_span())
+    } else {
+        min_ts
+    };
+    match header.index_offset {
+        Some(index_offset) => Ok(search_index(rdr, index_offset, min_ts)?.map(|(_i, entry)| entry)),
+        None => {
+            let entries = read_batch_index(rdr, header)?;
+            let i = entries.partition_point(|entry| entry.ref_ts < min_ts);
+            Ok(entries.get(i.saturating_sub(1)).copied())
+        }
     }
-    v
 }
 
-#[cfg_attr(feature = "count_alloc", count_alloc)]
-pub fn append(fname: &str, ups: &[Update]) -> Result<(), io::Error> {
-    let mut rdr = file_reader(fname)?;
-    let _symbol = read_symbol(&mut rdr)?;
-
-    let old_max_ts = read_max_ts(&mut rdr)?;
-
-    let mut ups = ups.into_iter().filter(|up| up.ts > old_max_ts).peekable();
-
-    if ups.peek().is_none() {
-        return Ok(());
+pub fn read_batch_index<T: Read + Seek>(
+    rdr: &mut T,
+    header: &Header,
+) -> Result<Vec<BatchIndexEntry>, WstfError> {
+    if let Some(index_offset) = header.index_offset {
+        return read_index(rdr, index_offset);
     }
 
-    let new_min_ts = ups.clone().next().unwrap().ts;
-    let new_max_ts = ups.clone().next_back().unwrap().ts;
-
-    if new_min_ts <= old_max_ts {
-        panic!("Cannot append data!(not implemented)");
+    let fmt = header.batch_format();
+    let mut entries = vec![];
+    let mut nums = 0;
+    rdr.seek(SeekFrom::Start(header.main_offset()))?;
+    while let Some(meta) = read_next_batch_meta(rdr, &fmt, None)? {
+        entries.push(BatchIndexEntry {
+            ref_ts: meta.ref_ts,
+            offset: meta.offset,
+            count: meta.count,
+        });
+        if meta.record_kind != RecordKind::Event {
+            nums += u64::from(meta.count);
+        }
+        rdr.seek(SeekFrom::Current(meta.payload_len() as i64))?;
     }
+    check_nums(header, nums)?;
+    Ok(entries)
+}
 
-    let cur_len = read_len(&mut rdr)?;
-
-    let new_len = cur_len + ups.clone().count() as u64;
-
-    let mut wtr = file_writer(fname, false)?;
-    write_len(&mut wtr, new_len)?;
-    write_max_ts(&mut wtr, new_max_ts)?;
-
-    if cur_len == 0 {
-        wtr.seek(SeekFrom::Start(MAIN_OFFSET)).unwrap();
+pub fn read_one_batch(rdr: &mut impl Read) -> Result<Vec<Update>, WstfError> {
+    let is_ref = rdr.read_u8()? == 0x1;
+    if !is_ref {
+        Ok(vec![])
     } else {
-        wtr.seek(SeekFrom::End(0)).unwrap();
+        let meta = read_one_batch_meta(rdr)?;
+        read_one_batch_main(rdr, &meta)
     }
-    write_batches(&mut wtr, ups.peekable())?;
-    wtr.flush().unwrap();
+}
 
+pub fn read_one_batch_for_each<R: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut R,
+    f: &mut F,
+) -> Result<(), WstfError> {
+    if let Some(meta) = read_next_batch_meta(rdr, &BatchFormat::default(), None)? {
+        read_one_batch_main_for_each(rdr, &meta, f)?;
+    }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serial_test::serial;
+pub fn read_one_batch_meta(rdr: &mut impl Read) -> Result<BatchMetadata, WstfError> {
+    read_batch_meta(rdr, &BatchFormat::default(), 0)
+}
 
-    static SYMBOL: &str = "BTC_USDT";
+pub(crate) fn read_batch_meta(
+    rdr: &mut dyn Read,
+    fmt: &BatchFormat,
+    offset: u64,
+) -> Result<BatchMetadata, WstfError> {
+    let truncated = move |_| WstfError::Truncated { offset };
+    let ref_ts = rdr.read_u64::<BigEndian>().map_err(truncated)?;
+    let ref_seq = rdr.read_u32::<BigEndian>().map_err(truncated)?;
+    let count = rdr.read_u16::<BigEndian>().map_err(truncated)?;
+    let checksum = if fmt.checksum {
+        Some(rdr.read_u32::<BigEndian>().map_err(truncated)?)
+    } else {
+        None
+    };
+    let compressed_len = if fmt.compression != Compression::None {
+        Some(rdr.read_u32::<BigEndian>().map_err(truncated)?)
+    } else {
+        None
+    };
+    let (encoding, encoded_len) = if fmt.encoding != BatchEncoding::Raw {
+        let encoding = encoding_from_byte(rdr.read_u8().map_err(truncated)?)?;
+        (
+            encoding,
+            Some(rdr.read_u32::<BigEndian>().map_err(truncated)?),
+        )
+    } else {
+        (BatchEncoding::Raw, None)
+    };
+    let symbol_id = if fmt.symbol_id {
+        Some(rdr.read_u16::<BigEndian>().map_err(truncated)?)
+    } else {
+        None
+    };
+    let record_kind = if fmt.kind_byte {
+        let byte = rdr.read_u8().map_err(truncated)?;
+        RecordKind::from_byte(byte)
+            .ok_or_else(|| WstfError::Corrupt(format!("unknown record kind {}", byte)))?
+    } else {
+        fmt.record_kind
+    };
+
+    let meta = BatchMetadata {
+        ref_ts,
+        ref_seq,
+        count,
+        offset,
+        checksum,
+        compression: fmt.compression,
+        compressed_len,
+        encoding,
+        encoded_len,
+        symbol_id,
+        kind_byte: fmt.kind_byte,
+        scale: fmt.scale,
+        time_unit: fmt.time_unit,
+        record_kind,
+    };
+    meta.check_lens()?;
+    Ok(meta)
+}
+
+/// Reads the next batch reference, or `None` at the end of the data: the end of the file or
+/// the index marker at `index_offset`. Without an index, any index marker ends the batches, so
+/// the stale index of an interrupted append is not mistaken for corruption.
+fn read_next_batch_meta<R: Read + Seek>(
+    rdr: &mut R,
+    fmt: &BatchFormat,
+    index_offset: Option<u64>,
+) -> Result<Option<BatchMetadata>, WstfError> {
+    let offset = rdr.stream_position()?;
+    let marker = match rdr.read_u8() {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match marker {
+        0x1 => Ok(Some(read_batch_meta(rdr, fmt, offset)?)),
+        byte if byte == INDEX_MARKER && index_offset.is_none_or(|index| index == offset) => {
+            rdr.seek(SeekFrom::Start(offset))?;
+            Ok(None)
+        }
+        byte => Err(WstfError::Corrupt(format!(
+            "expected a batch at offset {}, found byte {:#04x}",
+            offset, byte
+        ))),
+    }
+}
+
+/// Fails when a full scan of the batches found a different number of rows than the header.
+pub(crate) fn check_nums(header: &Header, nums: u64) -> Result<(), WstfError> {
+    if nums != header.nums {
+        return Err(WstfError::Corrupt(format!(
+            "batches hold {} rows, the header counts {}",
+            nums, header.nums
+        )));
+    }
+    Ok(())
+}
+
+/// Reads through `take`, so a payload running past the end of a truncated file fails
+/// without allocating more than the bytes that are left.
+fn read_batch_payload(rdr: &mut dyn Read, meta: &BatchMetadata) -> Result<Vec<u8>, WstfError> {
+    let len = meta.payload_len();
+    let mut payload = Vec::with_capacity(len.min(PAYLOAD_PREALLOC));
+    let read = rdr.take(len as u64).read_to_end(&mut payload);
+    if read.ok() != Some(len) {
+        return Err(WstfError::Truncated {
+            offset: meta.offset,
+        });
+    }
+    Ok(payload)
+}
+
+pub(crate) fn batch_rows<'a>(
+    meta: &BatchMetadata,
+    payload: &'a [u8],
+) -> Result<Cow<'a, [u8]>, WstfError> {
+    if let Some(expected) = meta.checksum {
+        let found = batch_checksum(meta, payload);
+        if found != expected {
+            return Err(WstfError::ChecksumMismatch {
+                offset: meta.offset,
+                expected,
+                found,
+            });
+        }
+    }
+
+    let encoded = if meta.compressed_len.is_some() {
+        Cow::Owned(decompress(meta.compression, payload, meta.encoded_len())?)
+    } else {
+        Cow::Borrowed(payload)
+    };
+    let rows = decode_payload(meta.encoding, encoded, meta.count, meta.time_unit)?;
+    if rows.len() != meta.raw_len() {
+        return Err(WstfError::Corrupt(format!(
+            "batch at offset {} does not hold {} rows",
+            meta.offset, meta.count
+        )));
+    }
+    Ok(rows)
+}
+
+fn read_one_batch_main_for_each<F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut dyn Read,
+    meta: &BatchMetadata,
+    f: &mut F,
+) -> Result<(), WstfError> {
+    let payload = read_batch_payload(rdr, meta)?;
+    for row in batch_rows(meta, &payload)?.chunks_exact(meta.row_len()) {
+        f(&parse_update(row, meta)?);
+    }
+    Ok(())
+}
+
+fn read_one_batch_main(rdr: &mut dyn Read, meta: &BatchMetadata) -> Result<Vec<Update>, WstfError> {
+    let mut v: Vec<Update> = Vec::with_capacity(meta.count as usize);
+    read_one_batch_main_for_each(rdr, meta, &mut |up| v.push(*up))?;
+    Ok(v)
+}
+
+pub(crate) fn parse_update(row: &[u8], meta: &BatchMetadata) -> Result<Update, WstfError> {
+    if meta.record_kind != RecordKind::Update {
+        return Err(WstfError::Unsupported(format!(
+            "file stores {} records",
+            meta.record_kind
+        )));
+    }
+    if let Some(scale) = meta.scale {
+        return Ok(parse_fixed_update(row, meta)?.to_update(scale));
+    }
+    let (ts, seq, flags, values) = parse_row_prefix(row, meta);
+    let (is_bid, is_trade, flags) = flags.split();
+    let price = BigEndian::read_f32(&values[0..4]);
+    let size = BigEndian::read_f32(&values[4..8]);
+    Ok(Update {
+        ts,
+        seq,
+        is_trade,
+        is_bid,
+        flags,
+        price,
+        size,
+    })
+}
+
+pub(crate) fn parse_fixed_update(
+    row: &[u8],
+    meta: &BatchMetadata,
+) -> Result<FixedUpdate, WstfError> {
+    let (ts, seq, flags, values) = parse_row_prefix(row, meta);
+    let (is_bid, is_trade, flags) = flags.split();
+    Ok(FixedUpdate {
+        ts,
+        seq,
+        is_trade,
+        is_bid,
+        flags,
+        price: BigEndian::read_i64(&values[0..8]),
+        size: BigEndian::read_i64(&values[8..16]),
+    })
+}
+
+pub(crate) fn parse_order(row: &[u8], meta: &BatchMetadata) -> Result<OrderUpdate, WstfError> {
+    let (ts, seq, flags, values) = parse_row_prefix(row, meta);
+    OrderUpdate::from_values(ts, seq, flags.contains(Flags::FLAG_IS_BID), values)
+}
+
+pub(crate) fn parse_event(row: &[u8], meta: &BatchMetadata) -> Result<MarketEvent, WstfError> {
+    let (ts, seq, flags, values) = parse_row_prefix(row, meta);
+    MarketEvent::from_values(ts, seq, flags.contains(Flags::FLAG_IS_BID), values)
+}
+
+fn parse_row_prefix<'a>(row: &'a [u8], meta: &BatchMetadata) -> (u64, u32, Flags, &'a [u8]) {
+    let d = meta.time_unit.delta_len();
+    let ts = BigEndian::read_uint(&row[..d], d) + meta.ref_ts;
+    let seq = u32::from(row[d]) + meta.ref_seq;
+    (ts, seq, Flags::from_byte(row[d + 1]), &row[d + 2..])
+}
+
+fn read_first_batch<T: BufRead + Seek>(rdr: &mut T) -> Result<Vec<Update>, WstfError> {
+    let header = read_header(rdr)?;
+    rdr.seek(SeekFrom::Start(header.main_offset()))?;
+    let mut v = vec![];
+    let fmt = header.batch_format();
+    read_next_batch_for_each(
+        rdr,
+        &fmt,
+        header.index_offset,
+        BatchFilter::All,
+        &mut |up| v.push(*up),
+    )?;
+    Ok(v)
+}
+
+fn read_first<T: BufRead + Seek>(mut rdr: &mut T) -> Result<Update, WstfError> {
+    let batch = read_first_batch(&mut rdr)?;
+    batch
+        .first()
+        .copied()
+        .ok_or_else(|| WstfError::Corrupt("file contains no batches".to_owned()))
+}
+
+pub fn get_size(fname: &str) -> Result<u64, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    read_len(&mut rdr)
+}
+
+pub fn read_meta_from_buf<T: BufRead + Seek>(mut rdr: &mut T) -> Result<Metadata, WstfError> {
+    let header = read_header(&mut rdr)?;
+    let min_ts = match header.min_ts {
+        Some(min_ts) => min_ts,
+        None if header.nums > 0 => read_min_ts(&mut rdr)?,
+        None => header.max_ts,
+    };
+
+    Ok(Metadata {
+        symbol: header.symbol,
+        symbols: header.symbols,
+        instrument: header.instrument,
+        time_unit: header.time_unit,
+        nums: header.nums,
+        max_ts: header.max_ts,
+        min_ts,
+    })
+}
+
+pub fn read_meta(fname: &str) -> Result<Metadata, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    read_meta_from_buf(&mut rdr)
+}
+
+pub struct WSTFBufReader {
+    pub rdr: BufReader<File>,
+    batch_size: u32,
+    fmt: BatchFormat,
+    index_offset: Option<u64>,
+    failed: bool,
+}
+
+impl WSTFBufReader {
+    pub fn new(fname: &str, batch_size: u32) -> Result<Self, WstfError> {
+        let mut rdr = file_reader(fname)?;
+        let header = read_header(&mut rdr)?;
+        rdr.seek(SeekFrom::Start(header.main_offset()))?;
+        Ok(WSTFBufReader {
+            rdr,
+            batch_size,
+            fmt: header.batch_format(),
+            index_offset: header.index_offset,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for WSTFBufReader {
+    type Item = Result<Vec<Update>, WstfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match read_n_batches(&mut self.rdr, &self.fmt, self.index_offset, self.batch_size) {
+            Ok(v) if v.is_empty() => None,
+            Ok(v) => Some(Ok(v)),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Iterates batches from the end of the file, yielding each batch newest update first.
+pub struct ReverseBatchReader<T: Read + Seek> {
+    rdr: T,
+    fmt: BatchFormat,
+    entries: Vec<BatchIndexEntry>,
+    failed: bool,
+}
+
+impl ReverseBatchReader<BufReader<File>> {
+    pub fn open(fname: &str) -> Result<Self, WstfError> {
+        ReverseBatchReader::new(file_reader(fname)?)
+    }
+}
+
+impl<T: Read + Seek> ReverseBatchReader<T> {
+    pub fn new(mut rdr: T) -> Result<Self, WstfError> {
+        let header = read_header(&mut rdr)?;
+        let entries = read_batch_index(&mut rdr, &header)?;
+        Ok(ReverseBatchReader {
+            rdr,
+            fmt: header.batch_format(),
+            entries,
+            failed: false,
+        })
+    }
+
+    /// Skips the trailing batches that can only hold updates at or after `ts`.
+    pub fn before(mut self, ts: u64) -> Self {
+        let i = self.entries.partition_point(|entry| entry.ref_ts < ts);
+        self.entries.truncate(i);
+        self
+    }
+
+    fn read_batch(&mut self, entry: BatchIndexEntry) -> Result<Vec<Update>, WstfError> {
+        self.rdr.seek(SeekFrom::Start(entry.offset))?;
+        let meta = read_next_batch_meta(&mut self.rdr, &self.fmt, None)?
+            .ok_or_else(|| WstfError::Corrupt(format!("no batch at offset {}", entry.offset)))?;
+        if meta.record_kind == RecordKind::Event {
+            return Ok(vec![]);
+        }
+        let mut v = read_one_batch_main(&mut self.rdr, &meta)?;
+        v.reverse();
+        Ok(v)
+    }
+}
+
+impl<T: Read + Seek> Iterator for ReverseBatchReader<T> {
+    type Item = Result<Vec<Update>, WstfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.entries.pop()?;
+        let batch = self.read_batch(entry);
+        self.failed = batch.is_err();
+        Some(batch)
+    }
+}
+
+pub fn last_n_before(fname: &str, ts: u64, n: usize) -> Result<Vec<Update>, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    read_last_n_before(&mut rdr, ts, n)
+}
+
+pub fn read_last_n_before<T: Read + Seek>(
+    rdr: &mut T,
+    ts: u64,
+    n: usize,
+) -> Result<Vec<Update>, WstfError> {
+    let mut v = Vec::with_capacity(n);
+    if n == 0 {
+        return Ok(v);
+    }
+    for batch in ReverseBatchReader::new(rdr)?.before(ts) {
+        let remaining = n - v.len();
+        v.extend(batch?.into_iter().filter(|up| up.ts < ts).take(remaining));
+        if v.len() == n {
+            break;
+        }
+    }
+    v.reverse();
+    Ok(v)
+}
+
+fn read_next_batch_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut T,
+    fmt: &BatchFormat,
+    index_offset: Option<u64>,
+    filter: BatchFilter,
+    f: &mut F,
+) -> Result<bool, WstfError> {
+    while let Some(meta) = read_next_batch_meta(rdr, fmt, index_offset)? {
+        if filter.matches(&meta) {
+            read_one_batch_main_for_each(rdr, &meta, f)?;
+            return Ok(true);
+        }
+        skip_batch_payload(rdr, &meta)?;
+    }
+    Ok(false)
+}
+
+fn skip_batch_payload<T: Seek>(rdr: &mut T, meta: &BatchMetadata) -> Result<(), WstfError> {
+    rdr.seek(SeekFrom::Current(meta.payload_len() as i64))?;
+    Ok(())
+}
+
+fn read_n_batches<T: BufRead + Seek>(
+    rdr: &mut T,
+    fmt: &BatchFormat,
+    index_offset: Option<u64>,
+    num_rows: u32,
+) -> Result<Vec<Update>, WstfError> {
+    let mut v: Vec<Update> = vec![];
+    read_n_batches_for_each(
+        rdr,
+        fmt,
+        index_offset,
+        BatchFilter::All,
+        num_rows,
+        &mut |up| v.push(*up),
+    )?;
+    Ok(v)
+}
+
+fn read_all<T: BufRead + Seek>(rdr: &mut T, header: &Header) -> Result<Vec<Update>, WstfError> {
+    // `nums` is checked against the batches once they are read, so it only bounds the guess.
+    let mut v: Vec<Update> = Vec::with_capacity(header.nums.min(PAYLOAD_PREALLOC as u64) as usize);
+    read_all_for_each(rdr, header, BatchFilter::All, &mut |up| v.push(*up))?;
+    Ok(v)
+}
+
+/// Reads every batch from the current position, returning the number of updates walked.
+fn read_batches_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut T,
+    fmt: &BatchFormat,
+    index_offset: Option<u64>,
+    filter: BatchFilter,
+    f: &mut F,
+) -> Result<u64, WstfError> {
+    let mut nums = 0;
+    while let Some(meta) = read_next_batch_meta(rdr, fmt, index_offset)? {
+        if meta.record_kind != RecordKind::Event {
+            nums += u64::from(meta.count);
+        }
+        if filter.matches(&meta) {
+            read_one_batch_main_for_each(rdr, &meta, f)?;
+        } else {
+            skip_batch_payload(rdr, &meta)?;
+        }
+    }
+    Ok(nums)
+}
+
+/// Reads every batch of the file and checks their rows against the header.
+fn read_all_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut T,
+    header: &Header,
+    filter: BatchFilter,
+    f: &mut F,
+) -> Result<(), WstfError> {
+    rdr.seek(SeekFrom::Start(header.main_offset()))?;
+    let nums = read_batches_for_each(rdr, &header.batch_format(), header.index_offset, filter, f)?;
+    check_nums(header, nums)
+}
+
+fn read_n_batches_for_each<T: Read + Seek, F: for<'a> FnMut(&'a Update)>(
+    rdr: &mut T,
+    fmt: &BatchFormat,
+    index_offset: Option<u64>,
+    filter: BatchFilter,
+    num_rows: u32,
+    f: &mut F,
+) -> Result<(), WstfError> {
+    let mut count = 0;
+    if num_rows == 0 {
+        return Ok(());
+    }
+    while read_next_batch_for_each(rdr, fmt, index_offset, filter, f)? {
+        count += 1;
+        if count > num_rows {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub fn decode(fname: &str, num_rows: Option<u32>) -> Result<Vec<Update>, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    let header = read_header(&mut rdr)?;
+    let fmt = header.batch_format();
+    rdr.seek(SeekFrom::Start(header.main_offset()))?;
+
+    match num_rows {
+        Some(num_rows) => read_n_batches(&mut rdr, &fmt, header.index_offset, num_rows),
+        None => read_all(&mut rdr, &header),
+    }
+}
+
+pub fn decode_for_each<F: for<'a> FnMut(&'a Update)>(
+    fname: &str,
+    num_rows: Option<u32>,
+    f: &mut F,
+) -> Result<(), WstfError> {
+    decode_for_each_with_symbol(fname, num_rows, None, f)
+}
+
+pub fn decode_for_each_with_symbol<F: for<'a> FnMut(&'a Update)>(
+    fname: &str,
+    num_rows: Option<u32>,
+    symbol: Option<&str>,
+    f: &mut F,
+) -> Result<(), WstfError> {
+    let mut rdr = file_reader(fname)?;
+    let header = read_header(&mut rdr)?;
+    let fmt = header.batch_format();
+    let filter = match BatchFilter::new(&header, symbol) {
+        Some(filter) => filter,
+        None => return Ok(()),
+    };
+    rdr.seek(SeekFrom::Start(header.main_offset()))?;
+
+    match num_rows {
+        Some(num_rows) => {
+            read_n_batches_for_each(&mut rdr, &fmt, header.index_offset, filter, num_rows, f)
+        }
+        None => read_all_for_each(&mut rdr, &header, filter, f),
+    }
+}
+
+pub fn decode_buffer(buf: &mut dyn Read) -> Result<Vec<Update>, WstfError> {
+    let mut bytes = vec![];
+    buf.read_to_end(&mut bytes)?;
+    let mut v = vec![];
+    for batch in Batches::new(&bytes, 0, BatchFormat::default()) {
+        for up in batch?.updates()? {
+            v.push(up?);
+        }
+    }
+    Ok(v)
+}
+
+/// Reads the exact tick values of a fixed-point file.
+pub fn decode_fixed(fname: &str) -> Result<Vec<FixedUpdate>, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    let header = read_header(&mut rdr)?;
+    if header.scale.is_none() || header.record_kind != RecordKind::Update {
+        return Err(WstfError::Unsupported(
+            "file does not store fixed-point rows".to_owned(),
+        ));
+    }
+    rows_in_range(
+        &mut rdr,
+        &header,
+        0,
+        u64::MAX,
+        RecordKind::Update,
+        parse_fixed_update,
+    )
+}
+
+pub fn decode_orders(fname: &str) -> Result<Vec<OrderUpdate>, WstfError> {
+    get_orders_in_range(fname, 0, u64::MAX)
+}
+
+pub fn get_orders_in_range(
+    fname: &str,
+    min_ts: u64,
+    max_ts: u64,
+) -> Result<Vec<OrderUpdate>, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    let header = read_header(&mut rdr)?;
+    if header.record_kind != RecordKind::Order {
+        return Err(WstfError::Unsupported(format!(
+            "file stores {} records",
+            header.record_kind
+        )));
+    }
+    rows_in_range(
+        &mut rdr,
+        &header,
+        min_ts,
+        max_ts,
+        RecordKind::Order,
+        parse_order,
+    )
+}
+
+pub fn decode_events(fname: &str) -> Result<Vec<MarketEvent>, WstfError> {
+    get_events_in_range(fname, 0, u64::MAX, None)
+}
+
+pub fn get_events_in_range(
+    fname: &str,
+    min_ts: u64,
+    max_ts: u64,
+    kind: Option<EventKind>,
+) -> Result<Vec<MarketEvent>, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    range_events(&mut rdr, min_ts, max_ts, kind)
+}
+
+/// Reads the auxiliary events in the range, keeping only those of `kind` when it is set.
+pub fn range_events<T: Read + Seek>(
+    rdr: &mut T,
+    min_ts: u64,
+    max_ts: u64,
+    kind: Option<EventKind>,
+) -> Result<Vec<MarketEvent>, WstfError> {
+    let header = read_header(rdr)?;
+    if !header.has_events() {
+        return Ok(vec![]);
+    }
+    let mut events = rows_in_range(rdr, &header, min_ts, max_ts, RecordKind::Event, parse_event)?;
+    if let Some(kind) = kind {
+        events.retain(|event| event.kind == kind);
+    }
+    Ok(events)
+}
+
+fn rows_in_range<T: Read + Seek, R: Record>(
+    rdr: &mut T,
+    header: &Header,
+    min_ts: u64,
+    max_ts: u64,
+    record_kind: RecordKind,
+    parse: fn(&[u8], &BatchMetadata) -> Result<R, WstfError>,
+) -> Result<Vec<R>, WstfError> {
+    let mut v = vec![];
+    if min_ts > max_ts {
+        return Ok(v);
+    }
+    let first = match first_batch_for(rdr, header, min_ts)? {
+        Some(entry) => entry,
+        None => return Ok(v),
+    };
+
+    let fmt = header.batch_format();
+    rdr.seek(SeekFrom::Start(first.offset))?;
+    while let Some(meta) = read_next_batch_meta(rdr, &fmt, header.index_offset)? {
+        if meta.ref_ts > max_ts {
+            break;
+        }
+        if meta.record_kind != record_kind {
+            skip_batch_payload(rdr, &meta)?;
+            continue;
+        }
+        let payload = read_batch_payload(rdr, &meta)?;
+        for row in batch_rows(&meta, &payload)?.chunks_exact(meta.row_len()) {
+            let row = parse(row, &meta)?;
+            if row.ts() >= min_ts && row.ts() <= max_ts {
+                v.push(row);
+            }
+        }
+    }
+    Ok(v)
+}
+
+pub fn append(fname: &str, ups: &[Update]) -> Result<(), WstfError> {
+    append_with_policy(fname, ups, AppendPolicy::default())
+}
+
+pub fn append_with_policy(
+    fname: &str,
+    ups: &[Update],
+    policy: AppendPolicy,
+) -> Result<(), WstfError> {
+    let mut rdr = file_reader(fname)?;
+    let mut header = read_header(&mut rdr)?;
+    if header.is_container() {
+        return Err(WstfError::Unsupported(
+            "appending to container files".to_owned(),
+        ));
+    }
+    if header.record_kind != RecordKind::Update {
+        return Err(WstfError::Unsupported(format!(
+            "appending updates to a file of {} records",
+            header.record_kind
+        )));
+    }
+    let (entries, end) = batches_for_append(&mut rdr, &mut header)?;
+    let old_max_ts = header.max_ts;
+    let has_updates = header.nums > 0;
+    let is_late = move |up: &Update| has_updates && up.ts <= old_max_ts;
+
+    match policy {
+        AppendPolicy::Strict => {
+            let late = ups.iter().find(|up| is_late(up));
+            if let Some(up) = late.or_else(|| first_unsorted(ups.iter())) {
+                return Err(WstfError::OutOfOrder {
+                    ts: up.ts,
+                    seq: up.seq,
+                });
+            }
+            append_sorted(fname, header, entries, end, ups.iter())
+        }
+        AppendPolicy::DropLate => {
+            let ups = ups.iter().filter(|up| !is_late(up));
+            if let Some(up) = first_unsorted(ups.clone()) {
+                return Err(WstfError::OutOfOrder {
+                    ts: up.ts,
+                    seq: up.seq,
+                });
+            }
+            append_sorted(fname, header, entries, end, ups)
+        }
+        AppendPolicy::Merge if header.scale.is_some() => Err(WstfError::Unsupported(
+            "merging into fixed-point files".to_owned(),
+        )),
+        AppendPolicy::Merge if header.has_events() => Err(WstfError::Unsupported(
+            "merging into files with events".to_owned(),
+        )),
+        AppendPolicy::Merge if header.version == Version::V1 => Err(WstfError::Unsupported(
+            "merging into v1 files, which have no index to commit".to_owned(),
+        )),
+        AppendPolicy::Merge => {
+            let mut ups = ups.to_vec();
+            ups.sort();
+            match ups.first() {
+                Some(first) if is_late(first) => {
+                    merge_tail(fname, &mut rdr, header, entries, end, ups)
+                }
+                _ => append_sorted(fname, header, entries, end, ups.iter()),
+            }
+        }
+    }
+}
+
+/// Returns the first update that sorts before its predecessor.
+fn first_unsorted<'a, I: Iterator<Item = &'a Update> + Clone>(ups: I) -> Option<&'a Update> {
+    ups.clone()
+        .zip(ups.skip(1))
+        .find(|(a, b)| b < a)
+        .map(|(_a, b)| b)
+}
+
+/// Returns the index entries of the batches and the offset where they end.
+///
+/// An interrupted append leaves the index invalid, so the batches are scanned instead, and
+/// `header` is updated with the complete batches the append wrote, as `recover` would keep
+/// them. A batch that cannot be read fails the append.
+fn batches_for_append(
+    rdr: &mut BufReader<File>,
+    header: &mut Header,
+) -> Result<(Vec<BatchIndexEntry>, u64), WstfError> {
+    if let Some(index_offset) = header.index_offset {
+        return Ok((read_index(rdr, index_offset)?, index_offset));
+    }
+    if header.version == Version::V1 {
+        return Ok((vec![], rdr.seek(SeekFrom::End(0))?));
+    }
+    let scan = scan_chain(rdr, header)?;
+    if let Some((err, _runs_to_eof)) = scan.failure {
+        return Err(unrecoverable(err, scan.end));
+    }
+    header.nums = scan.nums;
+    header.events = scan.events;
+    header.max_ts = scan.max_ts;
+    header.min_ts = scan.min_ts;
+    Ok((scan.entries, scan.end))
+}
+
+#[cfg_attr(feature = "count_alloc", count_alloc)]
+fn append_sorted<'a, I: DoubleEndedIterator<Item = &'a Update> + Clone>(
+    fname: &str,
+    header: Header,
+    entries: Vec<BatchIndexEntry>,
+    end: u64,
+    ups: I,
+) -> Result<(), WstfError> {
+    let (new_min_ts, new_max_ts) = match (ups.clone().next(), ups.clone().next_back()) {
+        (Some(first), Some(last)) => (first.ts, last.ts),
+        _ => return Ok(()),
+    };
+
+    let cur_len = header.nums;
+
+    let new_len = cur_len + ups.clone().count() as u64;
+
+    let (mut header, mut entries) = (header, entries);
+    let mut wtr = file_writer(fname, false)?;
+    invalidate_index(&mut wtr, &header)?;
+    // Drop the old index, so an interrupted append leaves whole batches followed by at most
+    // one partial batch that runs to the end of the file.
+    wtr.get_ref().set_len(end)?;
+    sync(&mut wtr)?;
+
+    let pos = wtr.seek(SeekFrom::Start(end))?;
+    for mut entry in write_batches_with_format(&mut wtr, &header.batch_format(), ups.peekable())? {
+        entry.offset += pos;
+        entries.push(entry);
+    }
+    header.index_offset = write_trailing_index(&mut wtr, header.version, &entries)?;
+    sync(&mut wtr)?;
+
+    header.nums = new_len;
+    header.max_ts = new_max_ts;
+    if cur_len == 0 && header.version == Version::V2 {
+        header.min_ts = Some(new_min_ts);
+    }
+    commit_header(&mut wtr, &header)
+}
+
+fn merge_updates(existing: Vec<Update>, late: Vec<Update>) -> Vec<Update> {
+    let mut merged: Vec<Update> = Vec::with_capacity(existing.len() + late.len());
+    let mut existing = existing.into_iter().peekable();
+    for up in late {
+        while let Some(next) = existing.next_if(|next| *next <= up) {
+            merged.push(next);
+        }
+        let is_duplicate = merged
+            .iter()
+            .rev()
+            .take_while(|prev| prev.ts == up.ts)
+            .any(|prev| *prev == up);
+        if !is_duplicate {
+            merged.push(up);
+        }
+    }
+    merged.extend(existing);
+    merged
+}
+
+/// Rewrites the batches from the one before the first late update.
+///
+/// The merged batches are staged past the end of the file and committed with their index
+/// before they are moved over the old tail, so an interrupted merge leaves either the old
+/// batches or a committed index of the staged ones, which `recover` compacts.
+fn merge_tail(
+    fname: &str,
+    rdr: &mut BufReader<File>,
+    header: Header,
+    mut entries: Vec<BatchIndexEntry>,
+    end: u64,
+    late: Vec<Update>,
+) -> Result<(), WstfError> {
+    let fmt = header.batch_format();
+    let start = entries
+        .partition_point(|entry| entry.ref_ts < late[0].ts)
+        .saturating_sub(1);
+    let tail_offset = match entries.get(start) {
+        Some(entry) => entry.offset,
+        None => return append_sorted(fname, header, entries, end, late.iter()),
+    };
+    entries.truncate(start);
+
+    let mut tail = vec![];
+    rdr.seek(SeekFrom::Start(tail_offset))?;
+    read_batches_for_each(
+        rdr,
+        &fmt,
+        header.index_offset,
+        BatchFilter::All,
+        &mut |up| tail.push(*up),
+    )?;
+    let tail_len = tail.len() as u64;
+    let merged = merge_updates(tail, late);
+
+    let mut staged = vec![];
+    let staged_entries = write_batches_with_format(&mut staged, &fmt, merged.iter().peekable())?;
+    let compacted_len = staged.len() as u64 + index_len(entries.len() + staged_entries.len());
+
+    let mut header = header;
+    let mut wtr = file_writer(fname, false)?;
+    // Stage far enough out that compacting never overwrites the staged batches or their index.
+    let staged_offset = wtr.seek(SeekFrom::End(0))?.max(tail_offset + compacted_len);
+    wtr.seek(SeekFrom::Start(staged_offset))?;
+    wtr.write_all(&staged)?;
+    let first_staged = entries.len();
+    for mut entry in staged_entries {
+        entry.offset += staged_offset;
+        entries.push(entry);
+    }
+    header.index_offset = write_trailing_index(&mut wtr, header.version, &entries)?;
+    sync(&mut wtr)?;
+
+    header.nums = header.nums - tail_len + merged.len() as u64;
+    header.max_ts = get_max_ts_sorted(&merged);
+    if let (Some(min_ts), Some(first)) = (header.min_ts, merged.first()) {
+        header.min_ts = Some(min_ts.min(first.ts));
+    }
+    commit_header(&mut wtr, &header)?;
+
+    compact_staged(
+        &mut wtr,
+        &mut header,
+        &mut entries,
+        first_staged,
+        tail_offset,
+        &staged,
+    )
+}
+
+/// Moves the committed batches of `entries[first_staged..]`, whose bytes are `staged`, to
+/// `offset`, commits an index for the new layout and truncates what follows it.
+fn compact_staged(
+    wtr: &mut BufWriter<File>,
+    header: &mut Header,
+    entries: &mut [BatchIndexEntry],
+    first_staged: usize,
+    offset: u64,
+    staged: &[u8],
+) -> Result<(), WstfError> {
+    let shift = entries[first_staged].offset - offset;
+    for entry in &mut entries[first_staged..] {
+        entry.offset -= shift;
+    }
+    wtr.seek(SeekFrom::Start(offset))?;
+    wtr.write_all(staged)?;
+    header.index_offset = write_trailing_index(wtr, header.version, entries)?;
+    let end = wtr.stream_position()?;
+    sync(wtr)?;
+    commit_header(wtr, header)?;
+    wtr.get_ref().set_len(end)?;
+    Ok(())
+}
+
+fn sync(wtr: &mut BufWriter<File>) -> Result<(), WstfError> {
+    wtr.flush()?;
+    wtr.get_ref().sync_data()?;
+    Ok(())
+}
+
+fn invalidate_index(wtr: &mut BufWriter<File>, header: &Header) -> Result<(), WstfError> {
+    if header.index_offset.is_some() {
+        write_index_offset(wtr, 0)?;
+        sync(wtr)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_trailing_index<T: Write + Seek>(
+    wtr: &mut T,
+    version: Version,
+    entries: &[BatchIndexEntry],
+) -> Result<Option<u64>, WstfError> {
+    match version {
+        Version::V1 => Ok(None),
+        Version::V2 => {
+            let index_offset = wtr.stream_position()?;
+            write_index(wtr, entries)?;
+            Ok(Some(index_offset))
+        }
+    }
+}
+
+fn commit_header(wtr: &mut BufWriter<File>, header: &Header) -> Result<(), WstfError> {
+    write_len(wtr, header.version, header.nums)?;
+    write_max_ts(wtr, header.version, header.max_ts)?;
+    if header.version == Version::V2 {
+        write_min_ts(wtr, header.min_ts.unwrap_or(0))?;
+        write_index_offset(wtr, header.index_offset.unwrap_or(0))?;
+    }
+    if header.has_events() {
+        write_events_len(wtr, header)?;
+    }
+    sync(wtr)
+}
+
+/// The batches found by walking the file from its first batch.
+struct ChainScan {
+    entries: Vec<BatchIndexEntry>,
+    nums: u64,
+    events: u64,
+    min_ts: Option<u64>,
+    max_ts: u64,
+    /// The offset after the last readable batch.
+    end: u64,
+    /// Why the batch at `end` could not be read, and whether it runs to the end of the file.
+    failure: Option<(WstfError, bool)>,
+}
+
+fn scan_chain(rdr: &mut BufReader<File>, header: &Header) -> Result<ChainScan, WstfError> {
+    let fmt = header.batch_format();
+    let file_len = rdr.seek(SeekFrom::End(0))?;
+    let mut scan = ChainScan {
+        entries: vec![],
+        nums: 0,
+        events: 0,
+        min_ts: None,
+        max_ts: 0,
+        end: rdr.seek(SeekFrom::Start(header.main_offset()))?,
+        failure: None,
+    };
+    loop {
+        let (meta, rows) = match read_verified_batch(rdr, &fmt) {
+            Ok(Some(batch)) => batch,
+            Ok(None) => break,
+            Err(err @ WstfError::Io(_)) => return Err(err),
+            Err(err) => {
+                let runs_to_eof = match err {
+                    WstfError::Truncated { .. } => true,
+                    _ => batch_end(rdr, &fmt, scan.end).is_some_and(|end| end >= file_len),
+                };
+                scan.failure = Some((err, runs_to_eof));
+                break;
+            }
+        };
+
+        scan.entries.push(BatchIndexEntry {
+            ref_ts: meta.ref_ts,
+            offset: meta.offset,
+            count: meta.count,
+        });
+        match meta.record_kind {
+            RecordKind::Event => scan.events += u64::from(meta.count),
+            _ => scan.nums += u64::from(meta.count),
+        }
+        // Only the row timestamps are needed, which works for every record kind.
+        for row in rows.chunks_exact(meta.row_len()) {
+            let (row_ts, ..) = parse_row_prefix(row, &meta);
+            scan.min_ts = Some(scan.min_ts.map_or(row_ts, |ts| ts.min(row_ts)));
+            scan.max_ts = scan.max_ts.max(row_ts);
+        }
+        scan.end = rdr.stream_position()?;
+    }
+    Ok(scan)
+}
+
+/// Reads the next batch and checks its payload.
+fn read_verified_batch(
+    rdr: &mut BufReader<File>,
+    fmt: &BatchFormat,
+) -> Result<Option<(BatchMetadata, Vec<u8>)>, WstfError> {
+    let meta = match read_next_batch_meta(rdr, fmt, None)? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+    let payload = read_batch_payload(rdr, &meta)?;
+    let rows = batch_rows(&meta, &payload)?.into_owned();
+    Ok(Some((meta, rows)))
+}
+
+/// The offset after the batch at `offset`, if its reference can be read.
+fn batch_end(rdr: &mut BufReader<File>, fmt: &BatchFormat, offset: u64) -> Option<u64> {
+    rdr.seek(SeekFrom::Start(offset)).ok()?;
+    let meta = read_next_batch_meta(rdr, fmt, None).ok()??;
+    Some(offset + fmt.ref_len() + meta.payload_len() as u64)
+}
+
+/// Names the offset of a batch that cannot be read or recovered.
+fn unrecoverable(err: WstfError, offset: u64) -> WstfError {
+    match err {
+        WstfError::ChecksumMismatch { .. } | WstfError::Truncated { .. } => err,
+        err => WstfError::Corrupt(format!("batch at offset {} is unreadable: {}", offset, err)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub batches: u64,
+    pub nums: u64,
+    pub max_ts: u64,
+    pub truncated_at: Option<u64>,
+}
+
+/// Rebuilds the index and header of a file from its batches, after an interrupted write.
+///
+/// A partial batch at the end of the file is truncated. A batch that cannot be read but is
+/// followed by more data fails with its offset and leaves the file untouched. A merge that
+/// committed its index but was interrupted while compacting is completed instead.
+pub fn recover(fname: &str) -> Result<RecoveryReport, WstfError> {
+    let mut rdr = file_reader(fname)?;
+    let mut header = read_header(&mut rdr)?;
+    if let Some(report) = finish_merge(fname, &mut rdr, &mut header)? {
+        return Ok(report);
+    }
+
+    let scan = scan_chain(&mut rdr, &header)?;
+    let truncated_at = match scan.failure {
+        None => None,
+        Some((_err, true)) => Some(scan.end),
+        Some((err, false)) => return Err(unrecoverable(err, scan.end)),
+    };
+
+    let mut wtr = file_writer(fname, false)?;
+    invalidate_index(&mut wtr, &header)?;
+    wtr.seek(SeekFrom::Start(scan.end))?;
+    header.index_offset = write_trailing_index(&mut wtr, header.version, &scan.entries)?;
+    let file_len = wtr.stream_position()?;
+    sync(&mut wtr)?;
+    wtr.get_ref().set_len(file_len)?;
+
+    header.nums = scan.nums;
+    header.events = scan.events;
+    header.max_ts = scan.max_ts;
+    if header.version == Version::V2 {
+        header.min_ts = scan.min_ts;
+    }
+    commit_header(&mut wtr, &header)?;
+
+    Ok(RecoveryReport {
+        batches: scan.entries.len() as u64,
+        nums: scan.nums,
+        max_ts: scan.max_ts,
+        truncated_at,
+    })
+}
+
+/// Compacts the staged batches of a merge whose index was committed, returning `None` when the
+/// committed index does not describe staged batches that can all be read.
+fn finish_merge(
+    fname: &str,
+    rdr: &mut BufReader<File>,
+    header: &mut Header,
+) -> Result<Option<RecoveryReport>, WstfError> {
+    let index_offset = match header.index_offset {
+        Some(index_offset) => index_offset,
+        None => return Ok(None),
+    };
+    let mut entries = match read_index(rdr, index_offset) {
+        Ok(entries) => entries,
+        Err(_e) => return Ok(None),
+    };
+    let fmt = header.batch_format();
+
+    // The staged batches start at the first entry that does not follow its predecessor.
+    let mut end = header.main_offset();
+    let mut first_staged = None;
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.offset != end {
+            first_staged = Some(i);
+            break;
+        }
+        end = match batch_end(rdr, &fmt, entry.offset) {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+    }
+    let first_staged = match first_staged {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let staged_offset = entries[first_staged].offset;
+    if staged_offset < end + (index_offset - staged_offset) + index_len(entries.len()) {
+        return Ok(None);
+    }
+
+    rdr.seek(SeekFrom::Start(staged_offset))?;
+    for entry in &entries[first_staged..] {
+        match read_verified_batch(rdr, &fmt) {
+            Ok(Some((meta, _rows))) if meta.offset == entry.offset => {}
+            _ => return Ok(None),
+        }
+    }
+    if rdr.stream_position()? != index_offset {
+        return Ok(None);
+    }
+    let mut staged = vec![0u8; (index_offset - staged_offset) as usize];
+    rdr.seek(SeekFrom::Start(staged_offset))?;
+    rdr.read_exact(&mut staged)?;
+
+    let mut wtr = file_writer(fname, false)?;
+    compact_staged(&mut wtr, header, &mut entries, first_staged, end, &staged)?;
+    Ok(Some(RecoveryReport {
+        batches: entries.len() as u64,
+        nums: header.nums,
+        max_ts: header.max_ts,
+        truncated_at: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::io::Cursor;
+
+    static SYMBOL: &str = "BTC_USDT";
     static FNAME: &str = "./internal/mocks/tmp.wstf";
     static FNAME_DATA: &str = "./internal/mocks/data.wstf";
 
-    fn prepare_data_range(n: u64, with_seq: bool) -> Vec<Update> {
-        (1..n)
+    fn prepare_data_range(n: u64, with_seq: bool) -> Vec<Update> {
+        (1..n)
+            .map(|i| Update {
+                ts: i * 1000u64,
+                seq: if with_seq { i as u32 } else { 0 },
+                price: 0f32,
+                size: 0f32,
+                is_bid: false,
+                flags: Flags::FLAG_EMPTY,
+                is_trade: false,
+            })
+            .collect::<Vec<Update>>()
+    }
+
+    #[cfg(test)]
+    fn sample_data() -> Vec<Update> {
+        let mut ts: Vec<Update> = vec![];
+        let t = Update {
+            ts: 100,
+            seq: 113,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 1.14564564645,
+        };
+        let t1 = Update {
+            ts: 101,
+            seq: 113,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 2.14564564645,
+        };
+        let t2 = Update {
+            ts: 1000000,
+            seq: 113,
+            is_trade: true,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 1.123465,
+        };
+        ts.push(t);
+        ts.push(t1);
+        ts.push(t2);
+        ts.sort();
+        ts
+    }
+
+    #[cfg(test)]
+    fn sample_data_one_item() -> Vec<Update> {
+        let mut ts: Vec<Update> = vec![];
+        let t = Update {
+            ts: 100,
+            seq: 113,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 1.14564564645,
+        };
+        ts.push(t);
+        ts.sort();
+        ts
+    }
+
+    #[cfg(test)]
+    fn sample_data_append() -> Vec<Update> {
+        let mut ts: Vec<Update> = vec![];
+        let t2 = Update {
+            ts: 00000002,
+            seq: 113,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 1.14564564645,
+        };
+        let t1 = Update {
+            ts: 20000001,
+            seq: 113,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 1.14564564645,
+        };
+        let t = Update {
+            ts: 20000000,
+            seq: 113,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 5100.01,
+            size: 1.14564564645,
+        };
+        ts.push(t);
+        ts.push(t1);
+        ts.push(t2);
+        ts.sort();
+        ts
+    }
+
+    #[cfg(test)]
+    fn before() -> Vec<Update> {
+        let ts = sample_data();
+        encode(FNAME, SYMBOL, &ts).unwrap();
+        ts
+    }
+
+    #[test]
+    fn should_format_metadata_properly() {
+        let meta = Metadata {
+            symbol: SYMBOL.to_owned(),
+            symbols: vec![],
+            instrument: None,
+            time_unit: TimeUnit::Millis,
+            nums: 1,
+            max_ts: 1,
+            min_ts: 1,
+        };
+
+        assert_eq!(
+            format!(
+                r#"{{"symbol":{},"nums":{},"max_ts":{},"min_ts":{}}}"#,
+                meta.symbol, meta.nums, meta.max_ts, meta.min_ts,
+            ),
+            r#"{"symbol":BTC_USDT,"nums":1,"max_ts":1,"min_ts":1}"#
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn should_encode_decode_one_item() {
+        let ts = sample_data_one_item();
+        encode(FNAME, SYMBOL, &ts).unwrap();
+        let decoded_updates = decode(FNAME, None).unwrap();
+        assert_eq!(decoded_updates, ts);
+    }
+
+    #[test]
+    #[serial]
+    fn should_encode_and_decode_file() {
+        let ts = before();
+        let decoded_updates = decode(FNAME, None).unwrap();
+        assert_eq!(decoded_updates, ts);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_return_correct_updates_within_range() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 10000, 20000, &mut |up| result.push(*up)).unwrap();
+
+        let expected = (10..21)
             .map(|i| Update {
                 ts: i * 1000u64,
-                seq: if with_seq { i as u32 } else { 0 },
+                seq: i as u32,
                 price: 0f32,
                 size: 0f32,
                 is_bid: false,
+                flags: Flags::FLAG_EMPTY,
                 is_trade: false,
             })
-            .collect::<Vec<Update>>()
+            .collect::<Vec<Update>>();
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_return_correct_updates_within_range_without_seq() {
+        let updates = prepare_data_range(50, false);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 10000, 20000, &mut |up| result.push(*up)).unwrap();
+
+        let expected = (10..21)
+            .map(|i| Update {
+                ts: i * 1000u64,
+                seq: 0,
+                price: 0f32,
+                size: 0f32,
+                is_bid: false,
+                flags: Flags::FLAG_EMPTY,
+                is_trade: false,
+            })
+            .collect::<Vec<Update>>();
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_return_all_updates() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 0, 50 * 1000, &mut |up| result.push(*up)).unwrap();
+
+        assert_eq!(result, updates);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_return_all_updates_large() {
+        let updates = prepare_data_range(5000, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 0, 5000 * 1000, &mut |up| result.push(*up)).unwrap();
+
+        assert_eq!(result, updates);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_return_all_updates_without_seq() {
+        let updates = prepare_data_range(50, false);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 0, 50 * 1000, &mut |up| result.push(*up)).unwrap();
+
+        assert_eq!(result, updates);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_return_all_updates_without_seq_large() {
+        let updates = prepare_data_range(5000, false);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 0, 5000 * 1000, &mut |up| result.push(*up)).unwrap();
+
+        assert_eq!(result, updates);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_handle_empty_range() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 50000, 60000, &mut |up| result.push(*up)).unwrap();
+
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_handle_single_update_in_range() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 1000, 1000, &mut |up| result.push(*up)).unwrap();
+
+        let expected = vec![Update {
+            seq: 1,
+            ts: 1000,
+            price: 0f32,
+            size: 0f32,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            is_trade: false,
+        }];
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_handle_single_update_out_of_range() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 100000, 200000, &mut |up| result.push(*up)).unwrap();
+
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_handle_min_ts_greater_than_max_ts() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 20000, 10000, &mut |up| result.push(*up)).unwrap();
+
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn range_for_each_should_handle_min_ts_greater_than_max_ts_and_empty_range() {
+        let updates = prepare_data_range(50, true);
+        encode(FNAME, "BTC_USDT", &updates).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let mut result = vec![];
+        range_for_each(&mut rdr, 20000, 10000, &mut |up| result.push(*up)).unwrap();
+
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn should_return_correct_range_real() {
+        let mut rdr = file_reader(FNAME_DATA).unwrap();
+
+        let start = 1_510_168_156 * 1000;
+        let end = 1_510_171_756 * 1000;
+
+        let ups = range(&mut rdr, start, end).unwrap();
+        println!("{}", ups.len());
+        assert_eq!(ups.len(), 56564);
+
+        for up in ups.iter() {
+            assert!(up.ts >= start && up.ts <= end);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn should_return_correct_symbol() {
+        before();
+        let mut rdr = file_reader(FNAME).unwrap();
+        let sym = read_header(&mut rdr).unwrap().symbol;
+        assert_eq!(sym, SYMBOL);
+    }
+
+    #[test]
+    #[serial]
+    fn should_return_first_record() {
+        let vs = before();
+        let mut rdr = file_reader(FNAME).unwrap();
+        let v = read_first(&mut rdr).unwrap();
+        assert_eq!(vs[0], v);
+    }
+
+    #[test]
+    #[serial]
+    fn should_return_correct_num_of_items() {
+        let vs = before();
+        let mut rdr = file_reader(FNAME).unwrap();
+        let len = read_len(&mut rdr).unwrap();
+        assert_eq!(vs.len() as u64, len);
+    }
+
+    #[test]
+    #[serial]
+    fn should_return_max_ts() {
+        let vs = before();
+        let mut rdr = file_reader(FNAME).unwrap();
+        let max_ts = read_header(&mut rdr).unwrap().max_ts;
+        assert_eq!(max_ts, get_max_ts_sorted(&vs));
+    }
+
+    #[test]
+    #[serial]
+    fn should_append_filtered_data() {
+        should_encode_and_decode_file();
+
+        println!("----DONE----");
+
+        let old_data = sample_data();
+        let old_max_ts = get_max_ts_sorted(&old_data);
+        let append_data: Vec<Update> = sample_data_append()
+            .into_iter()
+            .filter(|up| up.ts >= old_max_ts)
+            .collect();
+        let new_size = append_data.len() + old_data.len();
+
+        append(FNAME, &append_data).unwrap();
+
+        println!("----APPENDED----");
+
+        let mut rdr = file_reader(FNAME).unwrap();
+
+        let max_ts = read_header(&mut rdr).unwrap().max_ts;
+        assert_eq!(max_ts, get_max_ts_sorted(&append_data));
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let len = read_len(&mut rdr).unwrap();
+        assert_eq!(new_size as u64, len);
+
+        let mut all_the_data = sample_data();
+        all_the_data.extend(append_data);
+        all_the_data.sort();
+        let decoded = decode(&FNAME, None).unwrap();
+        assert_eq!(all_the_data, decoded);
+    }
+
+    #[test]
+    #[serial]
+    fn strict_append_should_reject_late_updates() {
+        let updates = prepare_data_range(100, true);
+        encode(FNAME, SYMBOL, &updates[..50]).unwrap();
+        let before = std::fs::read(FNAME).unwrap();
+
+        let res = append_with_policy(FNAME, &updates[40..], AppendPolicy::Strict);
+        assert!(matches!(res, Err(WstfError::OutOfOrder { ts: 41000, .. })));
+        assert_eq!(std::fs::read(FNAME).unwrap(), before);
+
+        append_with_policy(FNAME, &updates[50..], AppendPolicy::Strict).unwrap();
+        assert_eq!(decode(FNAME, None).unwrap(), updates);
     }
 
-    #[cfg(test)]
-    fn sample_data() -> Vec<Update> {
-        let mut ts: Vec<Update> = vec![];
-        let t = Update {
-            ts: 100,
-            seq: 113,
-            is_trade: false,
-            is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
-        };
-        let t1 = Update {
-            ts: 101,
-            seq: 113,
-            is_trade: false,
-            is_bid: false,
-            price: 5100.01,
-            size: 2.14564564645,
-        };
-        let t2 = Update {
-            ts: 1000000,
-            seq: 113,
-            is_trade: true,
-            is_bid: false,
-            price: 5100.01,
-            size: 1.123465,
-        };
-        ts.push(t);
-        ts.push(t1);
-        ts.push(t2);
-        ts.sort();
-        ts
+    #[test]
+    #[serial]
+    fn drop_late_append_should_reject_unsorted_updates() {
+        let updates = prepare_data_range(100, true);
+        encode(FNAME, SYMBOL, &updates[..50]).unwrap();
+        let before = std::fs::read(FNAME).unwrap();
+
+        let mut incoming = updates[40..].to_vec();
+        incoming.swap(20, 30);
+        let res = append_with_policy(FNAME, &incoming, AppendPolicy::DropLate);
+        assert!(matches!(res, Err(WstfError::OutOfOrder { ts: 62000, .. })));
+        assert_eq!(std::fs::read(FNAME).unwrap(), before);
+
+        append_with_policy(FNAME, &updates[40..], AppendPolicy::DropLate).unwrap();
+        assert_eq!(decode(FNAME, None).unwrap(), updates);
     }
 
-    #[cfg(test)]
-    fn sample_data_one_item() -> Vec<Update> {
-        let mut ts: Vec<Update> = vec![];
-        let t = Update {
-            ts: 100,
-            seq: 113,
-            is_trade: false,
-            is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+    #[test]
+    #[serial]
+    fn merge_append_should_insert_late_updates() {
+        let updates = prepare_data_range(3000, true);
+        let (existing, late): (Vec<Update>, Vec<Update>) = updates
+            .iter()
+            .partition(|up| up.ts < 1_500_000 || up.ts % 7000 != 0);
+
+        let mut incoming = late.clone();
+        incoming.extend_from_slice(&existing[1990..]);
+
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
         };
-        ts.push(t);
-        ts.sort();
-        ts
+        encode_with_options(FNAME, SYMBOL, &existing[..2000], &opts).unwrap();
+        assert!(matches!(
+            append_with_policy(FNAME, &incoming, AppendPolicy::Merge),
+            Err(WstfError::Unsupported(_))
+        ));
+
+        encode(FNAME, SYMBOL, &existing[..2000]).unwrap();
+        let before = std::fs::read(FNAME).unwrap();
+        append_with_policy(FNAME, &incoming, AppendPolicy::Merge).unwrap();
+
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        assert_eq!(header.nums, updates.len() as u64);
+        assert_eq!(header.max_ts, updates.last().unwrap().ts);
+        assert_eq!(decode(FNAME, None).unwrap(), updates);
+        assert_eq!(
+            get_range_in_file(FNAME, 1_400_000, 1_500_000).unwrap(),
+            updates[1399..1500].to_vec()
+        );
+
+        let main = header.main_offset() as usize;
+        let after = std::fs::read(FNAME).unwrap();
+        assert_eq!(after[main..main + 10_000], before[main..main + 10_000]);
+        let index_offset = header.index_offset.unwrap();
+        let entries = read_index(&mut file_reader(FNAME).unwrap(), index_offset).unwrap();
+        assert_eq!(after.len() as u64, index_offset + index_len(entries.len()));
     }
 
-    #[cfg(test)]
-    fn sample_data_append() -> Vec<Update> {
-        let mut ts: Vec<Update> = vec![];
-        let t2 = Update {
-            ts: 00000002,
-            seq: 113,
-            is_trade: false,
-            is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
-        };
+    #[test]
+    #[serial]
+    fn recover_should_complete_an_interrupted_merge() {
+        let updates = prepare_data_range(3000, true);
+        let (existing, late): (Vec<Update>, Vec<Update>) = updates
+            .iter()
+            .partition(|up| up.ts < 1_500_000 || up.ts % 7000 != 0);
+
+        encode(FNAME, SYMBOL, &existing).unwrap();
+        let before = std::fs::read(FNAME).unwrap();
+        let old = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        let old_entries =
+            read_index(&mut file_reader(FNAME).unwrap(), old.index_offset.unwrap()).unwrap();
+        let start = old_entries.partition_point(|entry| entry.ref_ts < late[0].ts) - 1;
+        let tail_offset = old_entries[start].offset;
+
+        append_with_policy(FNAME, &late, AppendPolicy::Merge).unwrap();
+        let merged = std::fs::read(FNAME).unwrap();
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        let index_offset = header.index_offset.unwrap();
+        let mut entries = read_index(&mut file_reader(FNAME).unwrap(), index_offset).unwrap();
+
+        // The merged tail is staged past the old end of the file and committed, but not moved.
+        let staged_offset = before.len() as u64 * 2;
+        let shift = staged_offset - tail_offset;
+        for entry in entries.iter_mut().skip(start) {
+            entry.offset += shift;
+        }
+        let mut crashed = before.clone();
+        crashed[..header.main_offset() as usize]
+            .copy_from_slice(&merged[..header.main_offset() as usize]);
+        BigEndian::write_u64(
+            &mut crashed[V2_INDEX_OFFSET as usize..],
+            index_offset + shift,
+        );
+        crashed.resize(staged_offset as usize, 0);
+        crashed.extend_from_slice(&merged[tail_offset as usize..index_offset as usize]);
+        write_index(&mut crashed, &entries).unwrap();
+        std::fs::write(FNAME, &crashed).unwrap();
+        assert!(decode(FNAME, None).is_err());
+
+        let report = recover(FNAME).unwrap();
+        assert_eq!(report.nums, updates.len() as u64);
+        assert_eq!(report.truncated_at, None);
+        assert_eq!(std::fs::read(FNAME).unwrap(), merged);
+    }
+
+    #[test]
+    #[serial]
+    fn should_recover_from_interrupted_append() {
+        let updates = prepare_data_range(3000, true);
+
+        for version in [Version::V1, Version::V2] {
+            let opts = EncodeOptions {
+                version,
+                checksum: version == Version::V2,
+                ..Default::default()
+            };
+            encode_with_options(FNAME, SYMBOL, &updates[..1000], &opts).unwrap();
+            let before = std::fs::read(FNAME).unwrap();
+            let mut rdr = file_reader(FNAME).unwrap();
+            let old = read_header(&mut rdr).unwrap();
+            let old_end = old.index_offset.unwrap_or(before.len() as u64) as usize;
+
+            append(FNAME, &updates[1000..]).unwrap();
+            let after = std::fs::read(FNAME).unwrap();
+            let mut rdr = file_reader(FNAME).unwrap();
+            let new = read_header(&mut rdr).unwrap();
+            let batches = read_batch_index(&mut rdr, &new).unwrap();
+            let appended = batches
+                .iter()
+                .filter(|e| e.offset >= old_end as u64)
+                .collect::<Vec<_>>();
+            let complete = appended[..5]
+                .iter()
+                .map(|e| e.count as usize)
+                .sum::<usize>();
+            let cut = appended[5].offset as usize + 7;
+
+            let mut crashed = before[..old.main_offset() as usize].to_vec();
+            if version == Version::V2 {
+                crashed[V2_INDEX_OFFSET as usize..][..8].fill(0);
+            }
+            crashed.extend_from_slice(&after[old.main_offset() as usize..cut]);
+            std::fs::write(FNAME, &crashed).unwrap();
+            assert!(matches!(
+                decode(FNAME, None),
+                Err(WstfError::Truncated { .. })
+            ));
+
+            let report = recover(FNAME).unwrap();
+            let nums = 1000 + complete;
+            assert_eq!(report.nums, nums as u64);
+            assert_eq!(report.max_ts, updates[nums - 1].ts);
+            assert!(report.truncated_at.is_some());
+
+            let mut rdr = file_reader(FNAME).unwrap();
+            let header = read_header(&mut rdr).unwrap();
+            assert_eq!(header.nums, nums as u64);
+            assert_eq!(header.max_ts, updates[nums - 1].ts);
+            assert_eq!(decode(FNAME, None).unwrap(), updates[..nums].to_vec());
+            assert_eq!(
+                get_range_in_file(FNAME, 500_000, 2_000_000).unwrap(),
+                updates[499..nums].to_vec()
+            );
+
+            append(FNAME, &updates[nums..]).unwrap();
+            assert_eq!(decode(FNAME, None).unwrap(), updates);
+            assert_eq!(recover(FNAME).unwrap().truncated_at, None);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn recover_should_leave_corrupt_batches_before_the_end_alone() {
+        let updates = prepare_data_range(3000, true);
+        encode_with_options(FNAME, SYMBOL, &updates, &checksum_opts()).unwrap();
+        let mut rdr = file_reader(FNAME).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let batches = read_batch_index(&mut rdr, &header).unwrap();
+
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[V2_INDEX_OFFSET as usize..][..8].fill(0);
+        bytes[batches[1].offset as usize + 40] ^= 0xff;
+        std::fs::write(FNAME, &bytes).unwrap();
+
+        assert!(matches!(
+            recover(FNAME),
+            Err(WstfError::ChecksumMismatch { offset, .. }) if offset == batches[1].offset
+        ));
+        assert!(append(FNAME, &updates[..1]).is_err());
+        assert_eq!(std::fs::read(FNAME).unwrap(), bytes);
+    }
+
+    #[test]
+    #[serial]
+    fn recover_should_rebuild_an_index_with_a_corrupt_count() {
+        let updates = prepare_data_range(3000, true);
+        encode(FNAME, SYMBOL, &updates).unwrap();
+        let index_offset = read_header(&mut file_reader(FNAME).unwrap())
+            .unwrap()
+            .index_offset
+            .unwrap() as usize;
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[index_offset + 1..][..8].fill(0xFF);
+        std::fs::write(FNAME, &bytes).unwrap();
+
+        let report = recover(FNAME).unwrap();
+        assert_eq!(report.nums, updates.len() as u64);
+        assert_eq!(
+            get_range_in_file(FNAME, 500_000, 2_000_000).unwrap(),
+            updates[499..2000].to_vec()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn should_append_after_the_batches_of_a_crashed_append() {
+        let updates = prepare_data_range(3000, true);
+        encode(FNAME, SYMBOL, &updates[..1000]).unwrap();
+
+        // The index is invalidated before an append writes its batches.
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[V2_INDEX_OFFSET as usize..][..8].fill(0);
+        std::fs::write(FNAME, &bytes).unwrap();
+
+        append(FNAME, &updates[1000..]).unwrap();
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        assert_eq!(header.nums, updates.len() as u64);
+        assert_eq!(header.min_ts, Some(updates[0].ts));
+        assert_eq!(decode(FNAME, None).unwrap(), updates);
+        assert_eq!(
+            get_range_in_file(FNAME, 500_000, 2_000_000).unwrap(),
+            updates[499..2000].to_vec()
+        );
+    }
+
+    #[test]
+    fn should_speak_json() {
         let t1 = Update {
             ts: 20000001,
             seq: 113,
             is_trade: false,
             is_bid: false,
+            flags: Flags::FLAG_EMPTY,
             price: 5100.01,
             size: 1.14564564645,
         };
-        let t = Update {
-            ts: 20000000,
-            seq: 113,
-            is_trade: false,
-            is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
-        };
-        ts.push(t);
-        ts.push(t1);
-        ts.push(t2);
-        ts.sort();
-        ts
-    }
-
-    #[cfg(test)]
-    fn before() -> Vec<Update> {
-        let ts = sample_data();
-        encode(FNAME, SYMBOL, &ts).unwrap();
-        ts
+        assert_eq!(
+            t1.as_json(),
+            r#"{"ts":20000.001,"seq":113,"is_trade":false,"is_bid":false,"price":5100.01,"size":1.1456456}"#
+        );
     }
 
     #[test]
-    fn should_format_metadata_properly() {
-        let meta = Metadata {
-            symbol: SYMBOL.to_owned(),
-            nums: 1,
-            max_ts: 1,
-            min_ts: 1,
+    fn should_write_to_bytes() {
+        let up = Update {
+            ts: 0,
+            seq: 0,
+            is_trade: false,
+            is_bid: false,
+            flags: Flags::FLAG_EMPTY,
+            price: 0f32,
+            size: 0f32,
         };
-
+        let mut bytes = vec![];
+        write_batches(&mut bytes, [up].iter().peekable()).unwrap();
         assert_eq!(
-            format!(
-                r#"{{"symbol":{},"nums":{},"max_ts":{},"min_ts":{}}}"#,
-                meta.symbol, meta.nums, meta.max_ts, meta.min_ts,
-            ),
-            r#"{"symbol":BTC_USDT,"nums":1,"max_ts":1,"min_ts":1}"#
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            bytes
         );
     }
 
     #[test]
     #[serial]
-    fn should_encode_decode_one_item() {
-        let ts = sample_data_one_item();
-        encode(FNAME, SYMBOL, &ts).unwrap();
-        let decoded_updates = decode(FNAME, None).unwrap();
-        assert_eq!(decoded_updates, ts);
+    fn should_encode_and_decode_v1_file() {
+        let ts = sample_data();
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &ts, &opts).unwrap();
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        assert_eq!(read_magic_value(&mut rdr).unwrap(), Some(Version::V1));
+
+        let header = read_header(&mut rdr).unwrap();
+        assert_eq!(header.main_offset(), MAIN_OFFSET);
+        assert_eq!(header.min_ts, None);
+        assert_eq!(header.nums, ts.len() as u64);
+
+        rdr.seek(SeekFrom::Start(LEN_OFFSET)).unwrap();
+        assert_eq!(rdr.read_u64::<BigEndian>().unwrap(), ts.len() as u64);
+
+        let meta = read_meta(FNAME).unwrap();
+        assert_eq!(meta.min_ts, ts[0].ts);
+        assert_eq!(decode(FNAME, None).unwrap(), ts);
     }
 
     #[test]
     #[serial]
-    fn should_encode_and_decode_file() {
+    fn should_write_v2_header_by_default() {
         let ts = before();
-        let decoded_updates = decode(FNAME, None).unwrap();
-        assert_eq!(decoded_updates, ts);
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        assert_eq!(read_magic_value(&mut rdr).unwrap(), Some(Version::V2));
+
+        let header = read_header(&mut rdr).unwrap();
+        assert_eq!(header.header_len, V2_HEADER_LEN);
+        assert_eq!(header.flags, HeaderFlags::FLAG_EMPTY);
+        assert_eq!(header.symbol, SYMBOL);
+        assert_eq!(header.min_ts, Some(ts[0].ts));
+        assert_eq!(header.max_ts, get_max_ts_sorted(&ts));
     }
 
     #[test]
-    #[serial]
-    fn range_for_each_should_return_correct_updates_within_range() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_skip_unknown_header_space() {
+        let ts = sample_data();
+        let mut header = Header::new(Version::V2, SYMBOL);
+        header.header_len = 512;
+        header.nums = ts.len() as u64;
+        header.min_ts = Some(ts[0].ts);
+        header.max_ts = get_max_ts_sorted(&ts);
+
+        let mut buf = Cursor::new(vec![]);
+        write_header(&mut buf, &header).unwrap();
+        write_main(&mut buf, &header, ts.iter().peekable()).unwrap();
+
+        assert_eq!(read_header(&mut buf).unwrap(), header);
+        assert_eq!(range(&mut buf, 0, u64::MAX).unwrap(), ts);
+    }
 
-        let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 10000, 20000, &mut |up| result.push(*up)).unwrap();
+    #[test]
+    fn should_reject_unsupported_version() {
+        let mut buf = Cursor::new(vec![0x57, 0x53, 0x54, 0x46, 0x09]);
+        assert!(matches!(
+            read_magic_value(&mut buf),
+            Err(WstfError::UnsupportedVersion(0x09))
+        ));
 
-        let expected = (10..21)
-            .map(|i| Update {
-                ts: i * 1000u64,
-                seq: i as u32,
-                price: 0f32,
-                size: 0f32,
-                is_bid: false,
-                is_trade: false,
-            })
-            .collect::<Vec<Update>>();
+        let mut buf = Cursor::new(vec![0x00, 0x53, 0x54, 0x46, 0x01]);
+        assert_eq!(read_magic_value(&mut buf).unwrap(), None);
+    }
 
-        assert_eq!(result, expected);
+    #[test]
+    fn should_return_typed_errors() {
+        let mut buf = Cursor::new(vec![0u8; 128]);
+        assert!(matches!(read_header(&mut buf), Err(WstfError::BadMagic)));
+
+        let mut buf = Cursor::new(vec![]);
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        let res =
+            encode_buffer_with_options(&mut buf, "A_VERY_LONG_SYMBOL_NAME", &sample_data(), &opts);
+        assert!(matches!(res, Err(WstfError::SymbolTooLong(_))));
+
+        let mut buf = Cursor::new(vec![]);
+        encode_buffer(&mut buf, SYMBOL, &sample_data()).unwrap();
+        let mut bytes = buf.into_inner();
+        bytes[V2_FLAGS_OFFSET as usize] = 0xFF;
+        let res = read_header(&mut Cursor::new(bytes));
+        assert!(matches!(res, Err(WstfError::InvalidFlags(_))));
+
+        let up = sample_data()[0];
+        let res = up.serialize_to_buffer(&mut vec![], up.ts, up.seq + 1);
+        assert!(matches!(res, Err(WstfError::OutOfOrder { .. })));
+    }
+
+    #[test]
+    fn decode_buffer_should_report_truncated_batches() {
+        let updates = sample_data();
+        let mut buf = vec![];
+        write_batches(&mut buf, updates.iter().peekable()).unwrap();
+        assert_eq!(decode_buffer(&mut &buf[..]).unwrap(), updates);
+
+        let res = decode_buffer(&mut &buf[..buf.len() - 3]);
+        assert!(matches!(res, Err(WstfError::Truncated { .. })));
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_return_correct_updates_within_range_without_seq() {
-        let updates = prepare_data_range(50, false);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_append_to_v1_file() {
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &sample_data(), &opts).unwrap();
+        append(FNAME, &sample_data_append()).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 10000, 20000, &mut |up| result.push(*up)).unwrap();
-
-        let expected = (10..21)
-            .map(|i| Update {
-                ts: i * 1000u64,
-                seq: 0,
-                price: 0f32,
-                size: 0f32,
-                is_bid: false,
-                is_trade: false,
-            })
-            .collect::<Vec<Update>>();
-
-        assert_eq!(result, expected);
+        let header = read_header(&mut rdr).unwrap();
+        assert_eq!(header.version, Version::V1);
+        assert_eq!(header.nums, 5);
+        assert_eq!(header.max_ts, 20000001);
+        assert_eq!(decode(FNAME, None).unwrap().len(), 5);
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_return_all_updates() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_write_batch_index_footer() {
+        let updates = prepare_data_range(5000, true);
+        encode(FNAME, SYMBOL, &updates).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 0, 50 * 1000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        assert!(header.index_offset.is_some());
+
+        let indexed = read_batch_index(&mut rdr, &header).unwrap();
+        let scanned = read_batch_index(
+            &mut rdr,
+            &Header {
+                index_offset: None,
+                ..header.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(indexed, scanned);
+        assert_eq!(
+            indexed.iter().map(|e| e.count as u64).sum::<u64>(),
+            header.nums
+        );
+    }
 
-        assert_eq!(result, updates);
+    #[test]
+    #[serial]
+    fn indexed_range_should_match_linear_scan() {
+        let updates = prepare_data_range(5000, true);
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &updates, &opts).unwrap();
+        let mut linear = file_reader(FNAME).unwrap();
+        let linear = (0..60)
+            .map(|i| range(&mut linear, i * 83_000, i * 83_000 + 41_000).unwrap())
+            .collect::<Vec<_>>();
+
+        encode(FNAME, SYMBOL, &updates).unwrap();
+        let mut indexed = file_reader(FNAME).unwrap();
+        for (i, expected) in linear.iter().enumerate() {
+            let i = i as u64;
+            let got = range(&mut indexed, i * 83_000, i * 83_000 + 41_000).unwrap();
+            assert_eq!(&got, expected);
+        }
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_return_all_updates_large() {
+    fn should_maintain_index_on_append() {
         let updates = prepare_data_range(5000, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+        encode(FNAME, SYMBOL, &updates[..2000]).unwrap();
+        append(FNAME, &updates[2000..]).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 0, 5000 * 1000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let index = read_batch_index(&mut rdr, &header).unwrap();
+        assert_eq!(index.iter().map(|e| e.count as u64).sum::<u64>(), 4999);
 
-        assert_eq!(result, updates);
+        assert_eq!(decode(FNAME, None).unwrap(), updates);
+        assert_eq!(
+            get_range_in_file(FNAME, 1_999_000, 2_001_000).unwrap(),
+            updates[1998..2001].to_vec()
+        );
+
+        let batches = WSTFBufReader::new(FNAME, 1)
+            .unwrap()
+            .flat_map(Result::unwrap)
+            .count();
+        assert_eq!(batches, updates.len());
+    }
+
+    fn checksum_opts() -> EncodeOptions {
+        EncodeOptions {
+            checksum: true,
+            ..Default::default()
+        }
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_return_all_updates_without_seq() {
-        let updates = prepare_data_range(50, false);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_encode_and_decode_checksummed_file() {
+        let updates = prepare_data_range(5000, true);
+        encode_with_options(FNAME, SYMBOL, &updates, &checksum_opts()).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 0, 50 * 1000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        assert!(header.flags.contains(HeaderFlags::FLAG_CHECKSUM));
 
-        assert_eq!(result, updates);
+        assert_eq!(decode(FNAME, None).unwrap(), updates);
+        assert_eq!(
+            get_range_in_file(FNAME, 10000, 20000).unwrap(),
+            updates[9..20].to_vec()
+        );
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_return_all_updates_without_seq_large() {
-        let updates = prepare_data_range(5000, false);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_report_offset_of_corrupt_batch() {
+        let updates = prepare_data_range(500, true);
+        encode_with_options(FNAME, SYMBOL, &updates, &checksum_opts()).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 0, 5000 * 1000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let corrupt = read_batch_index(&mut rdr, &header).unwrap()[3];
+
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[(corrupt.offset + header.batch_format().ref_len()) as usize + 5] ^= 0xFF;
+        std::fs::write(FNAME, bytes).unwrap();
 
-        assert_eq!(result, updates);
+        let err = decode(FNAME, None).unwrap_err();
+        match err {
+            WstfError::ChecksumMismatch { offset, .. } => assert_eq!(offset, corrupt.offset),
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let err = get_range_in_file(FNAME, 0, u64::MAX).unwrap_err();
+        assert!(matches!(err, WstfError::ChecksumMismatch { .. }));
+        assert!(get_range_in_file(FNAME, 0, corrupt.ref_ts - 1).is_ok());
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_handle_empty_range() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn checksum_should_cover_batch_reference() {
+        let updates = prepare_data_range(500, true);
+        let opts = EncodeOptions {
+            events: true,
+            ..checksum_opts()
+        };
+        encode_with_options(FNAME, SYMBOL, &updates, &opts).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 50000, 60000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let corrupt = read_batch_index(&mut rdr, &header).unwrap()[3];
 
-        assert!(result.is_empty());
+        // Retag the batch as order rows through its kind byte, the last byte of the reference.
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[(corrupt.offset + header.batch_format().ref_len()) as usize - 1] = 0x01;
+        std::fs::write(FNAME, bytes).unwrap();
+
+        let err = decode(FNAME, None).unwrap_err();
+        assert!(
+            matches!(err, WstfError::ChecksumMismatch { offset, .. } if offset == corrupt.offset)
+        );
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_handle_single_update_in_range() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_report_offset_of_truncated_batch() {
+        let updates = prepare_data_range(500, true);
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &updates, &opts).unwrap();
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 1000, 1000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let last = *read_batch_index(&mut rdr, &header).unwrap().last().unwrap();
 
-        let expected = vec![Update {
-            seq: 1,
-            ts: 1000,
-            price: 0f32,
-            size: 0f32,
-            is_bid: false,
-            is_trade: false,
-        }];
+        let bytes = std::fs::read(FNAME).unwrap();
+        std::fs::write(FNAME, &bytes[..bytes.len() - 7]).unwrap();
 
-        assert_eq!(result, expected);
+        let err = decode(FNAME, None).unwrap_err();
+        assert!(matches!(err, WstfError::Truncated { offset } if offset == last.offset));
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_handle_single_update_out_of_range() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
+    fn should_reject_batch_lengths_before_reading() {
+        let updates = prepare_data_range(500, true);
+        let opts = EncodeOptions {
+            encoding: BatchEncoding::Xor,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &updates, &opts).unwrap();
+        let first = read_header(&mut file_reader(FNAME).unwrap())
+            .unwrap()
+            .main_offset() as usize;
+        let bytes = std::fs::read(FNAME).unwrap();
+
+        // The encoded length follows the marker, ref_ts, ref_seq, count and encoding byte.
+        let len_at = first + 1 + 8 + 4 + 2 + 1;
+        for len in [u32::MAX, 1 << 24] {
+            let mut corrupt = bytes.clone();
+            corrupt[len_at..len_at + 4].copy_from_slice(&len.to_be_bytes());
+            std::fs::write(FNAME, corrupt).unwrap();
+            assert!(matches!(decode(FNAME, None), Err(WstfError::Corrupt(_))));
+        }
 
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 100000, 200000, &mut |up| result.push(*up)).unwrap();
-
-        assert!(result.is_empty());
+        let header = read_header(&mut rdr).unwrap();
+        let last = *read_batch_index(&mut rdr, &header).unwrap().last().unwrap();
+        let mut truncated = bytes;
+        truncated.truncate(last.offset as usize + 20);
+        std::fs::write(FNAME, truncated).unwrap();
+        let err = decode(FNAME, None).unwrap_err();
+        assert!(matches!(err, WstfError::Truncated { offset } if offset == last.offset));
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_handle_min_ts_greater_than_max_ts() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
-
+    fn should_report_bytes_that_are_not_batches() {
+        let updates = prepare_data_range(500, true);
+        encode(FNAME, SYMBOL, &updates).unwrap();
         let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 20000, 10000, &mut |up| result.push(*up)).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let corrupt = read_batch_index(&mut rdr, &header).unwrap()[3];
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[corrupt.offset as usize] = 0x7F;
+        std::fs::write(FNAME, bytes).unwrap();
+        let offset = corrupt.offset.to_string();
+        for res in [
+            decode(FNAME, None),
+            get_range_in_file(FNAME, 0, u64::MAX),
+            crate::protocol::mmap::MmapReader::open(FNAME)
+                .unwrap()
+                .decode(),
+        ] {
+            assert!(matches!(res, Err(WstfError::Corrupt(msg)) if msg.contains(&offset)));
+        }
 
-        assert!(result.is_empty());
+        // Without an index, a stray index marker ends the batches early, which the row count
+        // catches.
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &updates, &opts).unwrap();
+        let mut rdr = file_reader(FNAME).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let corrupt = read_batch_index(&mut rdr, &header).unwrap()[3];
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[corrupt.offset as usize] = 0x00;
+        std::fs::write(FNAME, bytes).unwrap();
+        assert!(
+            matches!(decode(FNAME, None), Err(WstfError::Corrupt(msg)) if msg.contains("header"))
+        );
     }
 
     #[test]
     #[serial]
-    fn range_for_each_should_handle_min_ts_greater_than_max_ts_and_empty_range() {
-        let updates = prepare_data_range(50, true);
-        encode(FNAME, "BTC_USDT", &updates).unwrap();
-
-        let mut rdr = file_reader(FNAME).unwrap();
-        let mut result = vec![];
-        range_for_each(&mut rdr, 20000, 10000, &mut |up| result.push(*up)).unwrap();
+    fn should_report_row_counts_the_batches_cannot_hold() {
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, SYMBOL, &prepare_data_range(500, true), &opts).unwrap();
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        bytes[LEN_OFFSET as usize..][..8].fill(0xFF);
+        std::fs::write(FNAME, bytes).unwrap();
+        assert!(
+            matches!(decode(FNAME, None), Err(WstfError::Corrupt(msg)) if msg.contains("header"))
+        );
+    }
 
-        assert!(result.is_empty());
+    #[test]
+    fn should_reject_checksum_for_v1() {
+        let opts = EncodeOptions {
+            version: Version::V1,
+            checksum: true,
+            ..Default::default()
+        };
+        let mut buf = Cursor::new(vec![]);
+        assert!(encode_buffer_with_options(&mut buf, SYMBOL, &sample_data(), &opts).is_err());
     }
 
     #[test]
     #[serial]
-    fn should_return_correct_range_real() {
-        let mut rdr = file_reader(FNAME_DATA).unwrap();
+    fn should_decode_compressed_batches() {
+        let updates = prepare_data_range(5000, true);
+        encode(FNAME, SYMBOL, &updates).unwrap();
+        let raw_size = std::fs::metadata(FNAME).unwrap().len();
+
+        for compression in Compression::available() {
+            for checksum in [false, true] {
+                let opts = EncodeOptions {
+                    checksum,
+                    compression,
+                    ..Default::default()
+                };
+                encode_with_options(FNAME, SYMBOL, &updates[..3000], &opts).unwrap();
+                append(FNAME, &updates[3000..]).unwrap();
+
+                let mut rdr = file_reader(FNAME).unwrap();
+                assert_eq!(read_header(&mut rdr).unwrap().compression, compression);
+                if compression != Compression::None {
+                    assert!(std::fs::metadata(FNAME).unwrap().len() < raw_size);
+                }
 
-        let start = 1_510_168_156 * 1000;
-        let end = 1_510_171_756 * 1000;
+                assert_eq!(decode(FNAME, None).unwrap(), updates);
+                assert_eq!(
+                    get_range_in_file(FNAME, 10000, 20000).unwrap(),
+                    updates[9..20].to_vec()
+                );
+                let batched = WSTFBufReader::new(FNAME, 10)
+                    .unwrap()
+                    .flat_map(Result::unwrap)
+                    .collect::<Vec<_>>();
+                assert_eq!(batched, updates);
+            }
+        }
+    }
 
-        let ups = range(&mut rdr, start, end).unwrap();
-        println!("{}", ups.len());
-        assert_eq!(ups.len(), 56564);
+    fn sample_data_ticks(n: u64) -> Vec<Update> {
+        (0..n)
+            .map(|i| Update {
+                ts: 1_000_000 + i * 3,
+                seq: (i / 100) as u32,
+                is_trade: i % 11 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 5100.5 + ((i / 10) % 9) as f32 * 0.5,
+                size: ((i / 3) % 5) as f32,
+            })
+            .collect()
+    }
 
-        for up in ups.iter() {
-            assert!(up.ts >= start && up.ts <= end);
+    #[test]
+    #[serial]
+    fn should_decode_xor_encoded_batches() {
+        let updates = sample_data_ticks(5000);
+        encode(FNAME, SYMBOL, &updates).unwrap();
+        let raw_size = std::fs::metadata(FNAME).unwrap().len();
+
+        for compression in Compression::available() {
+            let opts = EncodeOptions {
+                checksum: true,
+                compression,
+                encoding: BatchEncoding::Xor,
+                ..Default::default()
+            };
+            encode_with_options(FNAME, SYMBOL, &updates[..3000], &opts).unwrap();
+            append(FNAME, &updates[3000..]).unwrap();
+
+            let mut rdr = file_reader(FNAME).unwrap();
+            let header = read_header(&mut rdr).unwrap();
+            assert_eq!(header.encoding, BatchEncoding::Xor);
+            rdr.seek(SeekFrom::Start(header.main_offset())).unwrap();
+            let meta = read_next_batch_meta(&mut rdr, &header.batch_format(), header.index_offset)
+                .unwrap()
+                .unwrap();
+            assert_eq!(meta.encoding, BatchEncoding::Xor);
+            assert!(std::fs::metadata(FNAME).unwrap().len() < raw_size / 2);
+
+            assert_eq!(decode(FNAME, None).unwrap(), updates);
+            assert_eq!(
+                get_range_in_file(FNAME, 1_003_000, 1_006_000).unwrap(),
+                updates[1000..=2000].to_vec()
+            );
         }
     }
 
     #[test]
-    #[serial]
-    fn should_return_correct_symbol() {
-        before();
-        let mut rdr = file_reader(FNAME).unwrap();
-        let sym = read_symbol(&mut rdr).unwrap();
-        assert_eq!(sym, SYMBOL);
+    fn should_fall_back_to_raw_batches() {
+        let updates = sample_data_one_item();
+        let opts = EncodeOptions {
+            encoding: BatchEncoding::Xor,
+            ..Default::default()
+        };
+        let mut buf = Cursor::new(vec![]);
+        encode_buffer_with_options(&mut buf, SYMBOL, &updates, &opts).unwrap();
+
+        let header = read_header(&mut buf).unwrap();
+        buf.seek(SeekFrom::Start(header.main_offset())).unwrap();
+        let meta = read_next_batch_meta(&mut buf, &header.batch_format(), header.index_offset)
+            .unwrap()
+            .unwrap();
+        assert_eq!(meta.encoding, BatchEncoding::Raw);
+        assert_eq!(meta.encoded_len(), meta.raw_len());
+        assert_eq!(read_one_batch_main(&mut buf, &meta).unwrap(), updates);
     }
 
     #[test]
-    #[serial]
-    fn should_return_first_record() {
-        let vs = before();
-        let mut rdr = file_reader(FNAME).unwrap();
-        let v = read_first(&mut rdr).unwrap();
-        assert_eq!(vs[0], v);
+    fn should_reject_unavailable_compression() {
+        let opts = EncodeOptions {
+            compression: Compression::Zstd,
+            ..Default::default()
+        };
+        let mut buf = Cursor::new(vec![]);
+        let res = encode_buffer_with_options(&mut buf, SYMBOL, &sample_data(), &opts);
+        assert_eq!(res.is_ok(), Compression::Zstd.is_available());
     }
 
     #[test]
     #[serial]
-    fn should_return_correct_num_of_items() {
-        let vs = before();
-        let mut rdr = file_reader(FNAME).unwrap();
-        let len = read_len(&mut rdr).unwrap();
-        assert_eq!(vs.len() as u64, len);
+    fn should_iterate_batches_in_reverse() {
+        let ups = prepare_data_range(1000, true);
+        for version in [Version::V1, Version::V2] {
+            let opts = EncodeOptions {
+                version,
+                ..Default::default()
+            };
+            encode_with_options(FNAME, SYMBOL, &ups, &opts).unwrap();
+
+            let reversed = ReverseBatchReader::open(FNAME)
+                .unwrap()
+                .flat_map(Result::unwrap)
+                .collect::<Vec<_>>();
+            assert_eq!(reversed, ups.iter().rev().copied().collect::<Vec<_>>());
+        }
     }
 
     #[test]
     #[serial]
-    fn should_return_max_ts() {
-        let vs = before();
-        let mut rdr = file_reader(FNAME).unwrap();
-        let max_ts = read_max_ts(&mut rdr).unwrap();
-        assert_eq!(max_ts, get_max_ts_sorted(&vs));
+    fn should_return_last_n_before() {
+        let ups = prepare_data_range(1000, true);
+        for version in [Version::V1, Version::V2] {
+            let opts = EncodeOptions {
+                version,
+                ..Default::default()
+            };
+            encode_with_options(FNAME, SYMBOL, &ups, &opts).unwrap();
+
+            assert_eq!(last_n_before(FNAME, 500_000, 40).unwrap(), ups[459..499]);
+            assert_eq!(last_n_before(FNAME, 500_500, 3).unwrap(), ups[497..500]);
+            assert_eq!(last_n_before(FNAME, 5_000, 10).unwrap(), ups[..4]);
+            assert_eq!(last_n_before(FNAME, u64::MAX, 2).unwrap(), ups[997..]);
+            assert!(last_n_before(FNAME, 1_000, 10).unwrap().is_empty());
+            assert!(last_n_before(FNAME, 500_000, 0).unwrap().is_empty());
+        }
+    }
+
+    fn container_streams() -> Vec<(&'static str, Vec<Update>)> {
+        let stream = |n: u64, start: u64, step: u64, price: f32| {
+            (0..n)
+                .map(|i| Update {
+                    ts: start + i * step,
+                    seq: 0,
+                    is_trade: i % 3 == 0,
+                    is_bid: i % 2 == 0,
+                    flags: Flags::FLAG_EMPTY,
+                    price: price + (i % 7) as f32,
+                    size: (i % 5) as f32,
+                })
+                .collect::<Vec<_>>()
+        };
+        vec![
+            ("binance_btc_usdt", stream(20_000, 1_000, 10, 60_000.)),
+            ("binance_eth_usdt", stream(5_000, 1_003, 37, 3_000.)),
+            ("binance_sol_usdt", stream(10, 5, 20_000, 150.)),
+        ]
     }
 
     #[test]
     #[serial]
-    fn should_append_filtered_data() {
-        should_encode_and_decode_file();
+    fn should_filter_container_by_symbol() {
+        let streams = container_streams();
+        let refs = streams
+            .iter()
+            .map(|(symbol, ups)| (*symbol, &ups[..]))
+            .collect::<Vec<_>>();
+        let opts = EncodeOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        encode_container(FNAME, "binance", &refs, &opts).unwrap();
 
-        println!("----DONE----");
+        let meta = read_meta(FNAME).unwrap();
+        assert_eq!(meta.symbol, "binance");
+        assert_eq!(
+            meta.symbols,
+            ["binance_btc_usdt", "binance_eth_usdt", "binance_sol_usdt"]
+        );
+        assert_eq!(meta.nums, 25_010);
+        assert_eq!(meta.min_ts, 5);
+
+        for (symbol, ups) in &streams {
+            let mut decoded = vec![];
+            decode_for_each_with_symbol(FNAME, None, Some(symbol), &mut |up| decoded.push(*up))
+                .unwrap();
+            assert_eq!(&decoded, ups);
+
+            for (min_ts, max_ts) in [(0, 1_000), (70_000, 140_000), (150_000, 400_000)] {
+                let expected = ups
+                    .iter()
+                    .filter(|up| up.ts >= min_ts && up.ts <= max_ts)
+                    .copied()
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    get_range_in_file_with_symbol(FNAME, min_ts, max_ts, Some(symbol)).unwrap(),
+                    expected
+                );
+            }
+        }
 
-        let old_data = sample_data();
-        let old_max_ts = get_max_ts_sorted(&old_data);
-        let append_data: Vec<Update> = sample_data_append()
-            .into_iter()
-            .filter(|up| up.ts >= old_max_ts)
-            .collect();
-        let new_size = append_data.len() + old_data.len();
+        assert_eq!(decode(FNAME, None).unwrap().len(), 25_010);
+        assert!(
+            get_range_in_file_with_symbol(FNAME, 0, u64::MAX, Some("binance_xrp_usdt"))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(matches!(
+            append(FNAME, &streams[0].1[..1]),
+            Err(WstfError::Unsupported(_))
+        ));
+    }
 
-        append(FNAME, &append_data).unwrap();
+    #[test]
+    #[serial]
+    fn should_match_header_symbol_for_single_symbol_files() {
+        let ups = prepare_data_range(100, false);
+        encode(FNAME, SYMBOL, &ups).unwrap();
 
-        println!("----APPENDED----");
+        let in_range = ups[9..20].to_vec();
+        assert_eq!(
+            get_range_in_file_with_symbol(FNAME, 10_000, 20_000, Some(SYMBOL)).unwrap(),
+            in_range
+        );
+        assert!(
+            get_range_in_file_with_symbol(FNAME, 10_000, 20_000, Some("ETH_USDT"))
+                .unwrap()
+                .is_empty()
+        );
+    }
 
-        let mut rdr = file_reader(FNAME).unwrap();
+    #[test]
+    #[serial]
+    fn should_round_trip_instrument_metadata() {
+        let ups = prepare_data_range(100, true);
+        let instrument = InstrumentMetadata {
+            symbol: "deribit_btc_option_BTC-27DEC24-100000-C".to_owned(),
+            asset_type: AssetType::OPTION,
+            tick_size: Some(OrderedFloat(0.0005)),
+            lot_size: Some(OrderedFloat(0.1)),
+            price_decimals: Some(4),
+            tags: vec![
+                ("exchange".to_owned(), "deribit".to_owned()),
+                ("underlying".to_owned(), "BTC".to_owned()),
+            ],
+        };
+        encode_with_metadata(FNAME, &instrument, &ups, &EncodeOptions::default()).unwrap();
+
+        let meta = read_meta(FNAME).unwrap();
+        assert_eq!(meta.symbol, instrument.symbol);
+        assert_eq!(meta.instrument.as_ref(), Some(&instrument));
+        assert_eq!(meta.min_ts, ups[0].ts);
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+
+        append(
+            FNAME,
+            &[Update {
+                ts: 200_000,
+                ..ups[0]
+            }],
+        )
+        .unwrap();
+        assert_eq!(
+            read_meta(FNAME).unwrap().instrument.as_ref(),
+            Some(&instrument)
+        );
 
-        let max_ts = read_max_ts(&mut rdr).unwrap();
-        assert_eq!(max_ts, get_max_ts_sorted(&append_data));
+        let long = "binance_usdt_1000SHIBUSDT-PERP";
+        encode(FNAME, long, &ups).unwrap();
+        let meta = read_meta(FNAME).unwrap();
+        assert_eq!(meta.symbol, long);
+        assert_eq!(meta.instrument, Some(InstrumentMetadata::new(long)));
+        assert_eq!(
+            get_range_in_file_with_symbol(FNAME, 0, u64::MAX, Some(long)).unwrap(),
+            ups
+        );
 
-        let mut rdr = file_reader(FNAME).unwrap();
-        let len = read_len(&mut rdr).unwrap();
-        assert_eq!(new_size as u64, len);
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            encode_with_metadata(FNAME, &instrument, &ups, &opts),
+            Err(WstfError::Unsupported(_))
+        ));
+    }
 
-        let mut all_the_data = sample_data();
-        all_the_data.extend(append_data);
-        all_the_data.sort();
-        let decoded = decode(&FNAME, None).unwrap();
-        assert_eq!(all_the_data, decoded);
+    #[test]
+    #[serial]
+    fn should_reject_instrument_blocks_past_the_header() {
+        let ups = prepare_data_range(100, true);
+        let instrument = InstrumentMetadata::new(SYMBOL);
+        encode_with_metadata(FNAME, &instrument, &ups, &EncodeOptions::default()).unwrap();
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+
+        let mut bytes = std::fs::read(FNAME).unwrap();
+        let start = (V2_FIXED_LEN + header.symbols_len()) as usize;
+        bytes[start..start + 4].fill(0xFF);
+        std::fs::write(FNAME, &bytes).unwrap();
+        assert!(matches!(
+            read_header(&mut file_reader(FNAME).unwrap()),
+            Err(WstfError::InvalidHeader(msg)) if msg.contains("instrument")
+        ));
     }
 
     #[test]
-    fn should_speak_json() {
-        let t1 = Update {
-            ts: 20000001,
-            seq: 113,
-            is_trade: false,
+    #[serial]
+    fn should_keep_exact_ticks_in_fixed_point_files() {
+        let scale = FixedScale::new(2, 8);
+        let ups = (0..5_000i64)
+            .map(|i| FixedUpdate {
+                ts: 1_000 + i as u64 * 30,
+                seq: (i % 4) as u32,
+                is_trade: i % 3 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 1_234_567_891 + i,
+                size: 100_000_001 * (i % 9),
+            })
+            .collect::<Vec<_>>();
+        let opts = EncodeOptions {
+            checksum: true,
+            compression: Compression::available().pop().unwrap(),
+            scale: Some(scale),
+            ..Default::default()
+        };
+        let mut writer =
+            WstfWriter::with_options(file_writer(FNAME, true).unwrap(), SYMBOL, &opts).unwrap();
+        for up in &ups {
+            writer.push_fixed(up).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        assert_eq!(header.scale, Some(scale));
+        assert!(header.flags.contains(HeaderFlags::FLAG_FIXED_POINT));
+        assert_eq!(decode_fixed(FNAME).unwrap(), ups);
+        assert_ne!(ups[1].to_update(scale).to_fixed(scale).unwrap(), ups[1]);
+
+        let floats = ups.iter().map(|up| up.to_update(scale)).collect::<Vec<_>>();
+        assert_eq!(decode(FNAME, None).unwrap(), floats);
+        assert_eq!(
+            get_range_in_file(FNAME, 2_000, 3_000).unwrap(),
+            floats[34..=66].to_vec()
+        );
+
+        let late = Update {
+            ts: 500_000,
+            seq: 0,
+            is_trade: true,
             is_bid: false,
-            price: 5100.01,
-            size: 1.14564564645,
+            flags: Flags::FLAG_EMPTY,
+            price: 101.25,
+            size: 0.5,
         };
+        append(FNAME, &[late]).unwrap();
         assert_eq!(
-            t1.as_json(),
-            r#"{"ts":20000.001,"seq":113,"is_trade":false,"is_bid":false,"price":5100.01,"size":1.1456456}"#
+            decode_fixed(FNAME).unwrap().last(),
+            Some(&late.to_fixed(scale).unwrap())
         );
+        assert!(matches!(
+            append_with_policy(FNAME, &[late], AppendPolicy::Merge),
+            Err(WstfError::Unsupported(_))
+        ));
     }
 
     #[test]
-    fn should_write_to_bytes() {
-        let up = Update {
+    fn should_reject_unsupported_fixed_point_options() {
+        let scale = Some(FixedScale::new(2, 4));
+        for opts in [
+            EncodeOptions {
+                version: Version::V1,
+                scale,
+                ..Default::default()
+            },
+            EncodeOptions {
+                encoding: BatchEncoding::Xor,
+                scale,
+                ..Default::default()
+            },
+            EncodeOptions {
+                scale: Some(FixedScale::new(19, 0)),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                header_for_options(SYMBOL, &opts),
+                Err(WstfError::Unsupported(_))
+            ));
+        }
+
+        let mut writer = WstfWriter::new(Cursor::new(vec![]), SYMBOL).unwrap();
+        let up = FixedUpdate {
             ts: 0,
             seq: 0,
             is_trade: false,
             is_bid: false,
-            price: 0f32,
-            size: 0f32,
+            flags: Flags::FLAG_EMPTY,
+            price: 1,
+            size: 1,
         };
-        let mut bytes = vec![];
-        write_batches(&mut bytes, [up].iter().peekable()).unwrap();
+        assert!(matches!(
+            writer.push_fixed(&up),
+            Err(WstfError::Unsupported(_))
+        ));
+
+        let scale = FixedScale::new(8, 18);
+        assert_eq!(scale.price_to_ticks(0.1).unwrap(), 10_000_000);
+        for size in [f64::NAN, f64::INFINITY, 10.] {
+            assert!(matches!(
+                scale.size_to_ticks(size),
+                Err(WstfError::Unsupported(_))
+            ));
+        }
+        assert!(scale.price_to_ticks(-1e10).is_ok());
+        assert!(FixedScale::new(19, 0).price_to_ticks(1.).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn should_store_nanosecond_timestamps_with_wide_deltas() {
+        let start = 1_518_488_928_000_000_000u64;
+        let ups = (0..20_000u64)
+            .map(|i| Update {
+                ts: start + i * 1_250_000 + i % 7,
+                seq: 0,
+                is_trade: i % 3 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 5_100. + (i % 11) as f32,
+                size: (i % 4) as f32,
+            })
+            .collect::<Vec<_>>();
+        for encoding in [BatchEncoding::Raw, BatchEncoding::Xor] {
+            let opts = EncodeOptions {
+                encoding,
+                time_unit: TimeUnit::Nanos,
+                ..Default::default()
+            };
+            encode_with_options(FNAME, SYMBOL, &ups, &opts).unwrap();
+
+            let mut rdr = file_reader(FNAME).unwrap();
+            let header = read_header(&mut rdr).unwrap();
+            assert_eq!(header.time_unit, TimeUnit::Nanos);
+            assert_eq!(read_batch_index(&mut rdr, &header).unwrap().len(), 6);
+            assert_eq!(decode(FNAME, None).unwrap(), ups);
+
+            let (min_ts, max_ts) = (start + 10_000_000_000, start + 12_000_000_000);
+            let expected = ups
+                .iter()
+                .filter(|up| up.ts >= min_ts && up.ts <= max_ts)
+                .copied()
+                .collect::<Vec<_>>();
+            assert_eq!(get_range_in_file(FNAME, min_ts, max_ts).unwrap(), expected);
+
+            let meta = read_meta(FNAME).unwrap();
+            assert_eq!(meta.time_unit, TimeUnit::Nanos);
+            assert!(meta.to_string().contains("2018-02-13 02:28:48 UTC"));
+        }
+
+        let opts = EncodeOptions {
+            version: Version::V1,
+            time_unit: TimeUnit::Micros,
+            ..Default::default()
+        };
+        assert!(matches!(
+            encode_with_options(FNAME, SYMBOL, &ups, &opts),
+            Err(WstfError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn should_use_full_seq_delta_range() {
+        let ups = (0..10_240u64)
+            .map(|i| Update {
+                ts: 1_000 + i / 4,
+                seq: i as u32,
+                is_trade: false,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 100. + (i % 9) as f32,
+                size: 1.,
+            })
+            .collect::<Vec<_>>();
+        encode(FNAME, SYMBOL, &ups).unwrap();
+        assert_eq!(decode(FNAME, None).unwrap(), ups);
+
+        let mut rdr = file_reader(FNAME).unwrap();
+        let header = read_header(&mut rdr).unwrap();
+        let entries = read_batch_index(&mut rdr, &header).unwrap();
+        assert_eq!(entries.len(), 40);
+        assert!(entries.iter().all(|entry| entry.count == 256));
+
+        let mut full = vec![];
+        write_batches(&mut full, ups.iter().peekable()).unwrap();
+        let main_offset = header.main_offset() as usize;
+        let bytes = std::fs::read(FNAME).unwrap();
+        assert_eq!(&bytes[main_offset..main_offset + full.len()], &full[..]);
+
+        // With a cutoff of 15 sequence numbers a batch holds at most 16 of these rows.
+        let mut cut = vec![];
+        let mut cut_batches = 0;
+        for chunk in ups.chunks(16) {
+            cut_batches += write_batches(&mut cut, chunk.iter().peekable())
+                .unwrap()
+                .len();
+        }
+        assert_eq!(cut_batches, 640);
         assert_eq!(
-            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-            bytes
+            (cut.len() - full.len()) as u64,
+            (cut_batches - entries.len()) as u64 * BATCH_REF_LEN
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn should_round_trip_order_records() {
+        let actions = [
+            OrderAction::Add,
+            OrderAction::Modify,
+            OrderAction::Execute,
+            OrderAction::Cancel,
+        ];
+        let orders = (0..3_000u64)
+            .map(|i| {
+                let action = actions[(i % 4) as usize];
+                OrderUpdate {
+                    ts: 1_000 + i * 40,
+                    seq: (i % 200) as u32,
+                    order_id: 9_000_000_000 + i / 4,
+                    action,
+                    is_bid: i % 8 < 4,
+                    price: 250. + (i % 13) as f32 * 0.25,
+                    size: (i % 5) as f32,
+                    counterparty_id: (action == OrderAction::Execute && i % 3 != 0)
+                        .then_some(7_000_000_000 + i),
+                }
+            })
+            .collect::<Vec<_>>();
+        encode_orders(FNAME, SYMBOL, &orders, &checksum_opts()).unwrap();
+
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        assert_eq!(header.record_kind, RecordKind::Order);
+        assert_eq!(header.nums, orders.len() as u64);
+        assert_eq!(decode_orders(FNAME).unwrap(), orders);
+        assert_eq!(
+            get_orders_in_range(FNAME, 41_000, 80_000).unwrap(),
+            orders[1_000..=1_975].to_vec()
         );
+
+        assert!(matches!(
+            decode(FNAME, None),
+            Err(WstfError::Unsupported(_))
+        ));
+        assert!(matches!(
+            append(FNAME, &sample_data()),
+            Err(WstfError::Unsupported(_))
+        ));
+        encode(FNAME, SYMBOL, &sample_data()).unwrap();
+        assert!(matches!(
+            decode_orders(FNAME),
+            Err(WstfError::Unsupported(_))
+        ));
+
+        let opts = EncodeOptions {
+            encoding: BatchEncoding::Xor,
+            ..Default::default()
+        };
+        assert!(matches!(
+            encode_orders(FNAME, SYMBOL, &orders, &opts),
+            Err(WstfError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn should_round_trip_extended_and_unknown_flags() {
+        let flags = [
+            Flags::FLAG_EMPTY,
+            Flags::FLAG_SNAPSHOT_START,
+            Flags::FLAG_SNAPSHOT_END,
+            Flags::FLAG_RESET,
+            Flags::FLAG_IMPLIED,
+            Flags::FLAG_AGGRESSOR_UNKNOWN,
+            Flags::from_byte(0b1000_0000),
+        ];
+        let ups = (0..700u64)
+            .map(|i| Update {
+                ts: 1_000 + i * 10,
+                seq: 0,
+                is_trade: i % 3 == 0,
+                is_bid: i % 2 == 0,
+                flags: flags[(i % 7) as usize],
+                price: 100.5,
+                size: 2.,
+            })
+            .collect::<Vec<_>>();
+        let formats = [
+            EncodeOptions::default(),
+            EncodeOptions {
+                encoding: BatchEncoding::Xor,
+                ..Default::default()
+            },
+            EncodeOptions {
+                scale: Some(FixedScale::new(1, 0)),
+                ..Default::default()
+            },
+        ];
+        for opts in formats {
+            encode_with_options(FNAME, SYMBOL, &ups, &opts).unwrap();
+            assert_eq!(decode(FNAME, None).unwrap(), ups);
+        }
+
+        let up = ups[6];
+        let mut raw = vec![];
+        up.serialize_raw_to_buffer(&mut raw).unwrap();
+        assert_eq!(raw[12], 0b1000_0011);
+        assert_eq!(Update::from_raw(&raw).unwrap(), up);
+    }
+
+    #[test]
+    #[serial]
+    fn should_store_events_alongside_updates() {
+        let kinds = [
+            EventKind::Funding,
+            EventKind::MarkPrice,
+            EventKind::IndexPrice,
+            EventKind::Liquidation,
+            EventKind::OpenInterest,
+        ];
+        let ups = (0..4_000u64)
+            .map(|i| Update {
+                ts: 1_000 + i * 50,
+                seq: (i % 4) as u32,
+                is_trade: i % 5 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 30_000. + (i % 11) as f32,
+                size: (i % 7) as f32,
+            })
+            .collect::<Vec<_>>();
+        let events = (0..1_500u64)
+            .map(|i| MarketEvent {
+                ts: 1_020 + i * 130,
+                seq: 0,
+                kind: kinds[(i % 5) as usize],
+                is_bid: i % 2 == 0,
+                value: 0.0001 * i as f64,
+                size: (i % 3) as f64,
+            })
+            .collect::<Vec<_>>();
+        let formats = [
+            EncodeOptions::default(),
+            EncodeOptions {
+                encoding: BatchEncoding::Xor,
+                ..checksum_opts()
+            },
+        ];
+
+        for opts in formats {
+            encode_with_events(FNAME, SYMBOL, &ups, &events, &opts).unwrap();
+            let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+            assert!(header.has_events());
+            assert_eq!(header.nums, ups.len() as u64);
+            assert_eq!(header.events, events.len() as u64);
+
+            assert_eq!(decode(FNAME, None).unwrap(), ups);
+            assert_eq!(
+                crate::protocol::mmap::MmapReader::open(FNAME)
+                    .unwrap()
+                    .decode()
+                    .unwrap(),
+                ups
+            );
+            assert_eq!(
+                get_range_in_file(FNAME, 70_000, 120_000).unwrap(),
+                ups[1_380..=2_380].to_vec()
+            );
+            assert_eq!(
+                last_n_before(FNAME, 101_000, 3).unwrap(),
+                ups[1_997..2_000].to_vec()
+            );
+
+            assert_eq!(decode_events(FNAME).unwrap(), events);
+            let liquidations =
+                get_events_in_range(FNAME, 70_000, 120_000, Some(EventKind::Liquidation)).unwrap();
+            assert_eq!(
+                liquidations,
+                events
+                    .iter()
+                    .filter(|event| event.ts >= 70_000 && event.ts <= 120_000)
+                    .filter(|event| event.kind == EventKind::Liquidation)
+                    .copied()
+                    .collect::<Vec<_>>()
+            );
+            assert!(!liquidations.is_empty());
+        }
+
+        append_with_policy(FNAME, &ups[..1], AppendPolicy::Strict).unwrap_err();
+        let late = Update {
+            ts: 300_000,
+            ..ups[0]
+        };
+        append(FNAME, &[late]).unwrap();
+        assert_eq!(decode_events(FNAME).unwrap(), events);
+        assert_eq!(get_range_in_file(FNAME, 250_000, u64::MAX).unwrap(), [late]);
+
+        let report = recover(FNAME).unwrap();
+        assert_eq!(report.nums, ups.len() as u64 + 1);
+        let header = read_header(&mut file_reader(FNAME).unwrap()).unwrap();
+        assert_eq!(header.nums, ups.len() as u64 + 1);
+        assert_eq!(header.events, events.len() as u64);
+
+        encode(FNAME, SYMBOL, &ups).unwrap();
+        assert!(decode_events(FNAME).unwrap().is_empty());
+        let opts = EncodeOptions {
+            record_kind: RecordKind::Event,
+            ..Default::default()
+        };
+        assert!(matches!(
+            encode_with_options(FNAME, SYMBOL, &ups, &opts),
+            Err(WstfError::Unsupported(_))
+        ));
     }
 }
@@ -0,0 +1,167 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::error::WstfError;
+
+pub(crate) static INDEX_MARKER: u8 = 0x00;
+static INDEX_PREAMBLE_LEN: u64 = 9;
+static BYTES_PER_ENTRY: u64 = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchIndexEntry {
+    pub ref_ts: u64,
+    pub offset: u64,
+    pub count: u16,
+}
+
+pub fn write_index(wtr: &mut dyn Write, entries: &[BatchIndexEntry]) -> Result<(), WstfError> {
+    wtr.write_u8(INDEX_MARKER)?;
+    wtr.write_u64::<BigEndian>(entries.len() as u64)?;
+    for entry in entries {
+        wtr.write_u64::<BigEndian>(entry.ref_ts)?;
+        wtr.write_u64::<BigEndian>(entry.offset)?;
+        wtr.write_u16::<BigEndian>(entry.count)?;
+    }
+    Ok(())
+}
+
+/// The number of bytes `write_index` writes for `entries` entries.
+pub fn index_len(entries: usize) -> u64 {
+    INDEX_PREAMBLE_LEN + entries as u64 * BYTES_PER_ENTRY
+}
+
+/// Reads the number of index entries, rejecting counts the rest of the stream cannot hold.
+pub fn read_index_len<R: Read + Seek>(rdr: &mut R, index_offset: u64) -> Result<u64, WstfError> {
+    let stream_len = rdr.seek(SeekFrom::End(0))?;
+    rdr.seek(SeekFrom::Start(index_offset))?;
+    if rdr.read_u8()? != INDEX_MARKER {
+        return Err(WstfError::Corrupt(
+            "batch index marker incorrect".to_owned(),
+        ));
+    }
+    let len = rdr.read_u64::<BigEndian>()?;
+    let max_len = stream_len.saturating_sub(index_offset + INDEX_PREAMBLE_LEN) / BYTES_PER_ENTRY;
+    if len > max_len {
+        return Err(WstfError::Corrupt(format!(
+            "batch index at offset {} counts {} entries, the file holds at most {}",
+            index_offset, len, max_len
+        )));
+    }
+    Ok(len)
+}
+
+fn read_entry(rdr: &mut dyn Read) -> Result<BatchIndexEntry, WstfError> {
+    let ref_ts = rdr.read_u64::<BigEndian>()?;
+    let offset = rdr.read_u64::<BigEndian>()?;
+    let count = rdr.read_u16::<BigEndian>()?;
+    Ok(BatchIndexEntry {
+        ref_ts,
+        offset,
+        count,
+    })
+}
+
+pub fn read_index_entry<R: Read + Seek>(
+    rdr: &mut R,
+    index_offset: u64,
+    i: u64,
+) -> Result<BatchIndexEntry, WstfError> {
+    rdr.seek(SeekFrom::Start(
+        index_offset + INDEX_PREAMBLE_LEN + i * BYTES_PER_ENTRY,
+    ))?;
+    read_entry(rdr)
+}
+
+pub fn read_index<R: Read + Seek>(
+    rdr: &mut R,
+    index_offset: u64,
+) -> Result<Vec<BatchIndexEntry>, WstfError> {
+    let len = read_index_len(rdr, index_offset)?;
+    let mut v = vec![];
+    for _i in 0..len {
+        v.push(read_entry(rdr)?);
+    }
+    Ok(v)
+}
+
+pub fn search_index<R: Read + Seek>(
+    rdr: &mut R,
+    index_offset: u64,
+    min_ts: u64,
+) -> Result<Option<(u64, BatchIndexEntry)>, WstfError> {
+    let len = read_index_len(rdr, index_offset)?;
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if read_index_entry(rdr, index_offset, mid)?.ref_ts < min_ts {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+
+    let i = lo.saturating_sub(1);
+    Ok(Some((i, read_index_entry(rdr, index_offset, i)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn entries() -> Vec<BatchIndexEntry> {
+        (0..100)
+            .map(|i| BatchIndexEntry {
+                ref_ts: i * 10,
+                offset: 1000 + i * 27,
+                count: 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_round_trip_index() {
+        let mut buf = Cursor::new(vec![0xAB; 7]);
+        buf.seek(SeekFrom::End(0)).unwrap();
+        write_index(&mut buf, &entries()).unwrap();
+
+        assert_eq!(read_index_len(&mut buf, 7).unwrap(), 100);
+        assert_eq!(read_index(&mut buf, 7).unwrap(), entries());
+        assert_eq!(read_index_entry(&mut buf, 7, 42).unwrap(), entries()[42]);
+
+        let mut bytes = buf.into_inner();
+        bytes.pop();
+        let mut buf = Cursor::new(bytes);
+        assert!(matches!(
+            read_index(&mut buf, 7),
+            Err(WstfError::Corrupt(_))
+        ));
+        buf.get_mut()[8..16].fill(0xFF);
+        assert!(matches!(
+            read_index_len(&mut buf, 7),
+            Err(WstfError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn should_find_first_batch_covering_ts() {
+        let mut buf = Cursor::new(vec![]);
+        write_index(&mut buf, &entries()).unwrap();
+
+        let found = |buf: &mut Cursor<Vec<u8>>, ts| search_index(buf, 0, ts).unwrap().unwrap().0;
+        assert_eq!(found(&mut buf, 0), 0);
+        assert_eq!(found(&mut buf, 5), 0);
+        assert_eq!(found(&mut buf, 10), 0);
+        assert_eq!(found(&mut buf, 11), 1);
+        assert_eq!(found(&mut buf, 995), 99);
+        assert_eq!(found(&mut buf, 5000), 99);
+
+        let mut empty = Cursor::new(vec![]);
+        write_index(&mut empty, &[]).unwrap();
+        assert_eq!(search_index(&mut empty, 0, 0).unwrap(), None);
+    }
+}
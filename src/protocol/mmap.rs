@@ -0,0 +1,314 @@
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Cursor;
+
+use crate::error::WstfError;
+use crate::protocol::file_format::{
+    batch_rows, check_nums, first_batch_for, parse_update, read_batch_meta, read_header,
+    BatchFormat, BatchMetadata, Header, RecordKind,
+};
+use crate::protocol::index::INDEX_MARKER;
+use crate::update::Update;
+
+/// Reads a WSTF file through a read-only memory map.
+///
+/// The file must not be modified while the reader is alive.
+pub struct MmapReader {
+    mmap: Mmap,
+    header: Header,
+}
+
+impl MmapReader {
+    pub fn open(fname: &str) -> Result<MmapReader, WstfError> {
+        let file = File::open(fname)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = read_header(&mut Cursor::new(&mmap[..]))?;
+        Ok(MmapReader { mmap, header })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+
+    pub fn batches(&self) -> Batches<'_> {
+        self.batches_from(self.header.main_offset())
+    }
+
+    fn batches_from(&self, offset: u64) -> Batches<'_> {
+        Batches::new(&self.mmap, offset as usize, self.header.batch_format())
+    }
+
+    /// Reads every update and checks their number against the header.
+    pub fn decode(&self) -> Result<Vec<Update>, WstfError> {
+        let row_len = self.header.batch_format().row_len();
+        let mut v =
+            Vec::with_capacity(self.header.nums.min((self.mmap.len() / row_len) as u64) as usize);
+        let mut nums = 0;
+        for batch in self.batches() {
+            let batch = batch?;
+            if batch.meta.record_kind == RecordKind::Event {
+                continue;
+            }
+            nums += u64::from(batch.meta.count);
+            for up in batch.updates()? {
+                v.push(up?);
+            }
+        }
+        check_nums(&self.header, nums)?;
+        Ok(v)
+    }
+
+    pub fn range(&self, min_ts: u64, max_ts: u64) -> Result<Vec<Update>, WstfError> {
+        let mut v = vec![];
+        self.range_for_each(min_ts, max_ts, &mut |up| v.push(*up))?;
+        Ok(v)
+    }
+
+    pub fn range_for_each<F: for<'a> FnMut(&'a Update)>(
+        &self,
+        min_ts: u64,
+        max_ts: u64,
+        f: &mut F,
+    ) -> Result<(), WstfError> {
+        if min_ts > max_ts {
+            return Ok(());
+        }
+
+        let mut rdr = Cursor::new(&self.mmap[..]);
+        let first = match first_batch_for(&mut rdr, &self.header, min_ts)? {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+
+        for batch in self.batches_from(first.offset) {
+            let batch = batch?;
+            if batch.meta.ref_ts > max_ts {
+                break;
+            }
+            if batch.meta.record_kind == RecordKind::Event {
+                continue;
+            }
+            for up in batch.updates()? {
+                let up = up?;
+                if up.ts >= min_ts && up.ts <= max_ts {
+                    f(&up);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A batch whose payload still points into the underlying buffer.
+pub struct Batch<'a> {
+    pub meta: BatchMetadata,
+    payload: &'a [u8],
+}
+
+impl<'a> Batch<'a> {
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Verifies and decompresses the payload, then decodes rows as they are iterated.
+    pub fn updates(&self) -> Result<Updates<'a>, WstfError> {
+        Ok(Updates {
+            meta: self.meta.clone(),
+            rows: batch_rows(&self.meta, self.payload)?,
+            pos: 0,
+        })
+    }
+}
+
+pub struct Updates<'a> {
+    meta: BatchMetadata,
+    rows: Cow<'a, [u8]>,
+    pos: usize,
+}
+
+impl Iterator for Updates<'_> {
+    type Item = Result<Update, WstfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.get(self.pos..self.pos + self.meta.row_len())?;
+        self.pos += self.meta.row_len();
+        Some(parse_update(row, &self.meta))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.rows.len() - self.pos) / self.meta.row_len();
+        (len, Some(len))
+    }
+}
+
+/// Walks the batches of a byte slice up to its end or an index marker.
+pub struct Batches<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    fmt: BatchFormat,
+    failed: bool,
+}
+
+impl<'a> Batches<'a> {
+    pub fn new(buf: &'a [u8], pos: usize, fmt: BatchFormat) -> Batches<'a> {
+        Batches {
+            buf,
+            pos,
+            fmt,
+            failed: false,
+        }
+    }
+
+    fn read_batch(&mut self) -> Result<Batch<'a>, WstfError> {
+        let offset = self.pos as u64;
+        let mut rdr = &self.buf[self.pos + 1..];
+        let meta = read_batch_meta(&mut rdr, &self.fmt, offset)?;
+        let start = self.pos + self.fmt.ref_len() as usize;
+        let payload = self
+            .buf
+            .get(start..start + meta.payload_len())
+            .ok_or(WstfError::Truncated { offset })?;
+        self.pos = start + payload.len();
+        Ok(Batch { meta, payload })
+    }
+}
+
+impl<'a> Iterator for Batches<'a> {
+    type Item = Result<Batch<'a>, WstfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let batch = match *self.buf.get(self.pos)? {
+            0x1 => self.read_batch(),
+            byte if byte == INDEX_MARKER => return None,
+            byte => Err(WstfError::Corrupt(format!(
+                "expected a batch at offset {}, found byte {:#04x}",
+                self.pos, byte
+            ))),
+        };
+        self.failed = batch.is_err();
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::compression::Compression;
+    use crate::protocol::encoding::BatchEncoding;
+    use crate::protocol::file_format::{
+        decode, encode, encode_with_options, get_range_in_file, EncodeOptions, Version,
+    };
+    use crate::update::Flags;
+    use serial_test::serial;
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+
+    fn updates(n: u64) -> Vec<Update> {
+        (0..n)
+            .map(|i| Update {
+                ts: 1_000 + i * 7,
+                seq: (i / 1_000) as u32,
+                is_trade: i % 3 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 5100. + (i % 13) as f32 * 0.5,
+                size: (i % 4) as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    #[serial]
+    fn should_match_buffered_reader() {
+        let ups = updates(30_000);
+        let formats = [
+            EncodeOptions {
+                version: Version::V1,
+                ..Default::default()
+            },
+            EncodeOptions::default(),
+            EncodeOptions {
+                checksum: true,
+                compression: Compression::available().pop().unwrap(),
+                encoding: BatchEncoding::Xor,
+                ..Default::default()
+            },
+        ];
+
+        for opts in formats {
+            encode_with_options(FNAME, "BTC-USD", &ups, &opts).unwrap();
+            let rdr = MmapReader::open(FNAME).unwrap();
+
+            assert_eq!(rdr.header().nums, ups.len() as u64);
+            assert_eq!(rdr.decode().unwrap(), decode(FNAME, None).unwrap());
+            assert!(rdr.batches().count() > 1);
+            for (min_ts, max_ts) in [(0, 500), (1_500, 90_000), (150_000, 200_000), (5, 1)] {
+                assert_eq!(
+                    rdr.range(min_ts, max_ts).unwrap(),
+                    get_range_in_file(FNAME, min_ts, max_ts).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn should_check_row_count_against_header() {
+        let ups = updates(5_000);
+        let opts = EncodeOptions {
+            version: Version::V1,
+            ..Default::default()
+        };
+        encode_with_options(FNAME, "BTC-USD", &ups, &opts).unwrap();
+        let bytes = MmapReader::open(FNAME).unwrap().as_bytes().to_vec();
+        let last = MmapReader::open(FNAME)
+            .unwrap()
+            .batches()
+            .last()
+            .unwrap()
+            .unwrap()
+            .meta
+            .offset as usize;
+
+        // A file cut at a batch boundary still has whole batches, but fewer rows.
+        std::fs::write(FNAME, &bytes[..last]).unwrap();
+        let res = MmapReader::open(FNAME).unwrap().decode();
+        assert!(matches!(res, Err(WstfError::Corrupt(msg)) if msg.contains("header")));
+
+        // The v1 row count is the u64 at offset 25.
+        let mut corrupt = bytes;
+        corrupt[25..33].fill(0xFF);
+        std::fs::write(FNAME, corrupt).unwrap();
+        let res = MmapReader::open(FNAME).unwrap().decode();
+        assert!(matches!(res, Err(WstfError::Corrupt(msg)) if msg.contains("header")));
+    }
+
+    #[test]
+    #[serial]
+    fn should_report_truncated_batch() {
+        let ups = updates(100);
+        encode(FNAME, "BTC-USD", &ups).unwrap();
+        let bytes = MmapReader::open(FNAME).unwrap().as_bytes().to_vec();
+        let header = read_header(&mut Cursor::new(&bytes[..])).unwrap();
+        let main_offset = header.main_offset() as usize;
+
+        let mut batches = Batches::new(
+            &bytes[..bytes.len() - 40],
+            main_offset,
+            header.batch_format(),
+        );
+        assert!(matches!(
+            batches.next(),
+            Some(Err(WstfError::Truncated { offset })) if offset == main_offset as u64
+        ));
+        assert!(batches.next().is_none());
+    }
+}
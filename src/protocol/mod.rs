@@ -1,2 +1,8 @@
+pub mod compression;
+pub mod encoding;
 pub mod file_format;
+pub mod index;
+pub mod mmap;
 pub mod symbol;
+pub mod time_unit;
+pub mod writer;
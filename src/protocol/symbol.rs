@@ -1,3 +1,4 @@
+use ordered_float::OrderedFloat;
 use std::{fmt, str::FromStr};
 
 pub struct Symbol {
@@ -23,9 +24,33 @@ impl FromStr for Symbol {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub enum AssetType {
     SPOT,
+    FUTURE,
+    PERPETUAL,
+    OPTION,
+}
+
+impl AssetType {
+    pub fn from_byte(byte: u8) -> Option<AssetType> {
+        match byte {
+            0x00 => Some(AssetType::SPOT),
+            0x01 => Some(AssetType::FUTURE),
+            0x02 => Some(AssetType::PERPETUAL),
+            0x03 => Some(AssetType::OPTION),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            AssetType::SPOT => 0x00,
+            AssetType::FUTURE => 0x01,
+            AssetType::PERPETUAL => 0x02,
+            AssetType::OPTION => 0x03,
+        }
+    }
 }
 
 impl Default for AssetType {
@@ -37,11 +62,55 @@ impl Default for AssetType {
 impl fmt::Display for AssetType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &AssetType::SPOT => write!(f, "spot"),
+            AssetType::SPOT => write!(f, "spot"),
+            AssetType::FUTURE => write!(f, "future"),
+            AssetType::PERPETUAL => write!(f, "perpetual"),
+            AssetType::OPTION => write!(f, "option"),
+        }
+    }
+}
+
+impl FromStr for AssetType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spot" => Ok(AssetType::SPOT),
+            "future" => Ok(AssetType::FUTURE),
+            "perpetual" => Ok(AssetType::PERPETUAL),
+            "option" => Ok(AssetType::OPTION),
+            _ => Err(()),
         }
     }
 }
 
+/// Instrument details stored in the variable-length metadata block of a v2 header.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Serialize)]
+pub struct InstrumentMetadata {
+    pub symbol: String,
+    pub asset_type: AssetType,
+    pub tick_size: Option<OrderedFloat<f64>>,
+    pub lot_size: Option<OrderedFloat<f64>>,
+    pub price_decimals: Option<u8>,
+    pub tags: Vec<(String, String)>,
+}
+
+impl InstrumentMetadata {
+    pub fn new(symbol: &str) -> InstrumentMetadata {
+        InstrumentMetadata {
+            symbol: symbol.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _v)| k == key)
+            .map(|(_k, v)| v.as_str())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -54,4 +123,18 @@ mod tests {
         assert_eq!("usdt", sym.currency);
         assert_eq!("btc", sym.asset);
     }
+
+    #[test]
+    fn should_round_trip_asset_types() {
+        for asset_type in [
+            AssetType::SPOT,
+            AssetType::FUTURE,
+            AssetType::PERPETUAL,
+            AssetType::OPTION,
+        ] {
+            assert_eq!(AssetType::from_byte(asset_type.as_byte()), Some(asset_type));
+            assert_eq!(asset_type.to_string().parse(), Ok(asset_type));
+        }
+        assert_eq!(AssetType::from_byte(0xFF), None);
+    }
 }
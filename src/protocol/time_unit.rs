@@ -0,0 +1,101 @@
+use std::{fmt, str::FromStr};
+
+/// Resolution of the `ts` values stored in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum TimeUnit {
+    Seconds,
+    #[default]
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl TimeUnit {
+    pub fn from_byte(byte: u8) -> Option<TimeUnit> {
+        match byte {
+            0x00 => Some(TimeUnit::Seconds),
+            0x01 => Some(TimeUnit::Millis),
+            0x02 => Some(TimeUnit::Micros),
+            0x03 => Some(TimeUnit::Nanos),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            TimeUnit::Seconds => 0x00,
+            TimeUnit::Millis => 0x01,
+            TimeUnit::Micros => 0x02,
+            TimeUnit::Nanos => 0x03,
+        }
+    }
+
+    pub fn per_second(self) -> u64 {
+        match self {
+            TimeUnit::Seconds => 1,
+            TimeUnit::Millis => 1_000,
+            TimeUnit::Micros => 1_000_000,
+            TimeUnit::Nanos => 1_000_000_000,
+        }
+    }
+
+    /// Number of digits of a current epoch timestamp in this unit.
+    pub fn digits(self) -> u32 {
+        match self {
+            TimeUnit::Seconds => 10,
+            TimeUnit::Millis => 13,
+            TimeUnit::Micros => 16,
+            TimeUnit::Nanos => 19,
+        }
+    }
+
+    pub fn to_seconds(self, ts: u64) -> u64 {
+        ts / self.per_second()
+    }
+
+    /// Sub-millisecond units store 32-bit timestamp deltas so a batch can still span seconds.
+    pub fn has_wide_deltas(self) -> bool {
+        matches!(self, TimeUnit::Micros | TimeUnit::Nanos)
+    }
+
+    pub(crate) fn delta_len(self) -> usize {
+        if self.has_wide_deltas() {
+            4
+        } else {
+            2
+        }
+    }
+
+    pub(crate) fn max_batch_span(self) -> u64 {
+        if self.has_wide_deltas() {
+            0xFFFF_FFFF
+        } else {
+            0xFFFF
+        }
+    }
+}
+
+impl fmt::Display for TimeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeUnit::Seconds => write!(f, "s"),
+            TimeUnit::Millis => write!(f, "ms"),
+            TimeUnit::Micros => write!(f, "us"),
+            TimeUnit::Nanos => write!(f, "ns"),
+        }
+    }
+}
+
+impl FromStr for TimeUnit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s" => Ok(TimeUnit::Seconds),
+            "ms" => Ok(TimeUnit::Millis),
+            "us" => Ok(TimeUnit::Micros),
+            "ns" => Ok(TimeUnit::Nanos),
+            _ => Err(()),
+        }
+    }
+}
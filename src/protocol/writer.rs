@@ -0,0 +1,411 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::error::WstfError;
+use crate::protocol::file_format::{
+    header_for_instrument, header_for_options, write_header, write_trailing_index, BatchBuilder,
+    EncodeOptions, Header, HeaderFlags, IndexBuilder, Version,
+};
+use crate::protocol::symbol::InstrumentMetadata;
+use crate::update::{FixedUpdate, MarketEvent, OrderUpdate, Update};
+
+/// Incrementally encodes updates into `W`, buffering only the batch being built.
+///
+/// The header is written as a placeholder on creation and patched by `finish`. Files created
+/// with events also buffer one event batch, and every record must then be pushed in timestamp
+/// order.
+pub struct WstfWriter<W: Write + Seek> {
+    wtr: W,
+    header: Header,
+    batch: BatchBuilder,
+    events: Option<BatchBuilder>,
+    index: IndexBuilder,
+}
+
+impl<W: Write + Seek> WstfWriter<W> {
+    pub fn new(wtr: W, symbol: &str) -> Result<WstfWriter<W>, WstfError> {
+        WstfWriter::with_options(wtr, symbol, &EncodeOptions::default())
+    }
+
+    pub fn with_options(
+        wtr: W,
+        symbol: &str,
+        opts: &EncodeOptions,
+    ) -> Result<WstfWriter<W>, WstfError> {
+        WstfWriter::from_header(wtr, header_for_options(symbol, opts)?)
+    }
+
+    pub fn with_metadata(
+        wtr: W,
+        instrument: &InstrumentMetadata,
+        opts: &EncodeOptions,
+    ) -> Result<WstfWriter<W>, WstfError> {
+        WstfWriter::from_header(wtr, header_for_instrument(instrument, opts)?)
+    }
+
+    pub(crate) fn from_header(mut wtr: W, header: Header) -> Result<WstfWriter<W>, WstfError> {
+        let index = start_file(&mut wtr, &header)?;
+        Ok(WstfWriter {
+            wtr,
+            batch: BatchBuilder::new(header.batch_format(), None),
+            events: header
+                .has_events()
+                .then(|| BatchBuilder::new(header.event_format(), None)),
+            index,
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn push(&mut self, up: &Update) -> Result<(), WstfError> {
+        self.check_order(up.ts, up.seq)?;
+        if self.batch.is_full(up) {
+            self.flush_main()?;
+        }
+        self.batch.push(up)?;
+        track_update(&mut self.header, up.ts);
+        Ok(())
+    }
+
+    /// Pushes exact tick values into a file created with a fixed-point scale.
+    pub fn push_fixed(&mut self, up: &FixedUpdate) -> Result<(), WstfError> {
+        self.check_order(up.ts, up.seq)?;
+        if self.batch.is_full(up) {
+            self.flush_main()?;
+        }
+        self.batch.push_fixed(up)?;
+        track_update(&mut self.header, up.ts);
+        Ok(())
+    }
+
+    /// Pushes a market-by-order event into a file of `RecordKind::Order` records.
+    pub fn push_order(&mut self, order: &OrderUpdate) -> Result<(), WstfError> {
+        self.check_order(order.ts, order.seq)?;
+        if self.batch.is_full(order) {
+            self.flush_main()?;
+        }
+        self.batch.push_order(order)?;
+        track_update(&mut self.header, order.ts);
+        Ok(())
+    }
+
+    /// Pushes an auxiliary event into a file created with `EncodeOptions::events`.
+    pub fn push_event(&mut self, event: &MarketEvent) -> Result<(), WstfError> {
+        self.check_order(event.ts, event.seq)?;
+        let events = self
+            .events
+            .as_ref()
+            .ok_or_else(|| WstfError::Unsupported("file was not created with events".to_owned()))?;
+        if let Some(ref_ts) = events.ref_ts().filter(|_| events.is_full(event)) {
+            if self.batch.ref_ts().is_some_and(|main_ts| main_ts <= ref_ts) {
+                self.flush_main()?;
+            }
+            self.flush_events_through(u64::MAX)?;
+        }
+        if let Some(events) = self.events.as_mut() {
+            events.push_event(event)?;
+        }
+        self.header.events += 1;
+        track_ts(&mut self.header, event.ts);
+        Ok(())
+    }
+
+    pub fn flush_batch(&mut self) -> Result<(), WstfError> {
+        self.flush_main()?;
+        self.flush_events_through(u64::MAX)?;
+        Ok(self.wtr.flush()?)
+    }
+
+    pub fn finish(mut self) -> Result<W, WstfError> {
+        self.flush_main()?;
+        self.flush_events_through(u64::MAX)?;
+        finish_file(self.wtr, self.header, self.index)
+    }
+
+    /// The index is searched by timestamp, so it is only consistent with the batches, event
+    /// batches included, when records arrive in timestamp order.
+    fn check_order(&self, ts: u64, seq: u32) -> Result<(), WstfError> {
+        if self.header.nums + self.header.events > 0 && ts < self.header.max_ts {
+            return Err(WstfError::OutOfOrder { ts, seq });
+        }
+        Ok(())
+    }
+
+    /// Flushes the main batch, after a pending event batch that starts no later than it.
+    fn flush_main(&mut self) -> Result<(), WstfError> {
+        if let Some(ref_ts) = self.batch.ref_ts() {
+            self.flush_events_through(ref_ts)?;
+        }
+        self.batch.flush(&mut self.wtr, &mut self.index)
+    }
+
+    fn flush_events_through(&mut self, ts: u64) -> Result<(), WstfError> {
+        match self.events.as_mut() {
+            Some(events) if events.ref_ts().is_some_and(|ref_ts| ref_ts <= ts) => {
+                events.flush(&mut self.wtr, &mut self.index)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Encodes updates for several symbols into one file, tagging every batch with a symbol id.
+///
+/// Updates must be pushed in timestamp order across all symbols. Each symbol keeps its own
+/// pending batch, and batches are emitted in reference timestamp order.
+pub struct ContainerWriter<W: Write + Seek> {
+    wtr: W,
+    header: Header,
+    batches: Vec<BatchBuilder>,
+    index: IndexBuilder,
+}
+
+impl<W: Write + Seek> ContainerWriter<W> {
+    pub fn new(
+        wtr: W,
+        name: &str,
+        symbols: &[&str],
+        opts: &EncodeOptions,
+    ) -> Result<ContainerWriter<W>, WstfError> {
+        if opts.version != Version::V2 {
+            return Err(WstfError::Unsupported(
+                "containers require a v2 header".to_owned(),
+            ));
+        }
+        if let Some(symbol) = symbols
+            .iter()
+            .enumerate()
+            .find_map(|(i, symbol)| symbols[..i].contains(symbol).then_some(symbol))
+        {
+            return Err(WstfError::InvalidHeader(format!(
+                "symbol {} appears twice in the container",
+                symbol
+            )));
+        }
+        let mut header = header_for_options(name, opts)?;
+        header.flags |= HeaderFlags::FLAG_CONTAINER;
+        header.symbols = symbols.iter().map(|&symbol| symbol.to_owned()).collect();
+        header.fit_header_len();
+
+        let mut wtr = wtr;
+        let index = start_file(&mut wtr, &header)?;
+        let fmt = header.batch_format();
+        Ok(ContainerWriter {
+            wtr,
+            batches: (0..symbols.len())
+                .map(|id| BatchBuilder::new(fmt, Some(id as u16)))
+                .collect(),
+            index,
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn symbol_id(&self, symbol: &str) -> Option<u16> {
+        self.header.symbol_id(symbol)
+    }
+
+    pub fn push(&mut self, symbol_id: u16, up: &Update) -> Result<(), WstfError> {
+        if self.header.nums > 0 && up.ts < self.header.max_ts {
+            return Err(WstfError::OutOfOrder {
+                ts: up.ts,
+                seq: up.seq,
+            });
+        }
+        let batch = self.batches.get(symbol_id as usize).ok_or_else(|| {
+            WstfError::Unsupported(format!("unknown container symbol id {}", symbol_id))
+        })?;
+        if batch.is_full(up) {
+            if let Some(ref_ts) = batch.ref_ts() {
+                self.flush_through(ref_ts)?;
+            }
+        }
+        self.batches[symbol_id as usize].push(up)?;
+        track_update(&mut self.header, up.ts);
+        Ok(())
+    }
+
+    pub fn flush_batches(&mut self) -> Result<(), WstfError> {
+        self.flush_through(u64::MAX)?;
+        Ok(self.wtr.flush()?)
+    }
+
+    pub fn finish(mut self) -> Result<W, WstfError> {
+        self.flush_through(u64::MAX)?;
+        finish_file(self.wtr, self.header, self.index)
+    }
+
+    fn flush_through(&mut self, ts: u64) -> Result<(), WstfError> {
+        let mut pending = self
+            .batches
+            .iter()
+            .enumerate()
+            .filter_map(|(id, batch)| batch.ref_ts().map(|ref_ts| (ref_ts, id)))
+            .filter(|&(ref_ts, _id)| ref_ts <= ts)
+            .collect::<Vec<_>>();
+        pending.sort_unstable();
+        for (_ref_ts, id) in pending {
+            self.batches[id].flush(&mut self.wtr, &mut self.index)?;
+        }
+        Ok(())
+    }
+}
+
+fn start_file<W: Write + Seek>(wtr: &mut W, header: &Header) -> Result<IndexBuilder, WstfError> {
+    write_header(wtr, header)?;
+    let main_offset = header.main_offset();
+    wtr.seek(SeekFrom::Start(main_offset))?;
+    Ok(IndexBuilder::new(main_offset))
+}
+
+fn track_update(header: &mut Header, ts: u64) {
+    header.nums += 1;
+    track_ts(header, ts);
+}
+
+fn track_ts(header: &mut Header, ts: u64) {
+    header.max_ts = header.max_ts.max(ts);
+    header.min_ts = Some(header.min_ts.map_or(ts, |min_ts| min_ts.min(ts)));
+}
+
+fn finish_file<W: Write + Seek>(
+    mut wtr: W,
+    mut header: Header,
+    index: IndexBuilder,
+) -> Result<W, WstfError> {
+    if !index.entries.is_empty() {
+        header.index_offset = write_trailing_index(&mut wtr, header.version, &index.entries)?;
+    }
+    let end = wtr.stream_position()?;
+    write_header(&mut wtr, &header)?;
+    wtr.seek(SeekFrom::Start(end))?;
+    wtr.flush()?;
+    Ok(wtr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::compression::Compression;
+    use crate::protocol::encoding::BatchEncoding;
+    use crate::protocol::file_format::{
+        decode, encode_buffer_with_options, get_range_in_file, range, read_header, read_meta,
+        Version,
+    };
+    use crate::update::Flags;
+    use serial_test::serial;
+    use std::fs::File;
+    use std::io::{BufWriter, Cursor};
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+
+    fn updates(n: u64) -> Vec<Update> {
+        (0..n)
+            .map(|i| Update {
+                ts: 1_000 + i * 10,
+                seq: (i % 3) as u32,
+                is_trade: i % 4 == 0,
+                is_bid: i % 2 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: 100. + (i % 17) as f32,
+                size: (i % 5) as f32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_match_encode_buffer_output() {
+        let ups = updates(20_000);
+        let opts = EncodeOptions {
+            version: Version::V2,
+            checksum: true,
+            compression: Compression::None,
+            encoding: BatchEncoding::Xor,
+            ..Default::default()
+        };
+
+        let mut expected = Cursor::new(vec![]);
+        encode_buffer_with_options(&mut expected, "BTC-USD", &ups, &opts).unwrap();
+
+        let mut writer = WstfWriter::with_options(Cursor::new(vec![]), "BTC-USD", &opts).unwrap();
+        for up in &ups {
+            writer.push(up).unwrap();
+        }
+        let buf = writer.finish().unwrap().into_inner();
+        assert_eq!(buf, expected.into_inner());
+
+        let mut rdr = Cursor::new(buf);
+        let header = read_header(&mut rdr).unwrap();
+        assert_eq!(header.nums, ups.len() as u64);
+        assert_eq!(header.min_ts, Some(ups[0].ts));
+        assert_eq!(header.max_ts, ups.last().unwrap().ts);
+        assert!(header.index_offset.is_some());
+    }
+
+    #[test]
+    #[serial]
+    fn should_stream_updates_to_file() {
+        let ups = updates(1_000);
+        for version in [Version::V1, Version::V2] {
+            let opts = EncodeOptions {
+                version,
+                ..Default::default()
+            };
+            let file = File::create(FNAME).unwrap();
+            let mut writer =
+                WstfWriter::with_options(BufWriter::new(file), "BTC-USD", &opts).unwrap();
+            for chunk in ups.chunks(100) {
+                for up in chunk {
+                    writer.push(up).unwrap();
+                }
+                writer.flush_batch().unwrap();
+            }
+            writer.finish().unwrap();
+
+            assert_eq!(decode(FNAME, None).unwrap(), ups);
+            assert_eq!(read_meta(FNAME).unwrap().nums, ups.len() as u64);
+            assert_eq!(
+                get_range_in_file(FNAME, 3_000, 5_000).unwrap(),
+                ups[200..=400].to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn should_reject_updates_older_than_the_last() {
+        let ups = updates(20);
+        let mut writer = WstfWriter::new(Cursor::new(vec![]), "BTC-USD").unwrap();
+        for up in &ups[10..] {
+            writer.push(up).unwrap();
+        }
+        assert!(matches!(
+            writer.push(&ups[0]),
+            Err(WstfError::OutOfOrder { ts, .. }) if ts == ups[0].ts
+        ));
+        let same_ts = Update { seq: 0, ..ups[19] };
+        writer.push(&same_ts).unwrap();
+
+        let mut rdr = Cursor::new(writer.finish().unwrap().into_inner());
+        assert_eq!(read_header(&mut rdr).unwrap().nums, 11);
+        assert_eq!(range(&mut rdr, 0, u64::MAX).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn should_write_header_for_empty_writer() {
+        let writer = WstfWriter::new(Cursor::new(vec![]), "BTC-USD").unwrap();
+        assert_eq!(writer.header().nums, 0);
+        let buf = writer.finish().unwrap().into_inner();
+
+        let mut rdr = Cursor::new(buf);
+        let header = read_header(&mut rdr).unwrap();
+        assert_eq!(header.nums, 0);
+        assert_eq!(header.min_ts, None);
+        assert_eq!(header.index_offset, None);
+        assert!(range(&mut rdr, 0, u64::MAX).unwrap().is_empty());
+    }
+}
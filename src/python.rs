@@ -0,0 +1,216 @@
+//! Python extension module, built with `maturin` and the `python` feature.
+//!
+//! Updates are returned as numpy structured arrays, or pandas DataFrames with `as_frame=True`.
+//! Decoding runs without holding the GIL, and every column is a numpy owned buffer.
+
+use numpy::{Element, IntoPyArray};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::error::WstfError;
+use crate::protocol::file_format::{
+    decode as decode_file, get_range_in_file_with_symbol, read_meta,
+};
+use crate::protocol::symbol::InstrumentMetadata;
+use crate::update::Update;
+
+static COLUMNS: [&str; 7] = ["ts", "seq", "is_trade", "is_bid", "flags", "price", "size"];
+
+impl From<WstfError> for PyErr {
+    fn from(err: WstfError) -> PyErr {
+        match err {
+            WstfError::Io(err) => PyIOError::new_err(err.to_string()),
+            err => PyValueError::new_err(err.to_string()),
+        }
+    }
+}
+
+fn column<'py, T: Element>(
+    py: Python<'py>,
+    ups: &[Update],
+    f: fn(&Update) -> T,
+) -> Bound<'py, PyAny> {
+    ups.iter()
+        .map(f)
+        .collect::<Vec<_>>()
+        .into_pyarray(py)
+        .into_any()
+}
+
+fn to_python<'py>(py: Python<'py>, ups: &[Update], as_frame: bool) -> PyResult<Bound<'py, PyAny>> {
+    // Raises ImportError here rather than panicking when the numpy array API is missing.
+    let numpy = py.import("numpy")?;
+    let columns = [
+        column(py, ups, |up| up.ts),
+        column(py, ups, |up| up.seq),
+        column(py, ups, |up| up.is_trade),
+        column(py, ups, |up| up.is_bid),
+        column(py, ups, |up| up.flags.bits()),
+        column(py, ups, |up| up.price),
+        column(py, ups, |up| up.size),
+    ];
+    if as_frame {
+        let data = PyDict::new(py);
+        for (name, column) in COLUMNS.iter().zip(columns) {
+            data.set_item(name, column)?;
+        }
+        py.import("pandas")?.call_method1("DataFrame", (data,))
+    } else {
+        py.import("numpy.rec")?
+            .call_method1("fromarrays", (columns.to_vec(), COLUMNS.join(",")))?
+            .call_method1("view", (numpy.getattr("ndarray")?,))
+    }
+}
+
+/// Reads every update of the file.
+#[pyfunction]
+#[pyo3(signature = (fname, as_frame = false))]
+fn decode<'py>(py: Python<'py>, fname: &str, as_frame: bool) -> PyResult<Bound<'py, PyAny>> {
+    let ups = py.detach(|| decode_file(fname, None))?;
+    to_python(py, &ups, as_frame)
+}
+
+/// Reads the updates with `min_ts <= ts <= max_ts`, of one symbol of a container if set.
+#[pyfunction]
+#[pyo3(signature = (fname, min_ts, max_ts, symbol = None, as_frame = false))]
+fn range<'py>(
+    py: Python<'py>,
+    fname: &str,
+    min_ts: u64,
+    max_ts: u64,
+    symbol: Option<&str>,
+    as_frame: bool,
+) -> PyResult<Bound<'py, PyAny>> {
+    let ups = py.detach(|| get_range_in_file_with_symbol(fname, min_ts, max_ts, symbol))?;
+    to_python(py, &ups, as_frame)
+}
+
+fn instrument_dict<'py>(
+    py: Python<'py>,
+    instrument: &InstrumentMetadata,
+) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("symbol", &instrument.symbol)?;
+    dict.set_item("asset_type", instrument.asset_type.to_string())?;
+    dict.set_item("tick_size", instrument.tick_size.map(|size| size.0))?;
+    dict.set_item("lot_size", instrument.lot_size.map(|size| size.0))?;
+    dict.set_item("price_decimals", instrument.price_decimals)?;
+    let tags = PyDict::new(py);
+    for (key, value) in &instrument.tags {
+        tags.set_item(key, value)?;
+    }
+    dict.set_item("tags", tags)?;
+    Ok(dict)
+}
+
+/// Reads the header summary as a dict, with the instrument block or `None` under `instrument`.
+#[pyfunction]
+fn metadata<'py>(py: Python<'py>, fname: &str) -> PyResult<Bound<'py, PyDict>> {
+    let meta = py.detach(|| read_meta(fname))?;
+    let dict = PyDict::new(py);
+    dict.set_item("symbol", meta.symbol)?;
+    dict.set_item("symbols", meta.symbols)?;
+    dict.set_item("nums", meta.nums)?;
+    dict.set_item("min_ts", meta.min_ts)?;
+    dict.set_item("max_ts", meta.max_ts)?;
+    dict.set_item("time_unit", meta.time_unit.to_string())?;
+    let instrument = match &meta.instrument {
+        Some(instrument) => Some(instrument_dict(py, instrument)?),
+        None => None,
+    };
+    dict.set_item("instrument", instrument)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn wstf(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(decode, m)?)?;
+    m.add_function(wrap_pyfunction!(range, m)?)?;
+    m.add_function(wrap_pyfunction!(metadata, m)?)?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "python"))]
+mod tests {
+    use super::*;
+    use crate::protocol::file_format::{encode_with_metadata, EncodeOptions};
+    use crate::protocol::symbol::AssetType;
+    use crate::update::Flags;
+    use ordered_float::OrderedFloat;
+    use serial_test::serial;
+
+    static FNAME: &str = "./internal/mocks/tmp.wstf";
+
+    #[test]
+    #[serial]
+    fn should_expose_updates_and_metadata() {
+        let ups = (1..=100u64)
+            .map(|i| Update {
+                ts: i * 1000,
+                seq: i as u32,
+                is_trade: i % 2 == 0,
+                is_bid: i % 3 == 0,
+                flags: Flags::FLAG_EMPTY,
+                price: i as f32,
+                size: 0.5,
+            })
+            .collect::<Vec<_>>();
+        let instrument = InstrumentMetadata {
+            symbol: "binance_usdt_BTCUSDT-PERP".to_owned(),
+            asset_type: AssetType::PERPETUAL,
+            tick_size: Some(OrderedFloat(0.1)),
+            tags: vec![("exchange".to_owned(), "binance".to_owned())],
+            ..Default::default()
+        };
+        encode_with_metadata(FNAME, &instrument, &ups, &EncodeOptions::default()).unwrap();
+        let meta = read_meta(FNAME).unwrap();
+
+        Python::initialize();
+        Python::attach(|py| {
+            let dict = metadata(py, FNAME).unwrap();
+            let item = |key: &str| dict.get_item(key).unwrap().unwrap();
+            assert_eq!(item("symbol").extract::<String>().unwrap(), meta.symbol);
+            assert_eq!(item("nums").extract::<u64>().unwrap(), meta.nums);
+            assert_eq!(item("min_ts").extract::<u64>().unwrap(), meta.min_ts);
+            assert_eq!(item("max_ts").extract::<u64>().unwrap(), meta.max_ts);
+            let block = item("instrument");
+            let field = |key: &str| block.get_item(key).unwrap();
+            assert_eq!(
+                field("asset_type").extract::<String>().unwrap(),
+                "perpetual"
+            );
+            assert_eq!(field("tick_size").extract::<f64>().unwrap(), 0.1);
+            assert!(field("lot_size").is_none());
+            assert_eq!(
+                field("tags")
+                    .get_item("exchange")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "binance"
+            );
+
+            // The arrays need numpy at runtime, which the build does not.
+            if py.import("numpy").is_err() {
+                return;
+            }
+            let names = |array: &Bound<'_, PyAny>| {
+                array
+                    .getattr("dtype")
+                    .unwrap()
+                    .getattr("names")
+                    .unwrap()
+                    .extract::<Vec<String>>()
+                    .unwrap()
+            };
+            let all = decode(py, FNAME, false).unwrap();
+            assert_eq!(names(&all), COLUMNS);
+            assert_eq!(all.len().unwrap() as u64, meta.nums);
+            let some = range(py, FNAME, 10_000, 20_000, None, false).unwrap();
+            assert_eq!(names(&some), COLUMNS);
+            let ts = some.get_item("ts").unwrap().extract::<Vec<u64>>().unwrap();
+            assert_eq!(ts, (10..=20).map(|i| i * 1000).collect::<Vec<_>>());
+        });
+    }
+}
@@ -1,8 +1,9 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::cmp::Ordering;
-use std::io::ErrorKind::InvalidData;
 use std::io::{Cursor, Write};
 
+use crate::error::WstfError;
+
 pub trait UpdateVecConvert {
     fn as_json(&self) -> String;
     fn as_csv(&self) -> String;
@@ -45,38 +46,30 @@ pub struct Update {
     pub seq: u32,
     pub is_trade: bool,
     pub is_bid: bool,
+    /// Row flags other than `is_bid` and `is_trade`, including bits this version does not know.
+    #[serde(default)]
+    pub flags: Flags,
     pub price: f32,
     pub size: f32,
 }
 
 impl Update {
-    pub fn serialize_raw_to_buffer(&self, buf: &mut dyn Write) -> Result<(), std::io::Error> {
+    pub fn serialize_raw_to_buffer(&self, buf: &mut dyn Write) -> Result<(), WstfError> {
         buf.write_u64::<BigEndian>(self.ts)?;
         buf.write_u32::<BigEndian>(self.seq)?;
-
-        let mut flags = Flags::FLAG_EMPTY;
-        if self.is_bid {
-            flags |= Flags::FLAG_IS_BID;
-        }
-        if self.is_trade {
-            flags |= Flags::FLAG_IS_TRADE;
-        }
-        buf.write_u8(flags.bits())?;
+        buf.write_u8(Record::flags(self).bits())?;
 
         buf.write_f32::<BigEndian>(self.price)?;
         buf.write_f32::<BigEndian>(self.size)?;
         Ok(())
     }
 
-    pub fn from_raw(buf: &[u8]) -> Result<Self, std::io::Error> {
+    pub fn from_raw(buf: &[u8]) -> Result<Self, WstfError> {
         let mut rdr = Cursor::new(buf);
 
         let ts = rdr.read_u64::<BigEndian>()?;
         let seq = rdr.read_u32::<BigEndian>()?;
-        let flags = rdr.read_u8()?;
-        let is_trade =
-            (Flags::from_bits(flags).ok_or(InvalidData)? & Flags::FLAG_IS_TRADE).to_bool();
-        let is_bid = (Flags::from_bits(flags).ok_or(InvalidData)? & Flags::FLAG_IS_BID).to_bool();
+        let (is_bid, is_trade, flags) = Flags::from_byte(rdr.read_u8()?).split();
         let price = rdr.read_f32::<BigEndian>()?;
         let size = rdr.read_f32::<BigEndian>()?;
 
@@ -85,31 +78,34 @@ impl Update {
             seq,
             is_trade,
             is_bid,
+            flags,
             price,
             size,
         })
     }
 
-    pub fn serialize_to_buffer(&self, buf: &mut dyn Write, ref_ts: u64, ref_seq: u32) {
-        if self.seq < ref_seq {
-            panic!("reference seqno is bigger than the current seqno you are trying to encode");
-        }
-        let _ = buf.write_u16::<BigEndian>((self.ts - ref_ts) as u16);
-        let _ = buf.write_u8((self.seq - ref_seq) as u8);
-
-        let mut flags = Flags::FLAG_EMPTY;
-        if self.is_bid {
-            flags |= Flags::FLAG_IS_BID;
-        }
-        if self.is_trade {
-            flags |= Flags::FLAG_IS_TRADE;
-        }
-        let _ = buf.write_u8(flags.bits());
+    pub fn serialize_to_buffer(
+        &self,
+        buf: &mut dyn Write,
+        ref_ts: u64,
+        ref_seq: u32,
+    ) -> Result<(), WstfError> {
+        self.serialize_delta(buf, ref_ts, ref_seq, false)
+    }
 
-        let _ = buf.write_f32::<BigEndian>(self.price);
-        let _ = buf.write_f32::<BigEndian>(self.size);
+    pub fn to_fixed(&self, scale: FixedScale) -> Result<FixedUpdate, WstfError> {
+        Ok(FixedUpdate {
+            ts: self.ts,
+            seq: self.seq,
+            is_trade: self.is_trade,
+            is_bid: self.is_bid,
+            flags: self.flags,
+            price: scale.price_to_ticks(f64::from(self.price))?,
+            size: scale.size_to_ticks(f64::from(self.size))?,
+        })
     }
 
+    /// Legacy rendering with `ts` in fractional seconds, see `parser::text` for lossless output.
     pub fn as_json(&self) -> String {
         format!(
             r#"{{"ts":{},"seq":{},"is_trade":{},"is_bid":{},"price":{},"size":{}}}"#,
@@ -122,6 +118,7 @@ impl Update {
         )
     }
 
+    /// Legacy rendering with `ts` in fractional seconds, see `parser::text` for lossless output.
     pub fn as_csv(&self) -> String {
         format!(
             r#"{},{},{},{},{},{}"#,
@@ -135,6 +132,364 @@ impl Update {
     }
 }
 
+/// A row that can be delta-encoded against a batch reference.
+pub trait Record {
+    fn ts(&self) -> u64;
+    fn seq(&self) -> u32;
+    /// The full flags byte of the row.
+    fn flags(&self) -> Flags;
+    fn write_values(&self, buf: &mut dyn Write) -> Result<(), WstfError>;
+
+    /// Writes the row with a 16-bit timestamp delta, or a 32-bit one when `wide_ts` is set.
+    fn serialize_delta(
+        &self,
+        buf: &mut dyn Write,
+        ref_ts: u64,
+        ref_seq: u32,
+        wide_ts: bool,
+    ) -> Result<(), WstfError> {
+        if self.seq() < ref_seq || self.ts() < ref_ts {
+            return Err(WstfError::OutOfOrder {
+                ts: self.ts(),
+                seq: self.seq(),
+            });
+        }
+        if wide_ts {
+            buf.write_u32::<BigEndian>((self.ts() - ref_ts) as u32)?;
+        } else {
+            buf.write_u16::<BigEndian>((self.ts() - ref_ts) as u16)?;
+        }
+        buf.write_u8((self.seq() - ref_seq) as u8)?;
+        buf.write_u8(self.flags().bits())?;
+        self.write_values(buf)
+    }
+}
+
+impl Record for Update {
+    fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    fn flags(&self) -> Flags {
+        Flags::join(self.is_bid, self.is_trade, self.flags)
+    }
+
+    fn write_values(&self, buf: &mut dyn Write) -> Result<(), WstfError> {
+        buf.write_f32::<BigEndian>(self.price)?;
+        buf.write_f32::<BigEndian>(self.size)?;
+        Ok(())
+    }
+}
+
+/// The most decimals a `FixedScale` can keep, as 10^19 does not fit in an `i64`.
+pub static MAX_DECIMALS: u8 = 18;
+
+/// Number of decimal places kept when prices and sizes are stored as integer ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct FixedScale {
+    pub price_decimals: u8,
+    pub size_decimals: u8,
+}
+
+impl FixedScale {
+    pub fn new(price_decimals: u8, size_decimals: u8) -> FixedScale {
+        FixedScale {
+            price_decimals,
+            size_decimals,
+        }
+    }
+
+    /// Fails when either side keeps more than `MAX_DECIMALS` decimals.
+    pub fn validate(&self) -> Result<(), WstfError> {
+        match self.price_decimals.max(self.size_decimals) {
+            decimals if decimals > MAX_DECIMALS => Err(WstfError::Unsupported(format!(
+                "{} decimals, at most {} are supported",
+                decimals, MAX_DECIMALS
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn price_to_ticks(&self, price: f64) -> Result<i64, WstfError> {
+        self.validate()?;
+        to_ticks("price", price, self.price_decimals)
+    }
+
+    pub fn ticks_to_price(&self, ticks: i64) -> f64 {
+        ticks as f64 / 10f64.powi(i32::from(self.price_decimals))
+    }
+
+    pub fn size_to_ticks(&self, size: f64) -> Result<i64, WstfError> {
+        self.validate()?;
+        to_ticks("size", size, self.size_decimals)
+    }
+
+    pub fn ticks_to_size(&self, ticks: i64) -> f64 {
+        ticks as f64 / 10f64.powi(i32::from(self.size_decimals))
+    }
+}
+
+fn to_ticks(name: &str, value: f64, decimals: u8) -> Result<i64, WstfError> {
+    let ticks = (value * 10f64.powi(i32::from(decimals))).round();
+    // `i64::MAX as f64` rounds up to 2^63, which is already out of range.
+    if !ticks.is_finite() || ticks < i64::MIN as f64 || ticks >= i64::MAX as f64 {
+        return Err(WstfError::Unsupported(format!(
+            "{} {} does not fit in ticks of {} decimals",
+            name, value, decimals
+        )));
+    }
+    Ok(ticks as i64)
+}
+
+/// An update whose price and size are exact integer ticks of a `FixedScale`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixedUpdate {
+    pub ts: u64,
+    pub seq: u32,
+    pub is_trade: bool,
+    pub is_bid: bool,
+    #[serde(default)]
+    pub flags: Flags,
+    pub price: i64,
+    pub size: i64,
+}
+
+impl FixedUpdate {
+    pub fn to_update(&self, scale: FixedScale) -> Update {
+        Update {
+            ts: self.ts,
+            seq: self.seq,
+            is_trade: self.is_trade,
+            is_bid: self.is_bid,
+            flags: self.flags,
+            price: scale.ticks_to_price(self.price) as f32,
+            size: scale.ticks_to_size(self.size) as f32,
+        }
+    }
+}
+
+impl Record for FixedUpdate {
+    fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    fn flags(&self) -> Flags {
+        Flags::join(self.is_bid, self.is_trade, self.flags)
+    }
+
+    fn write_values(&self, buf: &mut dyn Write) -> Result<(), WstfError> {
+        buf.write_i64::<BigEndian>(self.price)?;
+        buf.write_i64::<BigEndian>(self.size)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderAction {
+    Add,
+    Modify,
+    Cancel,
+    Execute,
+}
+
+impl OrderAction {
+    pub fn from_byte(byte: u8) -> Option<OrderAction> {
+        match byte {
+            0x00 => Some(OrderAction::Add),
+            0x01 => Some(OrderAction::Modify),
+            0x02 => Some(OrderAction::Cancel),
+            0x03 => Some(OrderAction::Execute),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            OrderAction::Add => 0x00,
+            OrderAction::Modify => 0x01,
+            OrderAction::Cancel => 0x02,
+            OrderAction::Execute => 0x03,
+        }
+    }
+}
+
+// Set on the action byte when a counterparty order id follows.
+const ORDER_HAS_COUNTERPARTY: u8 = 0x80;
+
+/// A market-by-order event for a single resting order.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub ts: u64,
+    pub seq: u32,
+    pub order_id: u64,
+    pub action: OrderAction,
+    pub is_bid: bool,
+    pub price: f32,
+    pub size: f32,
+    /// The resting order matched by an `Execute`, when the venue reports it.
+    pub counterparty_id: Option<u64>,
+}
+
+impl OrderUpdate {
+    /// Reads the columns written by `write_values`.
+    pub fn from_values(
+        ts: u64,
+        seq: u32,
+        is_bid: bool,
+        values: &[u8],
+    ) -> Result<OrderUpdate, WstfError> {
+        let mut rdr = Cursor::new(values);
+        let order_id = rdr.read_u64::<BigEndian>()?;
+        let byte = rdr.read_u8()?;
+        let action = OrderAction::from_byte(byte & !ORDER_HAS_COUNTERPARTY)
+            .ok_or_else(|| WstfError::Corrupt(format!("unknown order action {}", byte)))?;
+        let price = rdr.read_f32::<BigEndian>()?;
+        let size = rdr.read_f32::<BigEndian>()?;
+        let counterparty_id = rdr.read_u64::<BigEndian>()?;
+        Ok(OrderUpdate {
+            ts,
+            seq,
+            order_id,
+            action,
+            is_bid,
+            price,
+            size,
+            counterparty_id: (byte & ORDER_HAS_COUNTERPARTY != 0).then_some(counterparty_id),
+        })
+    }
+}
+
+impl Record for OrderUpdate {
+    fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    fn flags(&self) -> Flags {
+        Flags::join(
+            self.is_bid,
+            self.action == OrderAction::Execute,
+            Flags::FLAG_EMPTY,
+        )
+    }
+
+    fn write_values(&self, buf: &mut dyn Write) -> Result<(), WstfError> {
+        buf.write_u64::<BigEndian>(self.order_id)?;
+        let mut action = self.action.as_byte();
+        if self.counterparty_id.is_some() {
+            action |= ORDER_HAS_COUNTERPARTY;
+        }
+        buf.write_u8(action)?;
+        buf.write_f32::<BigEndian>(self.price)?;
+        buf.write_f32::<BigEndian>(self.size)?;
+        buf.write_u64::<BigEndian>(self.counterparty_id.unwrap_or(0))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    Funding,
+    MarkPrice,
+    IndexPrice,
+    Liquidation,
+    OpenInterest,
+}
+
+impl EventKind {
+    pub fn from_byte(byte: u8) -> Option<EventKind> {
+        match byte {
+            0x00 => Some(EventKind::Funding),
+            0x01 => Some(EventKind::MarkPrice),
+            0x02 => Some(EventKind::IndexPrice),
+            0x03 => Some(EventKind::Liquidation),
+            0x04 => Some(EventKind::OpenInterest),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte(self) -> u8 {
+        match self {
+            EventKind::Funding => 0x00,
+            EventKind::MarkPrice => 0x01,
+            EventKind::IndexPrice => 0x02,
+            EventKind::Liquidation => 0x03,
+            EventKind::OpenInterest => 0x04,
+        }
+    }
+}
+
+/// An auxiliary derivatives event stored alongside the updates of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MarketEvent {
+    pub ts: u64,
+    pub seq: u32,
+    pub kind: EventKind,
+    /// Side of the liquidated position's order, unused by the other kinds.
+    pub is_bid: bool,
+    /// The funding rate, mark or index price, liquidation price or open interest.
+    pub value: f64,
+    /// The liquidated quantity, unused by the other kinds.
+    pub size: f64,
+}
+
+impl MarketEvent {
+    /// Reads the columns written by `write_values`.
+    pub fn from_values(
+        ts: u64,
+        seq: u32,
+        is_bid: bool,
+        values: &[u8],
+    ) -> Result<MarketEvent, WstfError> {
+        let mut rdr = Cursor::new(values);
+        let byte = rdr.read_u8()?;
+        let kind = EventKind::from_byte(byte)
+            .ok_or_else(|| WstfError::Corrupt(format!("unknown event kind {}", byte)))?;
+        let value = rdr.read_f64::<BigEndian>()?;
+        let size = rdr.read_f64::<BigEndian>()?;
+        Ok(MarketEvent {
+            ts,
+            seq,
+            kind,
+            is_bid,
+            value,
+            size,
+        })
+    }
+}
+
+impl Record for MarketEvent {
+    fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    fn flags(&self) -> Flags {
+        Flags::join(self.is_bid, false, Flags::FLAG_EMPTY)
+    }
+
+    fn write_values(&self, buf: &mut dyn Write) -> Result<(), WstfError> {
+        buf.write_u8(self.kind.as_byte())?;
+        buf.write_f64::<BigEndian>(self.value)?;
+        buf.write_f64::<BigEndian>(self.size)?;
+        Ok(())
+    }
+}
+
 impl PartialOrd for Update {
     fn partial_cmp(&self, other: &Update) -> Option<Ordering> {
         let selfts = self.ts;
@@ -158,15 +513,65 @@ impl Ord for Update {
 impl Eq for Update {}
 
 bitflags! {
+    // Transparent so `Update` keeps the same C layout as a plain `uint8_t` flags field.
+    #[repr(transparent)]
     pub struct Flags: u8 {
         const FLAG_EMPTY = 0b0000_0000;
         const FLAG_IS_BID = 0b0000_0001;
         const FLAG_IS_TRADE = 0b0000_0010;
+        const FLAG_SNAPSHOT_START = 0b0000_0100;
+        const FLAG_SNAPSHOT_END = 0b0000_1000;
+        /// The book must be cleared before this row is applied.
+        const FLAG_RESET = 0b0001_0000;
+        /// The level is derived from other books, e.g. implied from spreads.
+        const FLAG_IMPLIED = 0b0010_0000;
+        /// The venue did not report which side initiated the trade.
+        const FLAG_AGGRESSOR_UNKNOWN = 0b0100_0000;
     }
 }
 
 impl Flags {
     pub fn to_bool(&self) -> bool {
-        (self.bits == 0b0000_0001) || (self.bits == 0b0000_0010)
+        !self.is_empty()
+    }
+
+    /// Keeps bits without a named flag so rows written by newer versions round-trip.
+    pub fn from_byte(byte: u8) -> Flags {
+        // SAFETY: `Flags` only stores the bits, every value of the byte is valid.
+        unsafe { Flags::from_bits_unchecked(byte) }
+    }
+
+    /// Splits a row flags byte into `is_bid`, `is_trade` and the remaining flags.
+    pub fn split(self) -> (bool, bool, Flags) {
+        (
+            self.contains(Flags::FLAG_IS_BID),
+            self.contains(Flags::FLAG_IS_TRADE),
+            self - (Flags::FLAG_IS_BID | Flags::FLAG_IS_TRADE),
+        )
+    }
+
+    pub fn join(is_bid: bool, is_trade: bool, flags: Flags) -> Flags {
+        let mut flags = flags - (Flags::FLAG_IS_BID | Flags::FLAG_IS_TRADE);
+        flags.set(Flags::FLAG_IS_BID, is_bid);
+        flags.set(Flags::FLAG_IS_TRADE, is_trade);
+        flags
+    }
+}
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags::FLAG_EMPTY
+    }
+}
+
+impl serde::Serialize for Flags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.bits())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Flags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Flags, D::Error> {
+        <u8 as serde::Deserialize>::deserialize(deserializer).map(Flags::from_byte)
     }
 }
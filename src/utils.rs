@@ -1,3 +1,4 @@
+use crate::protocol::time_unit::TimeUnit;
 use crate::update::Update;
 use byteorder::{BigEndian, ReadBytesExt};
 use chrono::{DateTime, TimeZone, Utc};
@@ -6,11 +7,17 @@ use std::io::{BufWriter, Cursor, Error, Seek, SeekFrom, Write};
 type BookName = arrayvec::ArrayString<64>;
 
 pub fn fill_digits(input: u64) -> u64 {
+    fill_digits_for(input, TimeUnit::Millis)
+}
+
+/// Pads a truncated timestamp with zeros up to the digit count of `unit`.
+pub fn fill_digits_for(input: u64, unit: TimeUnit) -> u64 {
+    let min = 10u64.pow(unit.digits() - 1);
     let mut ret = input;
     if input == 0 {
         0
     } else {
-        while ret < 1_000_000_000_000 {
+        while ret < min {
             ret *= 10;
         }
         ret
@@ -86,4 +93,15 @@ mod tests {
         let epoch = 1518488928;
         assert_eq!("2018-02-13 02:28:48 UTC", epoch_to_human(epoch));
     }
+
+    #[test]
+    fn test_fill_digits_for_unit() {
+        assert_eq!(fill_digits(1518488928), 1518488928000);
+        assert_eq!(fill_digits_for(1518488928, TimeUnit::Seconds), 1518488928);
+        assert_eq!(
+            fill_digits_for(1518488928123, TimeUnit::Nanos),
+            1518488928123000000
+        );
+        assert_eq!(fill_digits_for(0, TimeUnit::Micros), 0);
+    }
 }
@@ -0,0 +1,96 @@
+/* Drives the C ABI against the fixture written by tests/c_abi.rs. */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "wstf.h"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                        \
+  do {                                                                     \
+    if (!(cond)) {                                                         \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+      failures++;                                                          \
+    }                                                                      \
+  } while (0)
+
+static void check_errors(const char *not_wstf) {
+  WstfSlice ups = {0};
+  char *err = NULL;
+
+  CHECK(wstf_decode(NULL, &ups, &err) == WSTF_STATUS_NULL_ARGUMENT);
+  CHECK(err != NULL);
+  wstf_str_free(err);
+
+  CHECK(wstf_decode("/nonexistent/file.wstf", &ups, &err) == WSTF_STATUS_IO);
+  CHECK(err != NULL && strlen(err) > 0);
+  wstf_str_free(err);
+
+  CHECK(wstf_decode(not_wstf, &ups, &err) == WSTF_STATUS_INVALID_FILE);
+  wstf_str_free(err);
+
+  CHECK(wstf_decode(not_wstf, NULL, NULL) == WSTF_STATUS_NULL_ARGUMENT);
+
+  /* The legacy entry points no longer abort. */
+  WstfSlice legacy = read_wstf_to_arr(NULL);
+  CHECK(legacy.len == 0);
+  wstf_slice_free(legacy);
+  CHECK(read_wstf_to_csv("/nonexistent/file.wstf") == NULL);
+}
+
+static void check_decode(const char *fname) {
+  WstfSlice ups = {0};
+  char *err = NULL;
+
+  CHECK(sizeof(WstfUpdate) == 24);
+  CHECK(wstf_decode(fname, &ups, &err) == WSTF_STATUS_OK);
+  CHECK(err == NULL);
+  CHECK(ups.len == 100);
+  if (ups.len == 100) {
+    CHECK(ups.ptr[0].ts == 1000);
+    CHECK(ups.ptr[0].is_bid && ups.ptr[0].is_trade);
+    CHECK(ups.ptr[99].ts == 1990);
+    CHECK(ups.ptr[99].price == 199.0f);
+    CHECK(ups.ptr[99].size == 99.0f);
+    CHECK(ups.ptr[7].flags == WSTF_FLAG_SNAPSHOT_END);
+  }
+  wstf_slice_free(ups);
+
+  CHECK(wstf_range(fname, 1100, 1190, &ups, &err) == WSTF_STATUS_OK);
+  CHECK(ups.len == 10);
+  if (ups.len == 10) {
+    CHECK(ups.ptr[0].ts == 1100);
+  }
+  wstf_slice_free(ups);
+
+  char *csv = NULL;
+  CHECK(wstf_to_csv(fname, &csv, &err) == WSTF_STATUS_OK);
+  CHECK(csv != NULL && strncmp(csv, "1,0,true,true,100,0", 19) == 0);
+  wstf_str_free(csv);
+}
+
+static void check_metadata(const char *fname) {
+  WstfMetadata meta = {0};
+  char *err = NULL;
+
+  CHECK(wstf_metadata(fname, &meta, &err) == WSTF_STATUS_OK);
+  CHECK(meta.symbol != NULL && strcmp(meta.symbol, "BTC-USD") == 0);
+  CHECK(meta.nums == 100);
+  CHECK(meta.min_ts == 1000);
+  CHECK(meta.max_ts == 1990);
+  CHECK(meta.ts_per_second == 1000);
+  wstf_metadata_free(&meta);
+  CHECK(meta.symbol == NULL);
+}
+
+int main(int argc, char **argv) {
+  if (argc != 2) {
+    fprintf(stderr, "usage: %s FIXTURE\n", argv[0]);
+    return 2;
+  }
+  check_errors(argv[0]);
+  check_decode(argv[1]);
+  check_metadata(argv[1]);
+  return failures == 0 ? 0 : 1;
+}
@@ -0,0 +1,93 @@
+//! Checks `include/wstf.h` against the sources and drives the C ABI from a C program.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use wstf::protocol::file_format::encode;
+use wstf::update::{Flags, Update};
+
+fn crate_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn generate_header() -> String {
+    let dir = crate_dir();
+    let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).unwrap();
+    let mut buf = vec![];
+    cbindgen::Builder::new()
+        .with_crate(&dir)
+        .with_config(config)
+        .generate()
+        .unwrap()
+        .write(&mut buf);
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn header_is_up_to_date() {
+    let path = crate_dir().join("include/wstf.h");
+    let header = generate_header();
+    if env::var_os("WSTF_BLESS").is_some() {
+        fs::write(&path, &header).unwrap();
+    }
+    assert!(
+        fs::read_to_string(&path).unwrap() == header,
+        "include/wstf.h is stale, regenerate it with WSTF_BLESS=1 cargo test --test c_abi"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn c_harness() {
+    let tmp = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let fixture = tmp.join("c_abi.wstf");
+    let ups = (0..100u64)
+        .map(|i| Update {
+            ts: 1_000 + i * 10,
+            seq: 0,
+            is_trade: i % 3 == 0,
+            is_bid: i % 2 == 0,
+            flags: if i == 7 {
+                Flags::FLAG_SNAPSHOT_END
+            } else {
+                Flags::FLAG_EMPTY
+            },
+            price: 100. + i as f32,
+            size: i as f32,
+        })
+        .collect::<Vec<_>>();
+    encode(fixture.to_str().unwrap(), "BTC-USD", &ups).unwrap();
+
+    // The cdylib is built next to the test binary.
+    let deps = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let harness = tmp.join("wstf_c_harness");
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_owned()))
+        .arg(crate_dir().join("tests/c/harness.c"))
+        .arg("-I")
+        .arg(crate_dir().join("include"))
+        .arg("-L")
+        .arg(&deps)
+        .arg(format!("-Wl,-rpath,{}", deps.display()))
+        .arg("-lwstf")
+        .arg("-o")
+        .arg(&harness)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Cargo's library path can hold an older copy of the library, so rely on the rpath alone.
+    let output = Command::new(&harness)
+        .arg(&fixture)
+        .env_remove("LD_LIBRARY_PATH")
+        .env_remove("DYLD_LIBRARY_PATH")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}